@@ -391,10 +391,15 @@ sysreg!(contextidr_el1);
 sysreg!(vbar_el1);
 sysreg!(mpidr_el1);
 sysreg!(midr_el1);
+sysreg!(id_aa64pfr0_el1);
 sysreg!(id_aa64pfr1_el1);
 sysreg!(id_aa64mmfr0_el1);
 sysreg!(id_aa64mmfr1_el1);
 sysreg!(clidr_el1);
+sysreg!(icc_sre_el1);
+sysreg!(icc_pmr_el1);
+sysreg!(icc_igrpen0_el1);
+sysreg!(icc_igrpen1_el1);
 
 sysreg!(sctlr_el2);
 sysreg!(mdcr_el2);
@@ -531,15 +536,20 @@ pub fn isb() {
     unsafe { asm!("isb") };
 }
 
+/// Release the secondary CPUs waiting on the spin-table of the firmware of Raspberry Pi.
+/// They jump to `_start`.
 pub fn start_non_primary() {
-    if cfg!(feature = "raspi3") {
+    if cfg!(any(feature = "raspi3", feature = "raspi4")) {
         unsafe {
             asm!(
                 "mov {0}, #0xe0
                  ldr {1}, =_start
-                 str {1}, [{0}]
+                 str {1}, [{0}]     // core #1
                  str {1}, [{0},  8] // core #2
-                 str {1}, [{0}, 16] // core #3",
+                 str {1}, [{0}, 16] // core #3
+                 dc  civac, {0}     // the waiting CPUs read memory without cache
+                 dsb sy
+                 sev",
             lateout(reg) _,
             lateout(reg) _
             );
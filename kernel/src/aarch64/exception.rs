@@ -1,8 +1,9 @@
-use super::{context::GpRegs, cpu, mmu, syscall};
+use super::{context::GpRegs, cpu, syscall, uaccess};
 use crate::{
-    allocator, bsp, driver, out,
+    bsp, driver, out,
     paging::{self, FaultResult},
     process,
+    syscall::ExitStatus,
 };
 
 const ESR_EL1_EC_MASK: u64 = 0b111111 << 26;
@@ -118,7 +119,7 @@ pub fn curr_el_sp0_sync_el1(ctx: *mut GpRegs, _sp: usize) {
     let ec = esr & ESR_EL1_EC_MASK;
     match ec {
         ESR_LE1_EC_DATA_KERN => {
-            page_fault_el1();
+            page_fault_el1(ctx);
         }
         _ => {
             let r = unsafe { &mut *ctx };
@@ -138,12 +139,13 @@ pub fn curr_el_sp0_sync_el1(ctx: *mut GpRegs, _sp: usize) {
 
 #[no_mangle]
 pub fn curr_el_sp0_irq_el1(_ctx: *mut GpRegs, _sp: usize) {
-    driver::uart::puts("EL1 exception: SP0 IRQ\n");
+    // kernel space is not preemptive
+    bsp::int::handle_irq();
 }
 
 #[no_mangle]
 pub fn curr_el_sp0_fiq_el1(_ctx: *mut GpRegs, _sp: usize) {
-    driver::uart::puts("EL1 exception: SP0 FIQ\n");
+    bsp::int::handle_irq();
 }
 
 #[no_mangle]
@@ -167,12 +169,13 @@ pub fn curr_el_spx_sync_el1(ctx: *mut GpRegs, _sp: usize) {
 
 #[no_mangle]
 pub fn curr_el_spx_irq_el1(_ctx: *mut GpRegs, _sp: usize) {
-    driver::uart::puts("EL1 exception: SPX IRQ\n");
+    // kernel space is not preemptive
+    bsp::int::handle_irq();
 }
 
 #[no_mangle]
 pub fn curr_el_spx_fiq_el1(_ctx: *mut GpRegs, _sp: usize) {
-    driver::uart::puts("EL1 exception: SPX FIQ\n");
+    bsp::int::handle_irq();
 }
 
 #[no_mangle]
@@ -184,7 +187,6 @@ pub fn curr_el_spx_serror_el1(_ctx: *mut GpRegs, _sp: usize) {
 #[no_mangle]
 pub fn lower_el_aarch64_sync_el1(ctx: *mut GpRegs, _sp: usize) {
     let _ent = process::EnterKernel::new();
-    detect_stack_overflow();
 
     let r = unsafe { &mut *ctx };
     let esr = r.x18;
@@ -193,7 +195,7 @@ pub fn lower_el_aarch64_sync_el1(ctx: *mut GpRegs, _sp: usize) {
     match ec {
         ESR_EL1_EC_WFI_OR_WFE => out::msg("EL1 Exception", "WFI or WFE"),
         ESR_LE1_EC_DATA => {
-            page_fault_el0(ctx);
+            page_fault_el0();
         }
         ESR_EL1_EC_SVC64 => {
             let n = syscall::handle64(r);
@@ -216,20 +218,29 @@ pub fn lower_el_aarch64_sync_el1(ctx: *mut GpRegs, _sp: usize) {
 
 #[no_mangle]
 pub fn lower_el_aarch64_irq_el1(_ctx: *mut GpRegs, _sp: usize) {
-    driver::uart::puts("EL1 exception: IRQ\n");
-    detect_stack_overflow();
+    let _ent = process::EnterKernel::new();
+
+    bsp::int::handle_irq();
+
+    // the context of the preempted process is saved by CALL_WITH_CONTEXT,
+    // and restored when the process is scheduled again
+    process::preempt();
 }
 
+/// Secure (group 0) interrupts of the GIC are signaled as FIQ.
 #[no_mangle]
 pub fn lower_el_aarch64_fiq_el1(_ctx: *mut GpRegs, _sp: usize) {
-    driver::uart::puts("EL1 exception: FIQ\n");
-    detect_stack_overflow();
+    let _ent = process::EnterKernel::new();
+
+    bsp::int::handle_irq();
+    process::preempt();
 }
 
 #[no_mangle]
 pub fn lower_el_aarch64_serror_el1(_ctx: *mut GpRegs, _sp: usize) {
+    let _ent = process::EnterKernel::new();
     driver::uart::puts("EL1 exception: Error\n");
-    process::exit();
+    process::exit(ExitStatus::Error);
 }
 
 // from lower EL (AArch32)
@@ -245,47 +256,55 @@ pub fn lower_el_aarch32_fiq_el1(_ctx: *mut GpRegs, _sp: usize) {}
 #[no_mangle]
 pub fn lower_el_aarch32_serror_el1(_ctx: *mut GpRegs, _sp: usize) {}
 
-fn page_fault_el1() {
+fn page_fault_el1(ctx: *mut GpRegs) {
     let far_el1 = cpu::far_el1::get();
-    match paging::fault(far_el1 as usize) {
+    let r = unsafe { &mut *ctx };
+
+    let result = paging::fault(far_el1 as usize);
+    if result != FaultResult::Ok {
+        // a fault while copying from or to a process makes the copy fail
+        if let Some(elr) = uaccess::fixup(r.elr) {
+            r.elr = elr;
+            return;
+        }
+    }
+
+    match result {
         FaultResult::InvalidAccess => {
             panic!("invalid memory access");
         }
+        FaultResult::QuotaExceeded => {
+            panic!("exceeded the page quota");
+        }
+        FaultResult::OutOfMemory => {
+            panic!("exhausted memory");
+        }
         FaultResult::StackOverflow => {
-            paging::map_canary();
+            panic!("stack overflow");
         }
         _ => {}
     }
 }
 
-#[no_mangle]
-extern "C" fn call_exit() {
-    crate::syscall::exit();
-}
-
-fn page_fault_el0(ctx: *mut GpRegs) {
+/// The kernel runs on the kernel stack of the process,
+/// so the process is terminated here when the fault cannot be resolved.
+fn page_fault_el0() {
     let far_el1 = cpu::far_el1::get();
     match paging::fault(far_el1 as usize) {
         FaultResult::InvalidAccess => {
-            unsafe { (*ctx).elr = call_exit as u64 };
+            process::exit(ExitStatus::InvalidAccess);
         }
         FaultResult::StackOverflow => {
-            paging::map_canary();
-            unsafe { (*ctx).elr = call_exit as u64 };
-        }
-        _ => {}
-    }
-}
-
-fn detect_stack_overflow() {
-    if let Some(id) = process::get_raw_id() {
-        let sp = cpu::get_sp();
-        if allocator::is_user_canary(id, sp as usize)
-            || allocator::is_user_canary(id, (sp - mmu::STACK_SIZE) as usize)
-        {
-            // stack overflow
             out::msg("stack overflow", "0");
-            process::exit();
+            process::exit(ExitStatus::StackOverflow);
         }
+        FaultResult::QuotaExceeded => {
+            process::exit(ExitStatus::QuotaExceeded);
+        }
+        FaultResult::OutOfMemory => {
+            // only this process is terminated, and the kernel keeps running with the reserve
+            process::exit(ExitStatus::OutOfMemory);
+        }
+        _ => {}
     }
 }
@@ -16,12 +16,13 @@ pub const EL1_ADDR_OFFSET: u64 = 0x3FFFFF << 42;
 
 // level 2 table x 1 (for 4TiB space)
 // level 3 table x 8 (for 512MiB x 8 = 4GiB space)
-// level 3 table x 32 (for 512MiB x 32 = 16GiB space, 64MiB * 256 Processes = 16GiB)
+//
+// level 3 tables for the user heap space (from 1TiB) are not preallocated.
+// they are taken from the pager on demand by set_table(),
+// because only a few of 64MiB * PROCESS_MAX are used at once
 pub const KERN_TTBR0_LV2_TABLE_NUM: usize = 1;
-pub const KERN_TTBR0_LV3_TABLE_NUM1: usize = 8; // from 0B to 4GiB
-pub const KERN_TTBR0_LV3_TABLE_NUM2: usize = 32; // from 1TiB to 1TiB + 16GiB
-pub const KERN_TTBR0_TABLE_NUM: usize =
-    KERN_TTBR0_LV2_TABLE_NUM + KERN_TTBR0_LV3_TABLE_NUM1 + KERN_TTBR0_LV3_TABLE_NUM2;
+pub const KERN_TTBR0_LV3_TABLE_NUM: usize = 8; // from 0B to 4GiB
+pub const KERN_TTBR0_TABLE_NUM: usize = KERN_TTBR0_LV2_TABLE_NUM + KERN_TTBR0_LV3_TABLE_NUM;
 
 // level 2 table x 1 (for 4TiB space)
 // level 3 table x 4 (for 512MiB x 4 = 2GiB space)
@@ -100,13 +101,18 @@ const FLAG_L2_NS: u64 = 1 << 63; // non secure table
 
 const FLAG_L3_XN: u64 = 1 << 54; // execute never
 const FLAG_L3_PXN: u64 = 1 << 53; // priviledged execute
+const FLAG_L3_COW: u64 = 1 << 55; // copy-on-write, reserved for software use
 const FLAG_L3_CONT: u64 = 1 << 52; // contiguous
 const FLAG_L3_DBM: u64 = 1 << 51; // dirty bit modifier
+const FLAG_L3_NG: u64 = 1 << 11; // not global, tagged by ASID
 const FLAG_L3_AF: u64 = 1 << 10; // access flag
 const FLAG_L3_NS: u64 = 1 << 5; // non secure
 
 const OFFSET_USER_HEAP_PAGE: usize = 2048; // 1TiB offset
 
+// [47:16]: next level table address
+const TABLE_ADDR_MASK: u64 = 0xffff_ffff << 16;
+
 // [9:8]: Shareability attribute, for Normal memory
 //    | Shareability
 // ---|------------------
@@ -129,6 +135,7 @@ const FLAG_L3_SH_RW_N: u64 = 0;
 const FLAG_L3_SH_RW_RW: u64 = 1 << 6;
 const FLAG_L3_SH_R_N: u64 = 0b10 << 6;
 const FLAG_L3_SH_R_R: u64 = 0b11 << 6;
+const FLAG_L3_AP_MASK: u64 = 0b11 << 6;
 
 // [4:2]: AttrIndx
 // defined in MAIR register
@@ -140,11 +147,9 @@ const FLAG_L3_ATTR_NC: u64 = 2 << 2; // non-cachable
 // transition table
 pub struct TTable {
     tt_lv2: &'static mut [u64],
-    tt_lv3_1: &'static mut [u64],
-    tt_lv3_2: &'static mut [u64],
+    tt_lv3: &'static mut [u64],
     num_lv2: usize,
-    num_lv3_1: usize,
-    num_lv3_2: usize,
+    num_lv3: usize,
 }
 
 // logical address information
@@ -306,41 +311,51 @@ pub fn get_ttbr0() -> TTable {
     TTable::new(
         addr.tt_el1_ttbr0_start + EL1_ADDR_OFFSET,
         KERN_TTBR0_LV2_TABLE_NUM,
-        KERN_TTBR0_LV3_TABLE_NUM1,
-        KERN_TTBR0_LV3_TABLE_NUM2,
+        KERN_TTBR0_LV3_TABLE_NUM,
     )
 }
 
+/// Physical address of the kernel's TTBR0, which is used while no process is running.
+pub fn get_kern_ttbr0_addr() -> u64 {
+    get_memory_map().tt_el1_ttbr0_start
+}
+
+/// Get the translation table of a process, whose level 2 table is at phy_addr.
+/// The level 2 table is accessed through TTBR1, like the level 3 tables of the 1TiB... space.
+pub fn get_user_ttbr0(phy_addr: u64) -> TTable {
+    let tt_lv2 =
+        unsafe { slice::from_raw_parts_mut((phy_addr + EL1_ADDR_OFFSET) as *mut u64, 8192) };
+
+    // the level 3 tables of the 0B... space are shared with the kernel
+    TTable {
+        tt_lv2,
+        ..get_ttbr0()
+    }
+}
+
 pub fn get_ttbr1() -> TTable {
     let addr = get_memory_map();
     TTable::new(
         addr.tt_el1_ttbr1_start + EL1_ADDR_OFFSET,
         KERN_TTBR1_LV2_TABLE_NUM,
         KERN_TTBR1_LV3_TABLE_NUM,
-        0,
     )
 }
 
 impl TTable {
-    fn new(tt_addr: u64, num_lv2: usize, num_lv3_1: usize, num_lv3_2: usize) -> TTable {
+    fn new(tt_addr: u64, num_lv2: usize, num_lv3: usize) -> TTable {
         let ptr = tt_addr as *mut u64;
         let tt_lv2 = unsafe { slice::from_raw_parts_mut(ptr, 8192 * num_lv2) };
 
         // 0... space
         let ptr = ((PAGESIZE * num_lv2 as u64) + tt_addr) as *mut u64;
-        let tt_lv3_1 = unsafe { slice::from_raw_parts_mut(ptr, 8192 * num_lv3_1) };
-
-        // 1TiB... space
-        let ptr = ((PAGESIZE * (num_lv2 + num_lv3_1) as u64) + tt_addr) as *mut u64;
-        let tt_lv3_2 = unsafe { slice::from_raw_parts_mut(ptr, 8192 * num_lv3_2) };
+        let tt_lv3 = unsafe { slice::from_raw_parts_mut(ptr, 8192 * num_lv3) };
 
         TTable {
             tt_lv2,
-            tt_lv3_1,
-            tt_lv3_2,
+            tt_lv3,
             num_lv2,
-            num_lv3_1,
-            num_lv3_2,
+            num_lv3,
         }
     }
 
@@ -350,81 +365,132 @@ impl TTable {
             *e = 0;
         }
 
-        for e in self.tt_lv3_1.iter_mut() {
-            *e = 0;
-        }
-
-        for e in self.tt_lv3_2.iter_mut() {
+        for e in self.tt_lv3.iter_mut() {
             *e = 0;
         }
 
         // set up level 2 tables for 0... space
         for i in 0..(8192 * self.num_lv2) {
-            if i >= self.num_lv3_1 {
+            if i >= self.num_lv3 {
                 break;
             }
-            self.tt_lv2[i] = (&self.tt_lv3_1[i * 8192] as *const u64) as u64 | 0b11;
+            self.tt_lv2[i] = (&self.tt_lv3[i * 8192] as *const u64) as u64 | 0b11;
         }
+    }
 
-        // set up level 2 tables for 1TiB... space
-        for i in OFFSET_USER_HEAP_PAGE..(8192 * self.num_lv2) {
-            let idx = i - OFFSET_USER_HEAP_PAGE;
-            if idx >= self.num_lv3_2 {
-                break;
-            }
-            self.tt_lv2[i] = (&self.tt_lv3_2[idx * 8192] as *const u64) as u64 | 0b11;
+    /// Initialize the level 2 table of a process.
+    /// The 0B...4GiB space is shared with the kernel's TTBR0, and the rest is unmapped.
+    pub fn init_user(&mut self) {
+        let kern = get_ttbr0();
+        for (i, e) in self.tt_lv2.iter_mut().enumerate() {
+            let desc = if i < self.num_lv3 { kern.tt_lv2[i] } else { 0 };
+            unsafe { write_volatile(e, desc) };
         }
     }
 
-    pub fn map(&mut self, vm_addr: u64, phy_addr: u64, flag: u64) {
+    /// Get the level 3 table of the 1TiB... space indexed by lv2idx.
+    /// The table is accessed through TTBR1, see set_table().
+    fn user_lv3(&self, lv2idx: usize) -> Option<&'static mut [u64]> {
+        let desc = unsafe { read_volatile(&self.tt_lv2[lv2idx]) };
+        if desc & 0b11 != 0b11 {
+            return None;
+        }
+
+        let ptr = ((desc & TABLE_ADDR_MASK) + EL1_ADDR_OFFSET) as *mut u64;
+        Some(unsafe { slice::from_raw_parts_mut(ptr, 8192) })
+    }
+
+    fn lv3_entry(&self, vm_addr: u64) -> Option<*mut u64> {
         let lv2idx = ((vm_addr >> 29) & 8191) as usize;
         let lv3idx = ((vm_addr >> 16) & 8191) as usize;
 
         if lv2idx >= (self.num_lv2 * 8192) {
-            // memory access error
-            panic!("memory map error");
+            return None;
         }
 
-        let e = phy_addr & !0xffff | flag;
-
         if lv2idx < OFFSET_USER_HEAP_PAGE {
             let idx = lv2idx * 8192 + lv3idx;
-            unsafe { write_volatile(&mut self.tt_lv3_1[idx], e) };
+            self.tt_lv3.get(idx).map(|e| e as *const u64 as *mut u64)
         } else {
-            let idx = (lv2idx - OFFSET_USER_HEAP_PAGE) * 8192 + lv3idx;
-            unsafe { write_volatile(&mut self.tt_lv3_2[idx], e) };
+            self.user_lv3(lv2idx).map(|t| &mut t[lv3idx] as *mut u64)
         }
     }
 
-    pub fn unmap(&mut self, vm_addr: u64) {
+    /// Check whether a level 3 table covering vm_addr is available.
+    pub fn has_table(&self, vm_addr: u64) -> bool {
+        self.lv3_entry(vm_addr).is_some()
+    }
+
+    /// Use the page at phy_addr as the level 3 table covering vm_addr in the 1TiB... space.
+    /// The page must be mapped to phy_addr + EL1_ADDR_OFFSET in TTBR1 by table_page_flag(),
+    /// and must not be accessible from EL0.
+    pub fn set_table(&mut self, vm_addr: u64, phy_addr: u64) {
         let lv2idx = ((vm_addr >> 29) & 8191) as usize;
-        let lv3idx = ((vm_addr >> 16) & 8191) as usize;
+        if lv2idx < OFFSET_USER_HEAP_PAGE || lv2idx >= (self.num_lv2 * 8192) {
+            panic!("memory map error");
+        }
 
-        if lv2idx >= (self.num_lv2 * 8192) {
-            // memory access error
-            panic!("memory unmap error");
+        let ptr = (phy_addr + EL1_ADDR_OFFSET) as *mut u64;
+        let table = unsafe { slice::from_raw_parts_mut(ptr, 8192) };
+        for e in table.iter_mut() {
+            unsafe { write_volatile(e, 0) };
         }
 
-        if lv2idx < OFFSET_USER_HEAP_PAGE {
-            let idx = lv2idx * 8192 + lv3idx;
-            unsafe { write_volatile(&mut self.tt_lv3_1[idx], 0) };
+        let e = phy_addr & TABLE_ADDR_MASK | 0b11;
+        unsafe { write_volatile(&mut self.tt_lv2[lv2idx], e) };
+    }
+
+    pub fn map(&mut self, vm_addr: u64, phy_addr: u64, flag: u64) {
+        let e = phy_addr & !0xffff | flag;
+
+        if let Some(ptr) = self.lv3_entry(vm_addr) {
+            unsafe { write_volatile(ptr, e) };
         } else {
-            let idx = (lv2idx - OFFSET_USER_HEAP_PAGE) * 8192 + lv3idx;
-            unsafe { write_volatile(&mut self.tt_lv3_2[idx], 0) };
+            // memory access error
+            panic!("memory map error");
         }
     }
 
-    pub fn to_phy_addr(&self, vm_addr: u64) -> Option<u64> {
-        let lv2idx = ((vm_addr >> 29) & 8191) as usize;
-        let lv3idx = ((vm_addr >> 16) & 8191) as usize;
+    pub fn unmap(&mut self, vm_addr: u64) {
+        if let Some(ptr) = self.lv3_entry(vm_addr) {
+            unsafe { write_volatile(ptr, 0) };
+        }
+    }
 
-        let val = if lv2idx < OFFSET_USER_HEAP_PAGE {
-            let idx = lv2idx * 8192 + lv3idx;
-            unsafe { read_volatile(&self.tt_lv3_1[idx]) }
+    /// Get the attributes of the page at vm_addr, that is the descriptor without the address.
+    pub fn get_flag(&self, vm_addr: u64) -> Option<u64> {
+        let val = unsafe { read_volatile(self.lv3_entry(vm_addr)?) };
+        if val == 0 {
+            None
         } else {
-            let idx = (lv2idx - OFFSET_USER_HEAP_PAGE) * 8192 + lv3idx;
-            unsafe { read_volatile(&self.tt_lv3_2[idx]) }
-        };
+            Some(val & !TABLE_ADDR_MASK)
+        }
+    }
+
+    /// Make the page at vm_addr read-only and mark it copy-on-write,
+    /// if it is writable from EL0.
+    /// The page is made writable again by map() with user_page_flag().
+    pub fn set_cow(&mut self, vm_addr: u64) -> bool {
+        if let Some(ptr) = self.lv3_entry(vm_addr) {
+            let val = unsafe { read_volatile(ptr) };
+            if val != 0 && val & FLAG_L3_AP_MASK == FLAG_L3_SH_RW_RW {
+                let e = val & !FLAG_L3_AP_MASK | FLAG_L3_SH_R_R | FLAG_L3_COW;
+                unsafe { write_volatile(ptr, e) };
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Check whether the page at vm_addr is shared copy-on-write.
+    pub fn is_cow(&self, vm_addr: u64) -> bool {
+        self.get_flag(vm_addr)
+            .map_or(false, |flag| flag & FLAG_L3_COW != 0)
+    }
+
+    pub fn to_phy_addr(&self, vm_addr: u64) -> Option<u64> {
+        let val = unsafe { read_volatile(self.lv3_entry(vm_addr)?) };
 
         if val == 0 {
             return None;
@@ -479,13 +545,39 @@ fn set_sctlr(sctlr: u64) {
 }
 
 pub fn user_page_flag() -> u64 {
-    FLAG_L3_XN | FLAG_L3_PXN | FLAG_L3_AF | FLAG_L3_ISH | FLAG_L3_SH_RW_RW | FLAG_L3_ATTR_MEM | 0b11
+    FLAG_L3_XN
+        | FLAG_L3_PXN
+        | FLAG_L3_NG
+        | FLAG_L3_AF
+        | FLAG_L3_ISH
+        | FLAG_L3_SH_RW_RW
+        | FLAG_L3_ATTR_MEM
+        | 0b11
+}
+
+/// flag to map read-only pages of processes
+pub fn user_page_flag_ro() -> u64 {
+    FLAG_L3_XN
+        | FLAG_L3_PXN
+        | FLAG_L3_NG
+        | FLAG_L3_AF
+        | FLAG_L3_ISH
+        | FLAG_L3_SH_R_R
+        | FLAG_L3_ATTR_MEM
+        | 0b11
 }
 
 pub fn kernel_page_flag() -> u64 {
     FLAG_L3_XN | FLAG_L3_PXN | FLAG_L3_AF | FLAG_L3_ISH | FLAG_L3_SH_RW_N | FLAG_L3_ATTR_MEM | 0b11
 }
 
+/// flag to map transition tables in TTBR1
+/// Tables are normal memory of the same cacheability and shareability as the walks in TCR_EL1,
+/// because pages of the pager were used as cacheable memory of processes before.
+pub fn table_page_flag() -> u64 {
+    FLAG_L3_XN | FLAG_L3_PXN | FLAG_L3_AF | FLAG_L3_ISH | FLAG_L3_SH_RW_N | FLAG_L3_ATTR_MEM | 0b11
+}
+
 /// set registers
 pub fn set_regs() {
     let addr = get_memory_map();
@@ -568,8 +660,7 @@ fn init_el1(addr: &Addr) -> (TTable, TTable) {
     let mut table0 = TTable::new(
         addr.tt_el1_ttbr0_start,
         KERN_TTBR0_LV2_TABLE_NUM,
-        KERN_TTBR0_LV3_TABLE_NUM1,
-        KERN_TTBR0_LV3_TABLE_NUM2,
+        KERN_TTBR0_LV3_TABLE_NUM,
     );
 
     table0.init();
@@ -613,9 +704,9 @@ fn init_el1(addr: &Addr) -> (TTable, TTable) {
         bss_start += PAGESIZE;
     }
 
-    // map userland heap
+    // map the pager's memory, which is accessed by EL0 only through the tables of processes
     let mut heap_start = addr.pager_mem_start;
-    let flag = user_page_flag();
+    let flag = kernel_page_flag();
     while heap_start < addr.pager_mem_end {
         table0.map(heap_start, heap_start, flag);
         heap_start += PAGESIZE;
@@ -642,7 +733,6 @@ fn init_el1(addr: &Addr) -> (TTable, TTable) {
         addr.tt_el1_ttbr1_start,
         KERN_TTBR1_LV2_TABLE_NUM,
         KERN_TTBR1_LV3_TABLE_NUM,
-        0,
     );
 
     table1.init();
@@ -706,15 +796,19 @@ fn set_reg_el1(ttbr0: usize, ttbr1: usize) {
     let mmfr = cpu::id_aa64mmfr0_el1::get();
     let b = mmfr & 0xF;
 
-    let tcr: u64 = b << 32 |
+    // 16 bits ASIDs if supported
+    let asid16 = if asid_bits() == 16 { 1 } else { 0 };
+
+    let tcr: u64 = asid16 << 36 |
+         b << 32 |
          3 << 30 | // 64KiB granule, TTBR1_EL1
          3 << 28 | // inner shadable, TTBR1_EL1
-         2 << 26 | // Normal memory, Outer Write-Through Read-Allocate Write-Allocate Cacheable, TTBR1_EL1
+         1 << 26 | // Normal memory, Outer Write-Back Read-Allocate Write-Allocate Cacheable, TTBR1_EL1
          1 << 24 | // Normal memory, Inner Write-Back Read-Allocate Write-Allocate Cacheable, TTBR1_EL1
         22 << 16 | // T1SZ = 22, 2 levels (level 2 and 3 translation tables), 2^42B (4TiB) space
          1 << 14 | // 64KiB granule
          3 << 12 | // inner shadable, TTBR0_EL1
-         2 << 10 | // Normal memory, Outer Write-Through Read-Allocate Write-Allocate Cacheable, TTBR0_EL1
+         1 << 10 | // Normal memory, Outer Write-Back Read-Allocate Write-Allocate Cacheable, TTBR0_EL1
          1 <<  8 | // Normal memory, Inner Write-Back Read-Allocate Write-Allocate Cacheable, TTBR0_EL1
         22; // T0SZ = 22, 2 levels (level 2 and 3 translation tables), 2^42B (4TiB) space
 
@@ -743,6 +837,35 @@ fn set_reg_el1(ttbr0: usize, ttbr1: usize) {
     cpu::isb();
 }
 
+/// Number of bits of ASIDs, which is 8 or 16.
+pub fn asid_bits() -> u32 {
+    if (cpu::id_aa64mmfr0_el1::get() >> 4) & 0xF == 0b0010 {
+        16
+    } else {
+        8
+    }
+}
+
+/// Switch TTBR0 to the table at phy_addr, whose TLB entries are tagged by asid.
+/// The 0B...4GiB space is shared by all tables, so the kernel keeps running.
+pub fn set_ttbr0(phy_addr: u64, asid: u16) {
+    cpu::ttbr0_el1::set(phy_addr | (asid as u64) << 48);
+    cpu::isb();
+}
+
+/// Invalidate the TLB entries tagged by asid on this CPU.
+pub fn tlb_flush_asid(asid: u16) {
+    unsafe {
+        asm!(
+            "dsb nshst
+             tlbi aside1, {}
+             dsb nsh
+             isb",
+             in(reg) (asid as u64) << 48
+        )
+    };
+}
+
 pub fn get_no_cache<T>() -> &'static mut T {
     let addr = get_memory_map();
     let addr = addr.no_cache_start + PAGESIZE * driver::topology::core_pos() as u64;
@@ -4,5 +4,8 @@ pub mod cpu;
 pub mod exception;
 pub mod int;
 pub mod mmu;
+pub mod psci;
 pub mod smc;
 pub mod syscall;
+pub mod timer;
+pub mod uaccess;
@@ -0,0 +1,39 @@
+//! Power State Coordination Interface
+
+use core::arch::asm;
+
+const PSCI_CPU_ON: u64 = 0xC400_0003;
+
+/// Instruction to call PSCI.
+pub enum Conduit {
+    Smc,
+    Hvc,
+}
+
+/// Power on the CPU of `mpidr`.
+/// It starts at `entry` with `ctx` in x0.
+/// Return 0 on success or a negative PSCI error code.
+pub fn cpu_on(conduit: Conduit, mpidr: u64, entry: u64, ctx: u64) -> i64 {
+    let mut ret = PSCI_CPU_ON;
+    unsafe {
+        match conduit {
+            Conduit::Smc => asm!(
+                "smc #0",
+                inout("x0") ret,
+                in("x1") mpidr,
+                in("x2") entry,
+                in("x3") ctx,
+                clobber_abi("C"),
+            ),
+            Conduit::Hvc => asm!(
+                "hvc #0",
+                inout("x0") ret,
+                in("x1") mpidr,
+                in("x2") entry,
+                in("x3") ctx,
+                clobber_abi("C"),
+            ),
+        }
+    }
+    ret as i64
+}
@@ -4,6 +4,7 @@ use core::arch::asm;
 
 extern "C" {
     fn smc_done(arg: u64);
+    fn smc_on_done(arg: u64);
 }
 
 pub fn done() -> ! {
@@ -23,3 +24,21 @@ pub fn done() -> ! {
 
     unreachable!()
 }
+
+/// Switch to normal world after a CPU has been powered on by vector_cpu_on_entry.
+pub fn on_done() -> ! {
+    let start = mmu::get_stack_el1_start();
+    let aff = core_pos() as u64;
+    let sp = start - mmu::STACK_SIZE * aff + mmu::EL1_ADDR_OFFSET;
+
+    unsafe {
+        asm! {
+            "mov     sp, {}
+             mov     x0, #0
+             b       smc_on_done",
+            in(reg) sp
+        }
+    }
+
+    unreachable!()
+}
@@ -1,41 +1,221 @@
 use crate::{
     allocator::set_user_allocator,
     paging,
-    process::{self, get_raw_id},
-    syscall::{self, Locator},
+    process::{self, get_raw_id, PROCESS_MAX},
+    syscall::{self, ExitStatus, Locator, ProcStat},
+    tty,
 };
+use alloc::vec::Vec;
+use core::mem::size_of;
 
-use super::context::GpRegs;
+use super::{
+    context::GpRegs,
+    uaccess::{
+        copy_from_user, copy_to_user, is_user_range, read_user, write_user, write_user_slice,
+    },
+};
+
+/// Maximum length of bytes copied at once by SYS_READ and SYS_WRITE.
+const IO_CHUNK_LEN: usize = 4096;
 
+/// Pointers passed by processes are accessed only through `uaccess`,
+/// and a syscall fails if a pointer is invalid.
 pub(super) fn handle64(regs: &GpRegs) -> i64 {
     match regs.x0 {
         syscall::SYS_SPAWN => {
-            if let Some(pid) = process::spawn(regs.x1) {
+            // out of range priorities are saturated to be invalid
+            let priority = regs.x3.min(u8::MAX as u64) as u8;
+
+            // copy the arguments from the user heap to the kernel heap
+            let len = regs.x5 as usize;
+            if len > syscall::ARGS_MAX_LEN {
+                return -1;
+            }
+            let args = if let Some(args) = copy_from_user(regs.x4, len) {
+                args
+            } else {
+                return -1;
+            };
+
+            if let Some(pid) = process::spawn(regs.x1, regs.x2 as usize, priority, &args) {
                 pid as i64
             } else {
                 -1
             }
         }
-        syscall::SYS_EXIT => process::exit(),
+        syscall::SYS_FORK => {
+            // the child returns through the copy of this frame
+            if let Some(pid) = process::fork(regs as *const GpRegs as u64) {
+                pid as i64
+            } else {
+                -1
+            }
+        }
+        syscall::SYS_EXIT => process::exit(ExitStatus::Exited(regs.x1 as i32)),
+        syscall::SYS_WAIT => {
+            // check the buffer before reaping the child
+            if !is_user_range(regs.x2, ExitStatus::BYTES_LEN) {
+                return -1;
+            }
+
+            // the status is serialized, because the enum has uninitialized bytes
+            match process::wait(regs.x1 as u32) {
+                Some(status) if copy_to_user(regs.x2, &status.to_bytes()) => 0,
+                _ => -1,
+            }
+        }
         syscall::SYS_SCHED => {
             process::schedule();
             0
         }
         syscall::SYS_GETPID => process::get_pid() as i64,
         syscall::SYS_RECV => {
-            let src = unsafe { &mut *(regs.x1 as *mut Locator) };
-            process::recv(src) as i64
+            // check the buffers before taking a message out of the mailbox
+            if !is_recv_buf(regs) {
+                return -1;
+            }
+
+            let mut src = Locator::Unknown;
+            let data = process::recv(&mut src);
+            copy_msg(&src, &data, regs)
+        }
+        syscall::SYS_TRY_RECV => {
+            if !is_recv_buf(regs) {
+                return -1;
+            }
+
+            let mut src = Locator::Unknown;
+            if let Some(data) = process::try_recv(&mut src) {
+                copy_msg(&src, &data, regs)
+            } else {
+                -1
+            }
+        }
+        syscall::SYS_RECV_TIMEOUT => {
+            if !is_recv_buf(regs) {
+                return -1;
+            }
+
+            let mut src = Locator::Unknown;
+            if let Some(data) = process::recv_timeout(&mut src, regs.x4) {
+                copy_msg(&src, &data, regs)
+            } else {
+                -1
+            }
+        }
+        syscall::SYS_SEND | syscall::SYS_SEND_WAIT => {
+            let len = regs.x3 as usize;
+            if len > syscall::MSG_MAX_LEN {
+                return 0;
+            }
+
+            // copy the destination and the payload from the user heap to the kernel heap
+            let (dst, data) = match (user_locator(regs.x1), copy_from_user(regs.x2, len)) {
+                (Some(dst), Some(data)) => (dst, data),
+                _ => return 0,
+            };
+
+            let ret = if regs.x0 == syscall::SYS_SEND_WAIT {
+                process::send_wait(&dst, data)
+            } else {
+                process::send(&dst, data)
+            };
+
+            if ret {
+                1
+            } else {
+                0
+            }
+        }
+        syscall::SYS_SEND_AFTER => {
+            let len = regs.x3 as usize;
+            if len > syscall::MSG_MAX_LEN {
+                return 0;
+            }
+
+            // copy the destination and the payload from the user heap to the kernel heap
+            let (dst, data) = match (user_locator(regs.x1), copy_from_user(regs.x2, len)) {
+                (Some(dst), Some(data)) => (dst, data),
+                _ => return 0,
+            };
+
+            if process::send_after(&dst, data, regs.x4) {
+                1
+            } else {
+                0
+            }
+        }
+        syscall::SYS_SEND_NAME | syscall::SYS_SEND_NAME_WAIT => {
+            let pid = user_name(regs.x1, regs.x2).and_then(|name| process::whereis(&name));
+
+            let len = regs.x4 as usize;
+            let pid = match pid {
+                Some(pid) if len <= syscall::MSG_MAX_LEN => pid,
+                _ => return 0,
+            };
+
+            // copy the payload from the user heap to the kernel heap
+            let data = if let Some(data) = copy_from_user(regs.x3, len) {
+                data
+            } else {
+                return 0;
+            };
+            let dst = Locator::Process(pid);
+            let ret = if regs.x0 == syscall::SYS_SEND_NAME_WAIT {
+                process::send_wait(&dst, data)
+            } else {
+                process::send(&dst, data)
+            };
+
+            if ret {
+                1
+            } else {
+                0
+            }
+        }
+        syscall::SYS_REGISTER => match user_name(regs.x1, regs.x2) {
+            Some(name) if process::register(&name) => 1,
+            _ => 0,
+        },
+        syscall::SYS_WHEREIS => {
+            match user_name(regs.x1, regs.x2).and_then(|name| process::whereis(&name)) {
+                Some(pid) => pid as i64,
+                None => -1,
+            }
+        }
+        syscall::SYS_UNREGISTER => {
+            if process::unregister() {
+                1
+            } else {
+                0
+            }
+        }
+        syscall::SYS_SLEEP => {
+            process::sleep(regs.x1);
+            0
         }
-        syscall::SYS_SEND => {
-            let dst = unsafe { &*(regs.x1 as *const Locator) };
-            if process::send(dst, regs.x2 as u32) {
+        syscall::SYS_IRQ_SUBSCRIBE => {
+            if process::subscribe_irq(regs.x1 as u32) {
+                1
+            } else {
+                0
+            }
+        }
+        syscall::SYS_IRQ_ACK => {
+            if process::ack_irq(regs.x1 as u32) {
                 1
             } else {
                 0
             }
         }
         syscall::SYS_SET_ALLOC => {
-            let ptr = unsafe { &mut *(regs.x1 as *mut memac::Allocator) };
+            // the allocator is used by the process, so it stays in the user heap,
+            // and only its range is checked
+            if !is_user_range(regs.x1, size_of::<memac::Allocator>()) {
+                return -1;
+            }
+
+            let ptr = regs.x1 as *mut memac::Allocator;
             if let Some(id) = get_raw_id() {
                 set_user_allocator(id, ptr);
             }
@@ -47,10 +227,146 @@ pub(super) fn handle64(regs: &GpRegs) -> i64 {
             }
             0
         }
+        syscall::SYS_MMAP => {
+            let prot = regs.x2.min(u8::MAX as u64) as u8;
+            if let Some(addr) =
+                process::get_raw_id().and_then(|id| paging::mmap_anon(id, regs.x1 as usize, prot))
+            {
+                addr as i64
+            } else {
+                0
+            }
+        }
+        syscall::SYS_MUNMAP => {
+            if let Some(id) = process::get_raw_id() {
+                if paging::munmap(id, regs.x1 as usize, regs.x2 as usize) {
+                    return 1;
+                }
+            }
+            0
+        }
+        syscall::SYS_SET_QUOTA => {
+            let pages = regs.x2.min(u32::MAX as u64) as u32;
+            if process::set_quota(regs.x1 as u32, pages) {
+                1
+            } else {
+                0
+            }
+        }
         syscall::SYS_KILL => {
             process::kill(regs.x1 as u32);
             0
         }
+        syscall::SYS_MONITOR => {
+            if process::monitor(regs.x1 as u32) {
+                1
+            } else {
+                0
+            }
+        }
+        syscall::SYS_LINK => {
+            if process::link(regs.x1 as u32) {
+                1
+            } else {
+                0
+            }
+        }
+        syscall::SYS_TRAP_EXIT => {
+            process::trap_exit(regs.x1 != 0);
+            0
+        }
+        syscall::SYS_SCHED_STATS => match process::sched_stats(regs.x1 as usize) {
+            Some(stats) if write_user(regs.x2, &stats) => 0,
+            _ => -1,
+        },
+        syscall::SYS_PS => {
+            // no more processes than PROCESS_MAX are stored
+            let len = (regs.x2 as usize).min(PROCESS_MAX);
+            if !is_user_range(regs.x1, len * size_of::<ProcStat>()) {
+                return -1;
+            }
+
+            let mut buf = vec![ProcStat::new(); len];
+            let num = process::snapshot(&mut buf);
+            if write_user_slice(regs.x1, &buf[..num.min(len)]) {
+                num as i64
+            } else {
+                -1
+            }
+        }
+        syscall::SYS_SETPRIORITY => {
+            let priority = regs.x2.min(u8::MAX as u64) as u8;
+            if process::set_priority(regs.x1 as u32, priority) {
+                1
+            } else {
+                0
+            }
+        }
+        syscall::SYS_READ => {
+            // a read may return fewer bytes than requested
+            let len = (regs.x2 as usize).min(IO_CHUNK_LEN);
+            if !is_user_range(regs.x1, len) {
+                return -1;
+            }
+
+            let mut buf = vec![0; len];
+            let n = tty::handler::read(&mut buf);
+            if copy_to_user(regs.x1, &buf[..n]) {
+                n as i64
+            } else {
+                -1
+            }
+        }
+        syscall::SYS_WRITE => {
+            let len = regs.x2 as usize;
+            if !is_user_range(regs.x1, len) {
+                return -1;
+            }
+
+            for pos in (0..len).step_by(IO_CHUNK_LEN) {
+                let n = (len - pos).min(IO_CHUNK_LEN);
+                if let Some(buf) = copy_from_user(regs.x1 + pos as u64, n) {
+                    tty::handler::write(&buf);
+                } else {
+                    return -1;
+                }
+            }
+            len as i64
+        }
         _ => 0,
     }
 }
+
+/// Copy a name of a process from the user heap.
+/// None is returned if it is too long or invalid.
+fn user_name(ptr: u64, len: u64) -> Option<Vec<u8>> {
+    let len = len as usize;
+    if len > syscall::NAME_MAX_LEN {
+        return None;
+    }
+
+    copy_from_user(ptr, len)
+}
+
+/// Read a Locator from the user heap.
+fn user_locator(ptr: u64) -> Option<Locator> {
+    Locator::from_raw(read_user::<[u32; 2]>(ptr)?)
+}
+
+/// Check the buffers of SYS_RECV, SYS_TRY_RECV and SYS_RECV_TIMEOUT,
+/// x1 for the source and x2 for the payload whose length is x3.
+fn is_recv_buf(regs: &GpRegs) -> bool {
+    is_user_range(regs.x1, size_of::<Locator>()) && is_user_range(regs.x2, regs.x3 as usize)
+}
+
+/// Copy a received message and its source to the user buffers,
+/// and return the length of the message.
+/// The message is lost if the buffers have been unmapped meanwhile.
+fn copy_msg(src: &Locator, data: &[u8], regs: &GpRegs) -> i64 {
+    let n = data.len().min(regs.x3 as usize);
+    if write_user(regs.x1, &src.to_raw()) && copy_to_user(regs.x2, &data[..n]) {
+        data.len() as i64
+    } else {
+        -1
+    }
+}
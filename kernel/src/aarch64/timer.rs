@@ -0,0 +1,77 @@
+//! Per-CPU tick of the EL1 physical timer (CNTP).
+//! The tick is used to preempt a running process when its time slice expires.
+
+use super::cpu;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+const CNTP_CTL_ENABLE: u64 = 1 << 0; // timer enabled
+const CNTP_CTL_IMASK: u64 = 1 << 1; // interrupt masked
+const CNTP_CTL_ISTATUS: u64 = 1 << 2; // timer condition is met
+
+/// Default time slice in milliseconds.
+pub const DEFAULT_QUANTUM_MS: u64 = 10;
+
+static QUANTUM_MS: AtomicU64 = AtomicU64::new(DEFAULT_QUANTUM_MS);
+
+/// Set the time slice in milliseconds.
+/// It takes effect from the next tick.
+pub fn set_quantum(msec: u64) {
+    if msec > 0 {
+        QUANTUM_MS.store(msec, Ordering::Relaxed);
+    }
+}
+
+/// Get the time slice in milliseconds.
+pub fn get_quantum() -> u64 {
+    QUANTUM_MS.load(Ordering::Relaxed)
+}
+
+fn quantum_ticks() -> u64 {
+    msec_to_count(get_quantum())
+}
+
+/// Get the current value of the physical counter.
+pub fn get_count() -> u64 {
+    cpu::cntpct_el0::get()
+}
+
+/// Convert milliseconds to a count of the physical counter.
+/// It saturates at u64::MAX.
+pub fn msec_to_count(msec: u64) -> u64 {
+    let frq = cpu::cntfrq_el0::get() & 0xffffffff;
+    (frq as u128 * msec as u128 / 1000).min(u64::MAX as u128) as u64
+}
+
+/// Convert a count of the physical counter to microseconds.
+pub fn count_to_usec(count: u64) -> u64 {
+    let frq = cpu::cntfrq_el0::get() & 0xffffffff;
+    if frq == 0 {
+        0
+    } else {
+        (count as u128 * 1000000 / frq as u128) as u64
+    }
+}
+
+/// Start the tick of this CPU.
+pub fn init() {
+    reload();
+    cpu::cntp_ctl_el0::set(CNTP_CTL_ENABLE);
+}
+
+/// Stop the tick of this CPU.
+pub fn stop() {
+    cpu::cntp_ctl_el0::set(CNTP_CTL_IMASK);
+}
+
+/// Program the next tick one time slice later.
+/// This also deasserts the interrupt of the current tick.
+pub fn reload() {
+    let now = cpu::cntpct_el0::get();
+    cpu::cntp_cval_el0::set(now + quantum_ticks());
+}
+
+/// Has the tick of this CPU fired?
+pub fn is_fired() -> bool {
+    let ctl = cpu::cntp_ctl_el0::get();
+    ctl & CNTP_CTL_ENABLE != 0 && ctl & CNTP_CTL_ISTATUS != 0
+}
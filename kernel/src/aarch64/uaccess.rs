@@ -0,0 +1,140 @@
+//! Access to the memory of the running process from the kernel.
+//!
+//! Addresses passed by processes are checked against `allocator::is_user_mem`
+//! and the anonymous mappings of `paging::mmap_anon`, and bytes are copied by `copy_user` of boot.S.
+//! If a fault cannot be resolved during the copy, `page_fault_el1` resumes at
+//! `copy_user_fault`, and the copy fails instead of panicking.
+
+use crate::{
+    allocator, paging, process,
+    syscall::{ProcStat, SchedStats},
+};
+use alloc::vec::Vec;
+use core::mem::{size_of, MaybeUninit};
+
+extern "C" {
+    fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    fn copy_user_fault();
+}
+
+/// Check that [addr, addr + len) is in the memory or an anonymous mapping of the running process.
+/// The canary of the stack is excluded.
+pub fn is_user_range(addr: u64, len: usize) -> bool {
+    let id = if let Some(id) = process::get_raw_id() {
+        id
+    } else {
+        return false;
+    };
+    let base = paging::layout(id);
+
+    if len == 0 {
+        return true;
+    }
+
+    let start = addr as usize;
+    let last = if let Some(last) = start.checked_add(len - 1) {
+        last
+    } else {
+        return false;
+    };
+
+    if paging::is_mmap_range(id, start, last) {
+        return true;
+    }
+
+    // the canary is the lowest page of the memory
+    !allocator::is_user_canary(base, start)
+        && allocator::is_user_mem(base, start)
+        && allocator::is_user_mem(base, last)
+}
+
+/// Copy `len` bytes at `src` of the running process to the kernel heap.
+/// None is returned if the range is invalid or a fault occurs.
+pub fn copy_from_user(src: u64, len: usize) -> Option<Vec<u8>> {
+    if !is_user_range(src, len) {
+        return None;
+    }
+
+    let mut buf = Vec::with_capacity(len);
+    if unsafe { copy_user(buf.as_mut_ptr(), src as *const u8, len) } != 0 {
+        return None;
+    }
+    unsafe { buf.set_len(len) };
+
+    Some(buf)
+}
+
+/// Copy data to `dst` of the running process.
+/// false is returned if the range is invalid or a fault occurs.
+pub fn copy_to_user(dst: u64, data: &[u8]) -> bool {
+    if !is_user_range(dst, data.len()) {
+        return false;
+    }
+
+    unsafe { copy_user(dst as *mut u8, data.as_ptr(), data.len()) == 0 }
+}
+
+/// Read a value at `src` of the running process.
+/// T must be valid for any bit pattern.
+pub fn read_user<T: Copy>(src: u64) -> Option<T> {
+    if !is_user_range(src, size_of::<T>()) {
+        return None;
+    }
+
+    let mut val = MaybeUninit::<T>::uninit();
+    let dst = val.as_mut_ptr() as *mut u8;
+    if unsafe { copy_user(dst, src as *const u8, size_of::<T>()) } != 0 {
+        return None;
+    }
+
+    Some(unsafe { val.assume_init() })
+}
+
+/// Types which can be copied to processes as they are.
+///
+/// # Safety
+///
+/// Every byte of a value must be initialized,
+/// so the type must be `#[repr(C)]` without padding, and must not be an enum with payloads.
+/// Otherwise, bytes of the kernel stack or heap are leaked to processes.
+pub unsafe trait Plain: Copy {}
+
+unsafe impl Plain for u32 {}
+unsafe impl Plain for u64 {}
+unsafe impl<T: Plain, const N: usize> Plain for [T; N] {}
+unsafe impl Plain for SchedStats {} // u64 only
+unsafe impl Plain for ProcStat {} // u32 x 6 (RunState is u32) and u64
+
+/// Write a value to `dst` of the running process.
+/// false is returned if the range is invalid or a fault occurs.
+pub fn write_user<T: Plain>(dst: u64, val: &T) -> bool {
+    if !is_user_range(dst, size_of::<T>()) {
+        return false;
+    }
+
+    let src = val as *const T as *const u8;
+    unsafe { copy_user(dst as *mut u8, src, size_of::<T>()) == 0 }
+}
+
+/// Write values to `dst` of the running process.
+/// false is returned if the range is invalid or a fault occurs.
+pub fn write_user_slice<T: Plain>(dst: u64, vals: &[T]) -> bool {
+    let len = size_of::<T>() * vals.len();
+    if !is_user_range(dst, len) {
+        return false;
+    }
+
+    let src = vals.as_ptr() as *const u8;
+    unsafe { copy_user(dst as *mut u8, src, len) == 0 }
+}
+
+/// Get the address to resume at if a fault at `elr` occurs in `copy_user`.
+pub(super) fn fixup(elr: u64) -> Option<u64> {
+    let start = copy_user as u64;
+    let fault = copy_user_fault as u64;
+    if start <= elr && elr < fault {
+        Some(fault)
+    } else {
+        None
+    }
+}
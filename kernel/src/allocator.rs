@@ -4,7 +4,6 @@ use crate::{
     process::{get_raw_id_user, is_kernel, PROCESS_MAX},
     syscall,
 };
-use arr_macro::arr;
 use core::{
     alloc::{GlobalAlloc, Layout},
     ptr::null_mut,
@@ -14,13 +13,20 @@ use memac::Allocator;
 const STACK_SIZE: usize = 1024 * 1024 * 2; // 2MiB
 const SLAB_SIZE: usize = 1024 * 1024 * 30; // 30MiB
 const BUDDY_SIZE: usize = 1024 * 1024 * 32; // 32MiB
-const USER_MEM_OFFSET: usize = 1026 * 1024 * 1024 * 1024; // 1TiB
+const USER_MEM_OFFSET: usize = 1026 * 1024 * 1024 * 1024; // 1TiB + 2GiB
 const USER_MEM_SIZE: usize = BUDDY_SIZE + SLAB_SIZE + STACK_SIZE; // must be 64MiB
 const KERN_HEAP_OFFSET: usize = 1024 * 1024 * 64; // 64MiB
-
+const MMAP_OFFSET: usize = 2050 * 1024 * 1024 * 1024; // 2TiB + 2GiB
+const MMAP_SIZE: usize = 1024 * 1024 * 64; // 64MiB
+const KERN_STACK_OFFSET: usize = 1024 * 1024 * 1024 * 1024; // 1TiB, in TTBR1
+const KERN_STACK_SIZE: usize = 1024 * 128; // 128KiB
+
+// user is looked up from EL0 by alloc() and dealloc(),
+// so it cannot be placed on the kernel heap which is mapped only for EL1,
+// and it has the entries of all the slots instead of growing with the process table
 #[global_allocator]
 static mut ALLOCATOR: UserKernAllocator = UserKernAllocator {
-    user: arr![null_mut(); 256], // 256 == PROCESS_MAX
+    user: [null_mut(); PROCESS_MAX],
     kernel: Allocator::new(),
     uid: [0; CORE_COUNT],
 };
@@ -28,7 +34,7 @@ static mut ALLOCATOR: UserKernAllocator = UserKernAllocator {
 struct UserKernAllocator {
     user: [*mut Allocator; PROCESS_MAX],
     kernel: Allocator,
-    uid: [u8; CORE_COUNT],
+    uid: [u32; CORE_COUNT],
 }
 
 unsafe impl GlobalAlloc for UserKernAllocator {
@@ -77,49 +83,88 @@ pub fn init_kernel() -> (usize, usize, usize, usize) {
     (offset, offset_slab, offset_slab, offset_slab + BUDDY_SIZE)
 }
 
-fn user_offset(id: u8) -> usize {
+fn user_offset(id: u32) -> usize {
     USER_MEM_OFFSET + id as usize * USER_MEM_SIZE
 }
 
-pub fn user_mem(id: u8) -> (usize, usize) {
+pub fn user_mem(id: u32) -> (usize, usize) {
     let offset = user_offset(id);
     (offset, offset + USER_MEM_SIZE)
 }
 
-pub fn user_canary(id: u8) -> *mut u8 {
+pub fn user_canary(id: u32) -> *mut u8 {
     user_offset(id) as *mut u8
 }
 
 /// Get user stack
-pub fn user_stack(id: u8) -> *mut u8 {
+pub fn user_stack(id: u32) -> *mut u8 {
     (user_offset(id) + STACK_SIZE) as *mut u8
 }
 
+/// Get the address where the arguments of id's process are placed.
+/// The length of the arguments (u64) is stored at the top of the stack,
+/// and the arguments are placed just below it.
+pub fn user_args(id: u32) -> *mut u64 {
+    (user_stack(id) as usize - 8) as *mut u64
+}
+
 /// Check addr is the canary region of id's process
 /// If true, stack overflow
-pub fn is_user_canary(id: u8, addr: usize) -> bool {
+pub fn is_user_canary(id: u32, addr: usize) -> bool {
     let offset = user_offset(id);
     addr & !(mmu::PAGESIZE - 1) as usize == offset
 }
 
 /// Check addr is a heap address of id's process
-pub fn is_user_mem(id: u8, addr: usize) -> bool {
+pub fn is_user_mem(id: u32, addr: usize) -> bool {
     let offset = user_offset(id);
     offset <= addr && addr < offset + USER_MEM_SIZE
 }
 
+/// Get the range reserved for anonymous mappings of id's process
+pub fn user_mmap(id: u32) -> (usize, usize) {
+    let offset = MMAP_OFFSET + id as usize * MMAP_SIZE;
+    (offset, offset + MMAP_SIZE)
+}
+
+/// Check addr is in the range for anonymous mappings of id's process
+pub fn is_user_mmap(id: u32, addr: usize) -> bool {
+    let (start, end) = user_mmap(id);
+    start <= addr && addr < end
+}
+
+/// Get the range of the kernel stack of id's process, [start, end).
+/// The kernel runs on it while the process is in the kernel.
+/// It is placed in TTBR1 at 1TiB + 256KiB * id, and the lower 128KiB is left unmapped as the canary.
+pub fn kern_stack(id: u32) -> (usize, usize) {
+    let offset =
+        KERN_STACK_OFFSET + mmu::EL1_ADDR_OFFSET as usize + id as usize * KERN_STACK_SIZE * 2;
+    (offset + KERN_STACK_SIZE, offset + KERN_STACK_SIZE * 2)
+}
+
 fn unmap_user_mem(start: usize, end: usize) {
     syscall::unmap(start, end);
 }
 
 /// Memory Layout
-/// +-----------------------------+ 1TiB (id = 0)
+///
+/// Every slot has its own 64MiB region, and
+/// 64MiB * PROCESS_MAX (16384) = 1TiB of address space is reserved.
+/// The region is sparse, page tables and pages are mapped on demand.
+/// A process has its own TTBR0, where only the region of its slot is mapped.
+///
+/// Anonymous mappings of `mmap_anon` are placed in another 64MiB region of the slot,
+/// which starts at 2TiB + 2GiB + 64MiB * id.
+/// Pages of both regions are limited by the page quota of the process.
+/// A child of fork uses the regions of its parent's slot, see `paging::layout`.
+///
+/// +-----------------------------+ 1TiB + 2GiB (id = 0)
 /// | 2MiB stack space            |
 /// +-----------------------------+
 /// | 30MiB slab allocator space  |
 /// +-----------------------------+
 /// | 32MiB buddy allocator space |
-/// +-----------------------------+ 1TiB + 64MiB (id = 1)
+/// +-----------------------------+ 1TiB + 2GiB + 64MiB (id = 1)
 /// | 2MiB stack space            |
 /// +-----------------------------+
 /// | 30MiB slab allocator space  |
@@ -127,7 +172,7 @@ fn unmap_user_mem(start: usize, end: usize) {
 /// | 32MiB buddy allocator space |
 /// +-----------------------------+
 /// ...
-pub fn set_user_allocator(id: u8, ptr: *mut Allocator) {
+pub fn set_user_allocator(id: u32, ptr: *mut Allocator) {
     unsafe {
         let allc = &mut *ptr;
         let offset = user_offset(id);
@@ -138,7 +183,13 @@ pub fn set_user_allocator(id: u8, ptr: *mut Allocator) {
     }
 }
 
-pub fn unset_user_allocator(id: u8) {
+/// Let the child of fork use the copy of the parent's allocator.
+/// The copy is at the same address, because the child uses the addresses of the parent.
+pub fn fork_user_allocator(parent: u32, child: u32) {
+    unsafe { ALLOCATOR.user[child as usize] = ALLOCATOR.user[parent as usize] };
+}
+
+pub fn unset_user_allocator(id: u32) {
     unsafe { ALLOCATOR.user[id as usize] = null_mut() };
 }
 
@@ -148,10 +199,10 @@ fn on_oom(layout: Layout) -> ! {
     uart::puts("memory allocation error: size = ");
     uart::decimal(size);
     uart::puts("\n");
-    uart::puts("exiting...\n");
     if is_kernel() {
-        loop {}
+        panic!("exhausted the kernel heap");
     } else {
-        syscall::exit();
+        uart::puts("exiting...\n");
+        syscall::exit(-1);
     }
 }
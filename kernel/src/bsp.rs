@@ -12,6 +12,13 @@ mod allwinner;
 #[cfg(feature = "pine64")]
 use allwinner as board;
 //---------------------------------------------------
+// QEMU virt machine
+#[cfg(feature = "virt")]
+mod virt;
+
+#[cfg(feature = "virt")]
+use virt as board;
+//---------------------------------------------------
 
 pub const SYSCNT_FRQ: u32 = board::SYSCNT_FRQ;
 type BoardInit = board::Init; // board dependent initializer
@@ -24,6 +31,12 @@ pub mod uart;
 pub trait BSPInit {
     fn early_init();
     fn init();
+
+    /// per-CPU initialization of a secondary CPU
+    fn init_secondary();
+
+    /// power on the secondary CPUs, which jump to `_start`
+    fn start_secondaries();
 }
 
 impl BoardInit where BoardInit: BSPInit {}
@@ -38,3 +51,11 @@ pub fn init() {
     int::init();
     BoardInit::init();
 }
+
+pub fn init_secondary() {
+    BoardInit::init_secondary();
+}
+
+pub fn start_secondaries() {
+    BoardInit::start_secondaries();
+}
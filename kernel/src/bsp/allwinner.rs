@@ -1,12 +1,35 @@
 pub(super) mod memory;
 
 use super::BSPInit;
+use crate::driver::{
+    gic::{self, GICVer},
+    uart::{sunxi_uart::SunxiUART, UART},
+};
+use memory::UART0_BASE;
 
 pub const SYSCNT_FRQ: u32 = 24000000;
 
+// GIC-400 of Allwinner A64
+const GICD_BASE: usize = 0x01c81000;
+const GICC_BASE: usize = 0x01c82000;
+
 pub(super) struct Init {}
 
 impl BSPInit for Init {
-    fn early_init() {}
-    fn init() {}
+    fn early_init() {
+        let uart0 = SunxiUART::new(UART0_BASE);
+        uart0.init(0, 0);
+        super::uart::init(uart0);
+    }
+    fn init() {
+        gic::init(GICC_BASE, GICD_BASE, GICVer::V2);
+    }
+
+    fn init_secondary() {
+        gic::init_secondary();
+    }
+
+    fn start_secondaries() {
+        // the normal world powers on secondary CPUs, which enter through vector_cpu_on_entry
+    }
 }
@@ -6,3 +6,5 @@ pub(in crate::bsp) const ROM_END: u64 = 0x00010000;
 pub(in crate::bsp) const SRAM_START: u64 = 0x00010000;
 pub(in crate::bsp) const SRAM_END: u64 = 0x00054000;
 pub(in crate::bsp) const DRAM_BASE: u64 = 0x40000000;
+
+pub(in crate::bsp) const UART0_BASE: usize = 0x01c28000;
@@ -2,11 +2,11 @@ use crate::driver::delays::{self, Delays};
 use core::arch::asm;
 
 //-------------------------------------------------------------------------
-// Raspberry Pi 3 (Qemu) or Pine64
-#[cfg(any(feature = "raspi3", feature = "pine64"))]
+// Raspberry Pi 3 (Qemu), Pine64 or QEMU virt
+#[cfg(any(feature = "raspi3", feature = "pine64", feature = "virt"))]
 type DevDelays = delays::generic::Delays;
 
-#[cfg(any(feature = "raspi3", feature = "pine64"))]
+#[cfg(any(feature = "raspi3", feature = "pine64", feature = "virt"))]
 const BSP_DELAYS: DevDelays = DevDelays::new();
 //-------------------------------------------------------------------------
 // Raspberry Pi 4
@@ -1,5 +1,5 @@
 /// Board specific interrupt handler
-use crate::global::GlobalVar;
+use crate::{cpuint, global::GlobalVar};
 use synctools::rwlock;
 
 // Raspberry Pi 4, Broadcom BCM2xxx
@@ -18,14 +18,92 @@ type DevIRQManager = crate::driver::gic::IRQManager;
 #[cfg(feature = "pine64")]
 pub type DevIRQNumber = crate::driver::gic::IRQNumber;
 
+// QEMU virt machine
+#[cfg(feature = "virt")]
+type DevIRQManager = crate::driver::gic::IRQManager;
+
+// QEMU virt machine
+#[cfg(feature = "virt")]
+pub type DevIRQNumber = crate::driver::gic::IRQNumber;
+
 impl DevIRQManager where DevIRQManager: IRQManager {}
 
+// Raspberry Pi 4, Broadcom BCM2xxx
+#[cfg(any(feature = "raspi3", feature = "raspi4"))]
+pub const IRQ_TIMER: DevIRQNumber = super::raspi::int::IRQ_TIMER;
+
+// Raspberry Pi 4, Broadcom BCM2xxx
+#[cfg(any(feature = "raspi3", feature = "raspi4"))]
+pub const IRQ_UART: DevIRQNumber = super::raspi::int::IRQ_UART;
+
+// Raspberry Pi 4, Broadcom BCM2xxx
+#[cfg(any(feature = "raspi3", feature = "raspi4"))]
+pub const IRQ_IPI: DevIRQNumber = super::raspi::int::IRQ_IPI;
+
+// Pine64, Allwineer sunxi
+// EL1 physical timer (non-secure, PPI 14)
+#[cfg(feature = "pine64")]
+pub const IRQ_TIMER: DevIRQNumber = 30;
+
+// Pine64, Allwineer sunxi
+// inter-processor interrupt (SGI 8, secure)
+#[cfg(feature = "pine64")]
+pub const IRQ_IPI: DevIRQNumber = 8;
+
+// Pine64, Allwineer sunxi
+// UART0 (SPI 0)
+#[cfg(feature = "pine64")]
+pub const IRQ_UART: DevIRQNumber = 32;
+
+// QEMU virt machine
+// EL1 physical timer (non-secure, PPI 14)
+#[cfg(feature = "virt")]
+pub const IRQ_TIMER: DevIRQNumber = 30;
+
+// QEMU virt machine
+// inter-processor interrupt (SGI 8, secure)
+#[cfg(feature = "virt")]
+pub const IRQ_IPI: DevIRQNumber = 8;
+
+// QEMU virt machine
+// PL011 UART0 (SPI 1)
+#[cfg(feature = "virt")]
+pub const IRQ_UART: DevIRQNumber = 33;
+
+#[derive(Clone, Copy)]
 pub struct IRQ<T> {
     description: &'static str,
     handler: fn(T),
+    is_oneshot: bool,
 }
 
 impl<T> IRQ<T> {
+    pub const fn new(description: &'static str, handler: fn(T)) -> Self {
+        IRQ {
+            description,
+            handler,
+            is_oneshot: false,
+        }
+    }
+
+    /// The interrupt is disabled before the handler is called,
+    /// and stays disabled until it is enabled by `enable_irq_num`.
+    pub const fn new_oneshot(description: &'static str, handler: fn(T)) -> Self {
+        IRQ {
+            description,
+            handler,
+            is_oneshot: true,
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        self.description
+    }
+
+    pub fn is_oneshot(&self) -> bool {
+        self.is_oneshot
+    }
+
     pub fn handle(&self, n: T) {
         (self.handler)(n);
     }
@@ -41,10 +119,50 @@ pub trait IRQManager {
     fn new() -> Self;
     fn enable(&self, irq_num: Self::IRQNumberType);
     fn disable(&self, irq_num: Self::IRQNumberType);
+
+    /// Enable a per-CPU interrupt on this CPU.
+    fn enable_local(&self, irq_num: Self::IRQNumberType) {
+        self.enable(irq_num);
+    }
+
+    /// Send the inter-processor interrupt `irq_num` to the CPUs of `cpu_mask`.
+    fn send_ipi(&self, _irq_num: Self::IRQNumberType, _cpu_mask: u8) {}
+
+    /// Get a pending interrupt.
+    /// It must be passed to `ack` after handling it.
+    fn pending(&self) -> Option<Self::IRQNumberType>;
+
     fn ack(&self, irq_num: Self::IRQNumberType);
-    fn handle(&self, irq_num: Self::IRQNumberType);
 
-    fn register_handler(&mut self, irq_num: Self::IRQNumberType, handler: IRQ<Self::IRQNumberType>);
+    /// Get the handler of the interrupt.
+    /// It is called after releasing the lock of the manager.
+    fn handler(&self, irq_num: Self::IRQNumberType) -> Option<IRQ<Self::IRQNumberType>>;
+
+    /// Set the priority of the interrupt. A lower value means a higher priority.
+    fn set_priority(&self, _irq_num: Self::IRQNumberType, _prio: u8) {}
+
+    /// Route the interrupt to the CPUs of `cpu_mask`.
+    fn set_target(&self, _irq_num: Self::IRQNumberType, _cpu_mask: u8) {}
+
+    /// Register the handler of the interrupt.
+    /// false is returned if the interrupt has a handler already, which is not replaced.
+    fn register_handler(
+        &mut self,
+        irq_num: Self::IRQNumberType,
+        handler: IRQ<Self::IRQNumberType>,
+    ) -> bool;
+
+    /// Remove the handler of the interrupt.
+    fn unregister_handler(&mut self, irq_num: Self::IRQNumberType);
+
+    /// Get the interrupt of the device number `n`, which user processes use.
+    /// Per-CPU interrupts cannot be delivered to user processes.
+    fn device_irq(_n: u32) -> Option<Self::IRQNumberType> {
+        None
+    }
+
+    /// Get the device number of the interrupt.
+    fn device_num(irq_num: Self::IRQNumberType) -> u32;
 }
 
 static IRQ_MANAGER: rwlock::RwLock<GlobalVar<DevIRQManager>> =
@@ -60,6 +178,7 @@ pub fn init() {
 }
 
 pub fn enable_irq_num(irq_num: DevIRQNumber) {
+    let _mask = cpuint::mask();
     let lock = IRQ_MANAGER.read();
     if let GlobalVar::Having(mng) = &*lock {
         mng.enable(irq_num);
@@ -67,15 +186,111 @@ pub fn enable_irq_num(irq_num: DevIRQNumber) {
 }
 
 pub fn disable_irq_num(irq_num: DevIRQNumber) {
+    let _mask = cpuint::mask();
     let lock = IRQ_MANAGER.read();
     if let GlobalVar::Having(mng) = &*lock {
         mng.disable(irq_num);
     }
 }
 
-pub fn register_handler(irq_num: DevIRQNumber, handler: IRQ<DevIRQNumber>) {
+/// Enable a per-CPU interrupt, such as the timer and IPIs, on this CPU.
+/// This must be called by every CPU which receives it.
+pub fn enable_local_irq_num(irq_num: DevIRQNumber) {
+    let _mask = cpuint::mask();
+    let lock = IRQ_MANAGER.read();
+    if let GlobalVar::Having(mng) = &*lock {
+        mng.enable_local(irq_num);
+    }
+}
+
+/// Send the inter-processor interrupt, `IRQ_IPI`, to the CPUs of `cpu_mask`.
+pub fn send_ipi(cpu_mask: u8) {
+    let _mask = cpuint::mask();
+    let lock = IRQ_MANAGER.read();
+    if let GlobalVar::Having(mng) = &*lock {
+        mng.send_ipi(IRQ_IPI, cpu_mask);
+    }
+}
+
+pub fn set_priority(irq_num: DevIRQNumber, prio: u8) {
+    let _mask = cpuint::mask();
+    let lock = IRQ_MANAGER.read();
+    if let GlobalVar::Having(mng) = &*lock {
+        mng.set_priority(irq_num, prio);
+    }
+}
+
+pub fn set_target(irq_num: DevIRQNumber, cpu_mask: u8) {
+    let _mask = cpuint::mask();
+    let lock = IRQ_MANAGER.read();
+    if let GlobalVar::Having(mng) = &*lock {
+        mng.set_target(irq_num, cpu_mask);
+    }
+}
+
+/// Register the handler of the interrupt.
+/// false is returned if the interrupt has a handler already.
+pub fn register_handler(irq_num: DevIRQNumber, handler: IRQ<DevIRQNumber>) -> bool {
+    let _mask = cpuint::mask();
     let mut lock = IRQ_MANAGER.write();
     if let GlobalVar::Having(mng) = &mut *lock {
-        mng.register_handler(irq_num, handler);
+        mng.register_handler(irq_num, handler)
+    } else {
+        false
+    }
+}
+
+pub fn unregister_handler(irq_num: DevIRQNumber) {
+    let _mask = cpuint::mask();
+    let mut lock = IRQ_MANAGER.write();
+    if let GlobalVar::Having(mng) = &mut *lock {
+        mng.unregister_handler(irq_num);
+    }
+}
+
+/// Get the interrupt of the device number `n`.
+/// Interrupts used by the kernel are refused when they are subscribed,
+/// because they have handlers already.
+pub fn device_irq(n: u32) -> Option<DevIRQNumber> {
+    DevIRQManager::device_irq(n)
+}
+
+/// Get the device number of the interrupt, which user processes use.
+pub fn device_num(irq_num: DevIRQNumber) -> u32 {
+    DevIRQManager::device_num(irq_num)
+}
+
+/// Acknowledge a pending interrupt, and copy its handler out.
+fn take_pending() -> Option<(DevIRQNumber, Option<IRQ<DevIRQNumber>>)> {
+    let lock = IRQ_MANAGER.read();
+    if let GlobalVar::Having(mng) = &*lock {
+        let irq_num = mng.pending()?;
+        let handler = mng.handler(irq_num);
+        if let Some(f) = &handler {
+            if f.is_oneshot() {
+                mng.disable(irq_num);
+            }
+        }
+        Some((irq_num, handler))
+    } else {
+        None
+    }
+}
+
+/// Handle a pending interrupt.
+/// This must be called from IRQ and FIQ handlers.
+///
+/// The handler is called without holding the lock of IRQ_MANAGER,
+/// so it can enable, disable or register interrupts.
+pub fn handle_irq() {
+    if let Some((irq_num, handler)) = take_pending() {
+        if let Some(f) = handler {
+            f.handle(irq_num);
+        }
+
+        let lock = IRQ_MANAGER.read();
+        if let GlobalVar::Having(mng) = &*lock {
+            mng.ack(irq_num);
+        }
     }
 }
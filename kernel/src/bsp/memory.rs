@@ -4,6 +4,9 @@ use super::raspi::memory;
 #[cfg(feature = "pine64")]
 use super::allwinner::memory;
 
+#[cfg(feature = "virt")]
+use super::virt::memory;
+
 pub const DEVICE_MEM_START: u64 = memory::DEVICE_MEM_START;
 pub const DEVICE_MEM_END: u64 = memory::DEVICE_MEM_END;
 pub const SRAM_START: u64 = memory::SRAM_START;
@@ -11,3 +14,6 @@ pub const SRAM_END: u64 = memory::SRAM_END;
 pub const ROM_START: u64 = memory::ROM_START;
 pub const ROM_END: u64 = memory::ROM_END;
 pub const DRAM_BASE: u64 = memory::DRAM_BASE;
+
+#[cfg(feature = "virt")]
+pub const UART0_BASE: usize = memory::UART0_BASE;
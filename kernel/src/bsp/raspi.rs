@@ -17,6 +17,12 @@ const UART0_BASE: usize = memory::MMIO_BASE + 0x00201000;
 const UART0_CLOCK: usize = 48000000;
 const UART0_BAUD: usize = 115200;
 
+// GIC-400 of BCM2711
+#[cfg(feature = "raspi4")]
+const GIC_DISTB: usize = 0xff841000;
+#[cfg(feature = "raspi4")]
+const GIC_CPUB: usize = 0xff842000;
+
 pub(super) struct Init {}
 
 impl BSPInit for Init {
@@ -24,7 +30,19 @@ impl BSPInit for Init {
         init_uart0();
     }
 
-    fn init() {}
+    fn init() {
+        #[cfg(feature = "raspi4")]
+        crate::driver::gic::init(GIC_CPUB, GIC_DISTB, crate::driver::gic::GICVer::V2);
+    }
+
+    fn init_secondary() {
+        #[cfg(feature = "raspi4")]
+        crate::driver::gic::init_secondary();
+    }
+
+    fn start_secondaries() {
+        crate::aarch64::cpu::start_non_primary();
+    }
 }
 
 fn init_uart0() {
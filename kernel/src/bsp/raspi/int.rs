@@ -4,10 +4,36 @@ pub(in crate::bsp) type IRQNumber = int_rpi::IRQNumber;
 #[cfg(feature = "raspi4")]
 use crate::driver::gic as int_rpi;
 
+/// EL1 physical timer (non-secure), used for the scheduler tick
+#[cfg(feature = "raspi3")]
+pub(in crate::bsp) const IRQ_TIMER: IRQNumber = int_rpi::IRQ_CNTPNS;
+
+/// EL1 physical timer (non-secure, PPI 14), used for the scheduler tick
+#[cfg(feature = "raspi4")]
+pub(in crate::bsp) const IRQ_TIMER: IRQNumber = 30;
+
+/// Mailbox 0 of the local interrupt controller
+#[cfg(feature = "raspi3")]
+pub(in crate::bsp) const IRQ_IPI: IRQNumber = int_rpi::IRQ_MAILBOX0;
+
+/// SGI 8 (secure)
+#[cfg(feature = "raspi4")]
+pub(in crate::bsp) const IRQ_IPI: IRQNumber = 8;
+
+/// PL011 UART0
+#[cfg(feature = "raspi3")]
+pub(in crate::bsp) const IRQ_UART: IRQNumber = int_rpi::IRQ_UART_INT;
+
+/// PL011 UART0 (VideoCore IRQ 57, SPI 121)
+#[cfg(feature = "raspi4")]
+pub(in crate::bsp) const IRQ_UART: IRQNumber = 153;
+
 #[cfg(feature = "raspi3")]
 pub(in crate::bsp::raspi) mod int_rpi {
     use crate::{
         bsp::{int, raspi::memory},
+        driver::topology::{core_pos, CORE_COUNT},
+        mmio::ReadWrite,
         mmio_r, mmio_rw,
     };
     use arr_macro::arr;
@@ -15,6 +41,59 @@ pub(in crate::bsp::raspi) mod int_rpi {
     const LOCAL_INTERRUPT_CTRL_OFFSET: usize = 0xb200;
     const LOCAL_CTRL: usize = memory::MMIO_BASE + LOCAL_INTERRUPT_CTRL_OFFSET;
 
+    // ARM local peripherals
+    // See QA7_rev3.4.pdf
+    const CORE_TIMER_IRQCNTL: usize = 0x40000040; // + 4 * core
+
+    // Core timers interrupt control
+    // bit 0: CNTPSIRQ
+    // bit 1: CNTPNSIRQ
+    // bit 2: CNTHPIRQ
+    // bit 3: CNTVIRQ
+    const NUM_CORE_TIMER_IRQ: u8 = 4;
+
+    // Core mailboxes
+    const CORE_MAILBOX_INT_CTRL: usize = 0x40000050; // + 4 * core
+    const CORE_MAILBOX_SET: usize = 0x40000080; // + 0x10 * core + 4 * mailbox, write to set
+    const CORE_MAILBOX_CLR: usize = 0x400000c0; // + 0x10 * core + 4 * mailbox, write to clear
+    const NUM_CORE_MAILBOX: u8 = 4;
+
+    // Core interrupt sources
+    // bit 0..3: core timers
+    // bit 4..7: mailboxes
+    // bit 8: GPU
+    const CORE_IRQ_SOURCE: usize = 0x40000060; // + 4 * core
+    const CORE_IRQ_SOURCE_GPU: u32 = 1 << 8;
+
+    fn core_timer_irqcntl() -> ReadWrite<u32> {
+        ReadWrite::new(CORE_TIMER_IRQCNTL + core_pos() * 4)
+    }
+
+    fn core_irq_source() -> ReadWrite<u32> {
+        ReadWrite::new(CORE_IRQ_SOURCE + core_pos() * 4)
+    }
+
+    fn core_mailbox_int_ctrl() -> ReadWrite<u32> {
+        ReadWrite::new(CORE_MAILBOX_INT_CTRL + core_pos() * 4)
+    }
+
+    fn core_mailbox_set(core: usize, mbox: u8) -> ReadWrite<u32> {
+        ReadWrite::new(CORE_MAILBOX_SET + core * 0x10 + mbox as usize * 4)
+    }
+
+    fn core_mailbox_clr(mbox: u8) -> ReadWrite<u32> {
+        ReadWrite::new(CORE_MAILBOX_CLR + core_pos() * 0x10 + mbox as usize * 4)
+    }
+
+    /// Is it a mailbox interrupt? Returns the mailbox number.
+    fn mailbox(n: u8) -> Option<u8> {
+        if (NUM_CORE_TIMER_IRQ..NUM_CORE_TIMER_IRQ + NUM_CORE_MAILBOX).contains(&n) {
+            Some(n - NUM_CORE_TIMER_IRQ)
+        } else {
+            None
+        }
+    }
+
     // ARM interrupt registers
     // See page 112 of https://www.raspberrypi.org/app/uploads/2012/02/BCM2835-ARM-Peripherals.pdf
     //
@@ -31,14 +110,17 @@ pub(in crate::bsp::raspi) mod int_rpi {
     mmio_rw!(LOCAL_CTRL + 0x024 => disable_basic_irqs<u32>);
 
     const MAX_LOCAL_IRQ_NUMBER: usize = 11;
-    const MAX_PERIPHERAL_IRQ_NUMBER: usize = 63;
+    const MAX_PERIPHERAL_IRQ_NUMBER: usize = 64;
 
-    #[derive(Debug, PartialEq, Eq)]
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
     pub enum IRQNumber {
         Private(u8),
         Peripheral(u8),
     }
 
+    pub(in crate::bsp::raspi) const IRQ_CNTPNS: IRQNumber = IRQNumber::Private(1);
+    pub(in crate::bsp::raspi) const IRQ_MAILBOX0: IRQNumber = IRQNumber::Private(4);
+
     pub(in crate::bsp::raspi) const IRQ_SYSTEM_TIMER_MATCH1: IRQNumber = IRQNumber::Peripheral(1);
     pub(in crate::bsp::raspi) const IRQ_SYSTEM_TIMER_MATCH3: IRQNumber = IRQNumber::Peripheral(3);
     pub(in crate::bsp::raspi) const IRQ_USB_CONTROLLER: IRQNumber = IRQNumber::Peripheral(9);
@@ -65,8 +147,16 @@ pub(in crate::bsp::raspi) mod int_rpi {
 
         fn enable(&self, irq_num: Self::IRQNumberType) {
             match irq_num {
-                IRQNumber::Private(_) => {
-                    unimplemented!();
+                IRQNumber::Private(n) => {
+                    if n < NUM_CORE_TIMER_IRQ {
+                        // enabled only on this CPU
+                        core_timer_irqcntl().setbits(1 << n);
+                    } else if let Some(mbox) = mailbox(n) {
+                        // enabled only on this CPU
+                        core_mailbox_int_ctrl().setbits(1 << mbox);
+                    } else {
+                        unimplemented!();
+                    }
                 }
                 IRQNumber::Peripheral(n) => {
                     if n < 32 {
@@ -80,8 +170,14 @@ pub(in crate::bsp::raspi) mod int_rpi {
 
         fn disable(&self, irq_num: Self::IRQNumberType) {
             match irq_num {
-                IRQNumber::Private(_) => {
-                    unimplemented!();
+                IRQNumber::Private(n) => {
+                    if n < NUM_CORE_TIMER_IRQ {
+                        core_timer_irqcntl().clrbits(1 << n);
+                    } else if let Some(mbox) = mailbox(n) {
+                        core_mailbox_int_ctrl().clrbits(1 << mbox);
+                    } else {
+                        unimplemented!();
+                    }
                 }
                 IRQNumber::Peripheral(n) => {
                     if n < 32 {
@@ -93,26 +189,56 @@ pub(in crate::bsp::raspi) mod int_rpi {
             }
         }
 
-        fn ack(&self, _irq_num: Self::IRQNumberType) {}
+        fn pending(&self) -> Option<Self::IRQNumberType> {
+            let src = core_irq_source().read();
+            if src & CORE_IRQ_SOURCE_GPU != 0 {
+                let p1 = irq_pending1().read();
+                if p1 != 0 {
+                    return Some(IRQNumber::Peripheral(p1.trailing_zeros() as u8));
+                }
 
-        fn handle(&self, irq_num: Self::IRQNumberType) {
-            match irq_num {
-                IRQNumber::Private(n) => {
-                    let n = n as usize;
-                    if let Some(f) = &self.hdls_private[n] {
-                        f.handle(irq_num);
-                    }
+                let p2 = irq_pending2().read();
+                if p2 != 0 {
+                    return Some(IRQNumber::Peripheral(32 + p2.trailing_zeros() as u8));
                 }
-                IRQNumber::Peripheral(n) => {
-                    let n = n as usize;
-                    assert!(n < MAX_PERIPHERAL_IRQ_NUMBER);
-                    if let Some(f) = &self.hdls_periheral[n] {
-                        f.handle(irq_num);
+
+                None
+            } else if src & ((1u32 << MAX_LOCAL_IRQ_NUMBER) - 1) != 0 {
+                Some(IRQNumber::Private(src.trailing_zeros() as u8))
+            } else {
+                None
+            }
+        }
+
+        // the source of the interrupt must be cleared by its handler,
+        // except for mailboxes
+        fn ack(&self, irq_num: Self::IRQNumberType) {
+            if let IRQNumber::Private(n) = irq_num {
+                if let Some(mbox) = mailbox(n) {
+                    core_mailbox_clr(mbox).write(!0);
+                }
+            }
+        }
+
+        fn send_ipi(&self, irq_num: Self::IRQNumberType, cpu_mask: u8) {
+            if let IRQNumber::Private(n) = irq_num {
+                if let Some(mbox) = mailbox(n) {
+                    for core in 0..CORE_COUNT {
+                        if cpu_mask & (1 << core) != 0 {
+                            core_mailbox_set(core, mbox).write(1);
+                        }
                     }
                 }
             }
         }
 
+        fn handler(&self, irq_num: Self::IRQNumberType) -> Option<int::IRQ<Self::IRQNumberType>> {
+            match irq_num {
+                IRQNumber::Private(n) => self.hdls_private[n as usize],
+                IRQNumber::Peripheral(n) => self.hdls_periheral[n as usize],
+            }
+        }
+
         fn new() -> Self {
             disable_basic_irqs().write(0);
             disable_irqs1().write(0);
@@ -120,7 +246,7 @@ pub(in crate::bsp::raspi) mod int_rpi {
 
             IRQManager {
                 hdls_private: arr![None; 11],
-                hdls_periheral: arr![None; 63],
+                hdls_periheral: arr![None; 64],
             }
         }
 
@@ -128,14 +254,38 @@ pub(in crate::bsp::raspi) mod int_rpi {
             &mut self,
             irq_num: Self::IRQNumberType,
             handler: int::IRQ<Self::IRQNumberType>,
-        ) {
+        ) -> bool {
+            let hdl = match irq_num {
+                IRQNumber::Private(n) => &mut self.hdls_private[n as usize],
+                IRQNumber::Peripheral(n) => &mut self.hdls_periheral[n as usize],
+            };
+
+            if hdl.is_some() {
+                return false;
+            }
+            *hdl = Some(handler);
+            true
+        }
+
+        fn unregister_handler(&mut self, irq_num: Self::IRQNumberType) {
             match irq_num {
-                IRQNumber::Private(n) => {
-                    self.hdls_private[n as usize] = Some(handler);
-                }
-                IRQNumber::Peripheral(n) => {
-                    self.hdls_periheral[n as usize] = Some(handler);
-                }
+                IRQNumber::Private(n) => self.hdls_private[n as usize] = None,
+                IRQNumber::Peripheral(n) => self.hdls_periheral[n as usize] = None,
+            }
+        }
+
+        /// Only peripheral interrupts are delivered to user processes.
+        fn device_irq(n: u32) -> Option<Self::IRQNumberType> {
+            if n < MAX_PERIPHERAL_IRQ_NUMBER as u32 {
+                Some(IRQNumber::Peripheral(n as u8))
+            } else {
+                None
+            }
+        }
+
+        fn device_num(irq_num: Self::IRQNumberType) -> u32 {
+            match irq_num {
+                IRQNumber::Private(n) | IRQNumber::Peripheral(n) => n as u32,
             }
         }
     }
@@ -6,7 +6,7 @@ use crate::{mmio_rw, mmio_w};
 mod raspi {
     pub(super) const MMIO_BASE: usize = 0x3F000000;
     pub(super) const DEVICE_MEM_START: u64 = 0x3C000000;
-    pub(super) const DEVICE_MEM_END: u64 = 0x40000000;
+    pub(super) const DEVICE_MEM_END: u64 = 0x40010000; // including the ARM local peripherals
 }
 //-----------------------------------------------------------------------------
 
@@ -8,7 +8,7 @@ const UART_BAUD: usize = 115200;
 #[cfg(feature = "pine64")]
 pub type DevUART = crate::driver::uart::sunxi_uart::SunxiUART;
 
-#[cfg(any(feature = "raspi3", feature = "raspi4"))]
+#[cfg(any(feature = "raspi3", feature = "raspi4", feature = "virt"))]
 pub type DevUART = crate::driver::uart::pl011::PL011;
 
 impl DevUART where DevUART: UART {}
@@ -131,24 +131,30 @@ pub fn decimal(mut h: u64) {
     }
 }
 
-pub fn read<F>(buf: &mut [u8], echo: F) -> usize
-where
-    F: Fn(&DevUART, u8),
-{
-    let mut n = 0;
-
+/// send a character to serial console as it is
+pub fn send(c: u8) {
     let _mask = cpuint::mask();
     let mut node = MCSNode::new();
     let lock = UART0.lock(&mut node);
     if let GlobalVar::Having(uart0) = &*lock {
-        loop {
-            let c = uart0.recv() as u8;
-            buf[n] = c;
-            n += 1;
+        uart0.send(c as u32);
+    }
+}
 
-            echo(uart0, c);
+/// Read received characters without blocking.
+/// This returns the number of the read characters.
+pub fn try_read(buf: &mut [u8]) -> usize {
+    let mut n = 0;
 
-            if n >= buf.len() {
+    let _mask = cpuint::mask();
+    let mut node = MCSNode::new();
+    let lock = UART0.lock(&mut node);
+    if let GlobalVar::Having(uart0) = &*lock {
+        while n < buf.len() {
+            if let Some(c) = uart0.try_recv() {
+                buf[n] = c as u8;
+                n += 1;
+            } else {
                 break;
             }
         }
@@ -0,0 +1,71 @@
+pub(super) mod memory;
+
+use super::BSPInit;
+use crate::{
+    aarch64::{
+        cpu,
+        psci::{self, Conduit},
+    },
+    driver::{
+        gic::{self, GICVer},
+        topology::CORE_COUNT,
+        uart::{pl011::PL011, UART},
+    },
+    out,
+};
+use memory::*;
+
+// QEMU's default frequency of the generic timer
+pub const SYSCNT_FRQ: u32 = 62500000;
+
+const UART0_CLOCK: usize = 24000000;
+const UART0_BAUD: usize = 115200;
+
+extern "C" {
+    fn _start();
+    static __boot_el: u32;
+}
+
+pub(super) struct Init {}
+
+impl BSPInit for Init {
+    fn early_init() {
+        init_uart0();
+    }
+
+    fn init() {
+        // QEMU reports the CPU interface of GICv3 only if gic-version=3
+        if (cpu::id_aa64pfr0_el1::get() >> 24) & 0xf != 0 {
+            gic::init(GICR_BASE, GICD_BASE, GICVer::V3);
+        } else {
+            gic::init(GICC_BASE, GICD_BASE, GICVer::V2);
+        }
+    }
+
+    fn init_secondary() {
+        gic::init_secondary();
+    }
+
+    fn start_secondaries() {
+        // QEMU handles PSCI by SMC if EL2 is available, otherwise by HVC
+        let el = unsafe { core::ptr::read_volatile(&__boot_el) };
+
+        for core in 1..CORE_COUNT {
+            let conduit = if el == 2 { Conduit::Smc } else { Conduit::Hvc };
+            let ret = psci::cpu_on(conduit, core as u64, _start as usize as u64, 0);
+            if ret != 0 {
+                let msg = format!("failed to power on CPU #{} ({})", core, ret);
+                out::msg("PSCI", &msg);
+            }
+        }
+    }
+}
+
+fn init_uart0() {
+    let uart0 = PL011::new(UART0_BASE);
+    uart0.off();
+    uart0.init(UART0_CLOCK, UART0_BAUD);
+    uart0.on();
+
+    super::uart::init(uart0);
+}
@@ -0,0 +1,14 @@
+// QEMU virt machine
+// See hw/arm/virt.c of QEMU
+pub(in crate::bsp) const DEVICE_MEM_START: u64 = 0x08000000;
+pub(in crate::bsp) const DEVICE_MEM_END: u64 = 0x0a010000;
+pub(in crate::bsp) const ROM_START: u64 = 0x00000000; // flash
+pub(in crate::bsp) const ROM_END: u64 = 0x08000000;
+pub(in crate::bsp) const SRAM_START: u64 = 0;
+pub(in crate::bsp) const SRAM_END: u64 = 0;
+pub(in crate::bsp) const DRAM_BASE: u64 = 0x40000000;
+
+pub(in crate::bsp) const GICD_BASE: usize = 0x08000000;
+pub(in crate::bsp) const GICC_BASE: usize = 0x08010000;
+pub(in crate::bsp) const GICR_BASE: usize = 0x080a0000;
+pub(in crate::bsp) const UART0_BASE: usize = 0x09000000;
@@ -47,6 +47,15 @@ impl UART for A64UART {
         c as u32
     }
 
+    fn try_recv(&self) -> Option<u32> {
+        if unsafe { read_volatile(UART0_LSR) } & 1 == 0 {
+            None
+        } else {
+            let c = unsafe { read_volatile(UART0_RBR) };
+            Some(c as u32)
+        }
+    }
+
     fn enable_recv_interrupt(&self) {}
     fn disable_recv_interrupt(&self) {}
     fn new(_base: usize) -> Self {
@@ -7,3 +7,7 @@ pub(crate) mod raspi;
 // Pine64, Allwineer sunxi
 #[cfg(feature = "pine64")]
 pub(crate) mod allwinner;
+
+// QEMU virt machine
+#[cfg(feature = "virt")]
+pub(crate) mod virt;
@@ -96,6 +96,14 @@ impl UART for RaspiUART {
         uart0_dr().read()
     }
 
+    fn try_recv(&self) -> Option<u32> {
+        if uart0_fr().read() & 0x10 != 0 {
+            None
+        } else {
+            Some(uart0_dr().read())
+        }
+    }
+
     fn enable_recv_interrupt(&self) {
         //uart0_imsc().setbits(IMSC_RXIM);
     }
@@ -0,0 +1,2 @@
+pub(in crate::driver) mod topology;
+pub(in crate::driver) mod uart;
@@ -0,0 +1,3 @@
+pub(in crate::driver) const MAX_CPUS_PER_CLUSTER: usize = 4;
+pub(in crate::driver) const CLUSTER_COUNT: usize = 1;
+pub(in crate::driver) const CORE_COUNT: usize = 4;
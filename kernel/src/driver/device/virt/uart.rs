@@ -0,0 +1,46 @@
+use crate::{
+    bsp::memory::UART0_BASE,
+    driver::uart::{pl011::PL011, UART},
+};
+
+pub(in crate::driver) struct VirtUART {}
+
+fn uart0() -> PL011 {
+    PL011::new(UART0_BASE)
+}
+
+impl UART for VirtUART {
+    fn new(_base: usize) -> Self {
+        Self {}
+    }
+
+    fn init(&self, uart_clock: usize, baudrate: usize) {
+        let uart0 = uart0();
+        uart0.off();
+        uart0.init(uart_clock, baudrate);
+        uart0.on();
+    }
+
+    fn send(&self, c: u32) {
+        uart0().send(c);
+    }
+
+    fn recv(&self) -> u32 {
+        uart0().recv()
+    }
+
+    fn try_recv(&self) -> Option<u32> {
+        uart0().try_recv()
+    }
+
+    fn enable_recv_interrupt(&self) {
+        uart0().enable_recv_interrupt();
+    }
+
+    fn disable_recv_interrupt(&self) {
+        uart0().disable_recv_interrupt();
+    }
+
+    fn on(&self) {}
+    fn off(&self) {}
+}
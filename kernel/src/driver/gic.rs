@@ -1,11 +1,14 @@
 use crate::{
+    aarch64::cpu,
     bsp::int::{self, IRQ},
+    cpuint,
+    driver::topology::core_pos,
     global::GlobalVar,
     mmio::ReadWrite,
     out,
 };
 use arr_macro::arr;
-use core::default::Default;
+use core::{arch::asm, default::Default};
 use synctools::mcs::{MCSLock, MCSNode};
 
 const GIC_MAX_INTS: usize = 1020;
@@ -16,6 +19,17 @@ const NUM_SGI: usize = 16;
 const ITARGETSR_FIELD_BITS: usize = 8;
 const ITARGETSR_FIELD_MASK: usize = 0xff;
 
+const GICC_IAR_IT_ID_MASK: u32 = 0x3ff;
+const GICC_IAR_CPU_ID_MASK: u32 = 0x7 << 10;
+const GIC_SPURIOUS_ID: u32 = 1023;
+
+// interrupts are signaled only if its priority is higher than GICC_PMR
+const GICC_PMR_DEFAULT: u32 = 0x80;
+const DEFAULT_PRIORITY: u8 = 0x40;
+
+const GICD_SGIR_TARGET_LIST_SHIFT: u32 = 16;
+const GICD_SGIR_INTID_MASK: u32 = 0xf;
+
 const GICC_CTLR_ENABLEGRP0: u32 = 1 << 0;
 const GICC_CTLR_ENABLEGRP1: u32 = 1 << 1;
 const GICD_CTLR_ENABLEGRP1S: u32 = 1 << 2;
@@ -23,6 +37,19 @@ const GICC_CTLR_FIQEN: u32 = 1 << 3;
 
 const GICD_CTLR_ENABLEGRP0: u32 = 1 << 0;
 const GICD_CTLR_ENABLEGRP1: u32 = 1 << 1;
+const GICD_CTLR_ARE: u32 = 1 << 4;
+const GICD_CTLR_RWP: u32 = 1 << 31;
+
+// redistributors of GICv3, each has RD_base and SGI_base frames of 64KiB
+const GICR_STRIDE: usize = 0x20000;
+const GICR_SGI_OFFSET: usize = 0x10000;
+const GICR_TYPER_LAST: u64 = 1 << 4;
+const GICR_WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+const GICR_WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+
+const ICC_SRE_SRE: u64 = 1;
+const ICC_SGIR_AFF1_SHIFT: u64 = 16;
+const ICC_SGIR_INTID_SHIFT: u64 = 24;
 
 static GIC_GLOBAL: MCSLock<GlobalVar<GIC>> = MCSLock::new(GlobalVar::UnInit);
 
@@ -30,6 +57,7 @@ static GIC_GLOBAL: MCSLock<GlobalVar<GIC>> = MCSLock::new(GlobalVar::UnInit);
 pub struct GIC {
     gicc_base: usize,
     gicd_base: usize,
+    gicr_base: usize,
     ver: GICVer,
     max_it: usize,
 }
@@ -46,17 +74,36 @@ impl Default for GICVer {
     }
 }
 
-pub fn init(gicc_base: usize, gicd_base: usize, ver: GICVer) {
+/// Initialize the distributor and the CPU interface of this CPU.
+/// `cpu_base` is the base address of the CPU interface for GICv2,
+/// or that of the redistributors for GICv3,
+/// whose CPU interface is accessed by system registers.
+pub fn init(cpu_base: usize, gicd_base: usize, ver: GICVer) {
     let mut node = MCSNode::new();
     let mut lock = GIC_GLOBAL.lock(&mut node);
 
+    let (gicc_base, gicr_base) = match ver {
+        GICVer::V2 => (cpu_base, 0),
+        GICVer::V3 => (0, cpu_base),
+    };
+
     let mut g = GIC {
         gicc_base,
         gicd_base,
+        gicr_base,
         ver,
         max_it: 0,
     };
 
+    if ver == GICVer::V3 {
+        // affinity routing must be enabled while the distributor is disabled
+        g.gicd_ctlr().write(0);
+        g.wait_rwp();
+        g.gicd_ctlr().write(GICD_CTLR_ARE);
+        g.wait_rwp();
+        g.wake_redistributor();
+    }
+
     // calculate the maximum number of interrupt
     g.probe_max_it();
 
@@ -83,15 +130,15 @@ pub fn init(gicc_base: usize, gicd_base: usize, ver: GICVer) {
         }
     }
 
-    g.gicc_pmr().write(0x80);
-
     // Enable GIC
-    let gicc_ctlr = g.gicc_ctlr();
-    let gicd_ctlr = g.gicd_ctlr();
-    gicc_ctlr.write(GICC_CTLR_FIQEN | GICC_CTLR_ENABLEGRP0 | GICC_CTLR_ENABLEGRP1);
-    gicd_ctlr.setbits(GICD_CTLR_ENABLEGRP0 | GICD_CTLR_ENABLEGRP1);
+    g.enable_cpu_interface();
+    g.gicd_ctlr()
+        .setbits(GICD_CTLR_ENABLEGRP0 | GICD_CTLR_ENABLEGRP1);
 
-    out::msg("GICv2", "Initialized");
+    match ver {
+        GICVer::V2 => out::msg("GICv2", "Initialized"),
+        GICVer::V3 => out::msg("GICv3", "Initialized"),
+    }
 
     if let GlobalVar::UnInit = *lock {
         *lock = GlobalVar::Having(g)
@@ -100,6 +147,38 @@ pub fn init(gicc_base: usize, gicd_base: usize, ver: GICVer) {
     }
 }
 
+/// Initialize the banked registers of a secondary CPU.
+/// The distributor must have been initialized by `init` on the primary CPU.
+pub fn init_secondary() {
+    with_gic(|g| {
+        if g.ver == GICVer::V3 {
+            g.wake_redistributor();
+        }
+
+        // per-CPU interrupts are banked, see `init`
+        g.gicd_icenabler(0).write(0xffffffff);
+        g.gicd_icpendr(0).write(0xffffffff);
+        g.gicd_igroupr(0).write(0xffff00ff);
+
+        g.enable_cpu_interface();
+    });
+}
+
+/// Call `f` with the global GIC, masking interrupts of this CPU.
+fn with_gic<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce(&GIC) -> R,
+{
+    let _mask = cpuint::mask();
+    let mut node = MCSNode::new();
+    let lock = GIC_GLOBAL.lock(&mut node);
+    if let GlobalVar::Having(g) = &*lock {
+        Some(f(g))
+    } else {
+        None
+    }
+}
+
 pub fn take() -> Option<GIC> {
     let mut node = MCSNode::new();
     let mut lock = GIC_GLOBAL.lock(&mut node);
@@ -120,6 +199,7 @@ impl Drop for GIC {
         *lock = GlobalVar::Having(GIC {
             gicc_base: self.gicc_base,
             gicd_base: self.gicd_base,
+            gicr_base: self.gicr_base,
             ver: self.ver,
             max_it: self.max_it,
         });
@@ -135,40 +215,133 @@ impl GIC {
         ReadWrite::new(self.gicc_base + 0x04)
     }
 
+    fn gicc_iar(&self) -> ReadWrite<u32> {
+        ReadWrite::new(self.gicc_base + 0x0c)
+    }
+
+    fn gicc_eoir(&self) -> ReadWrite<u32> {
+        ReadWrite::new(self.gicc_base + 0x10)
+    }
+
     fn gicd_ctlr(&self) -> ReadWrite<u32> {
         ReadWrite::new(self.gicd_base)
     }
 
+    /// Base address of the registers of the interrupts of the `n`-th register.
+    /// With GICv3, SGIs and PPIs are configured by the SGI_base frame of
+    /// the redistributor of this CPU, whose offsets are same as the distributor.
+    fn banked_base(&self, n: usize) -> usize {
+        if self.ver == GICVer::V3 && n == 0 {
+            self.gicr_rd_base() + GICR_SGI_OFFSET
+        } else {
+            self.gicd_base
+        }
+    }
+
     fn gicd_isenabler(&self, n: usize) -> ReadWrite<u32> {
-        ReadWrite::new(self.gicd_base + 0x100 + n * 4)
+        ReadWrite::new(self.banked_base(n) + 0x100 + n * 4)
     }
 
     fn gicd_icenabler(&self, n: usize) -> ReadWrite<u32> {
-        ReadWrite::new(self.gicd_base + 0x180 + n * 4)
+        ReadWrite::new(self.banked_base(n) + 0x180 + n * 4)
     }
 
     fn gicd_ispendr(&self, n: usize) -> ReadWrite<u32> {
-        ReadWrite::new(self.gicd_base + 0x200 + n * 4)
+        ReadWrite::new(self.banked_base(n) + 0x200 + n * 4)
     }
 
     fn gicd_icpendr(&self, n: usize) -> ReadWrite<u32> {
-        ReadWrite::new(self.gicd_base + 0x280 + n * 4)
+        ReadWrite::new(self.banked_base(n) + 0x280 + n * 4)
     }
 
     fn gicd_igroupr(&self, n: usize) -> ReadWrite<u32> {
-        ReadWrite::new(self.gicd_base + 0x80 + n * 4)
+        ReadWrite::new(self.banked_base(n) + 0x80 + n * 4)
+    }
+
+    fn gicd_ipriorityr(&self, it: usize) -> ReadWrite<u8> {
+        ReadWrite::new(self.banked_base(it / NUM_INTS_PER_REG) + 0x400 + it)
     }
 
     fn gicd_itargetsr(&self, n: usize) -> ReadWrite<u32> {
         ReadWrite::new(self.gicd_base + 0x800 + n * 4)
     }
 
+    fn gicd_sgir(&self) -> ReadWrite<u32> {
+        ReadWrite::new(self.gicd_base + 0xf00)
+    }
+
+    fn gicd_irouter(&self, it: usize) -> ReadWrite<u64> {
+        ReadWrite::new(self.gicd_base + 0x6000 + it * 8)
+    }
+
+    fn gicr_typer(&self, rd_base: usize) -> ReadWrite<u64> {
+        ReadWrite::new(rd_base + 0x08)
+    }
+
+    fn gicr_waker(&self, rd_base: usize) -> ReadWrite<u32> {
+        ReadWrite::new(rd_base + 0x14)
+    }
+
+    /// Find the redistributor of this CPU by its affinity.
+    fn gicr_rd_base(&self) -> usize {
+        let mpidr = cpu::mpidr_el1::get();
+        let aff = (mpidr & 0xffffff) | ((mpidr >> 8) & 0xff000000);
+
+        let mut rd_base = self.gicr_base;
+        loop {
+            let typer = self.gicr_typer(rd_base).read();
+            if typer >> 32 == aff {
+                return rd_base;
+            }
+
+            if typer & GICR_TYPER_LAST != 0 {
+                panic!("no redistributor of GICv3");
+            }
+            rd_base += GICR_STRIDE;
+        }
+    }
+
+    fn wake_redistributor(&self) {
+        let waker = self.gicr_waker(self.gicr_rd_base());
+        waker.clrbits(GICR_WAKER_PROCESSOR_SLEEP);
+        while waker.read() & GICR_WAKER_CHILDREN_ASLEEP != 0 {}
+    }
+
+    fn wait_rwp(&self) {
+        while self.gicd_ctlr().read() & GICD_CTLR_RWP != 0 {}
+    }
+
+    fn enable_cpu_interface(&self) {
+        match self.ver {
+            GICVer::V2 => {
+                self.gicc_pmr().write(GICC_PMR_DEFAULT);
+                self.gicc_ctlr()
+                    .write(GICC_CTLR_FIQEN | GICC_CTLR_ENABLEGRP0 | GICC_CTLR_ENABLEGRP1);
+            }
+            GICVer::V3 => {
+                cpu::icc_sre_el1::set(cpu::icc_sre_el1::get() | ICC_SRE_SRE);
+                cpu::isb();
+
+                // group 0 interrupts are signaled as FIQ
+                cpu::icc_pmr_el1::set(GICC_PMR_DEFAULT as u64);
+                cpu::icc_igrpen0_el1::set(1);
+                cpu::icc_igrpen1_el1::set(1);
+            }
+        }
+    }
+
     fn probe_max_it(&mut self) {
         let max_regs = ((GIC_MAX_INTS + NUM_INTS_PER_REG - 1) >> 5) - 1;
-        let gicc_ctlr = self.gicc_ctlr();
 
-        let old_ctlr = gicc_ctlr.read();
-        gicc_ctlr.write(0);
+        // the CPU interface of GICv3 is not enabled yet
+        let gicc_ctlr = self.gicc_ctlr();
+        let old_ctlr = if self.ver == GICVer::V2 {
+            let old = gicc_ctlr.read();
+            gicc_ctlr.write(0);
+            old
+        } else {
+            0
+        };
 
         'out: for i in (0..=max_regs).rev() {
             let set = self.gicd_isenabler(i);
@@ -186,7 +359,9 @@ impl GIC {
             }
         }
 
-        gicc_ctlr.write(old_ctlr);
+        if self.ver == GICVer::V2 {
+            gicc_ctlr.write(old_ctlr);
+        }
     }
 
     fn it_add(&self, it: usize) {
@@ -203,6 +378,20 @@ impl GIC {
     }
 
     fn it_set_cpu_mask(&self, it: usize, cpu_mask: u8) {
+        // SGIs and PPIs are banked per CPU, and their targets are read-only
+        if it < 32 || it > self.max_it || cpu_mask == 0 {
+            return;
+        }
+
+        if self.ver == GICVer::V3 {
+            // affinity routing delivers an SPI to the one CPU
+            let mpidr = cpu::mpidr_el1::get();
+            let aff0 = cpu_mask.trailing_zeros() as u64;
+            let aff = (mpidr & 0xff_00ff_ff00) | aff0;
+            self.gicd_irouter(it).write(aff);
+            return;
+        }
+
         let itargetsr = self.gicd_itargetsr(it >> 2);
 
         let mut target = itargetsr.read();
@@ -212,6 +401,67 @@ impl GIC {
         itargetsr.write(target);
     }
 
+    fn it_set_prio(&self, it: usize, prio: u8) {
+        if it > self.max_it {
+            return;
+        }
+
+        self.gicd_ipriorityr(it).write(prio);
+    }
+
+    fn it_disable(&self, it: usize) {
+        if it > self.max_it {
+            return;
+        }
+
+        let idx = it >> 5;
+        let mask = 1 << (it & (NUM_INTS_PER_REG - 1)) as u32;
+        self.gicd_icenabler(idx).write(mask);
+    }
+
+    /// Acknowledge the highest priority pending interrupt.
+    /// The returned value must be passed to `end_it`.
+    fn read_iar(&self) -> Option<u32> {
+        let iar = match self.ver {
+            GICVer::V2 => self.gicc_iar().read(),
+            GICVer::V3 => read_icc_iar0(),
+        };
+        let iar = iar & (GICC_IAR_CPU_ID_MASK | GICC_IAR_IT_ID_MASK);
+        if iar & GICC_IAR_IT_ID_MASK >= GIC_SPURIOUS_ID {
+            None
+        } else {
+            Some(iar)
+        }
+    }
+
+    fn end_it(&self, iar: u32) {
+        match self.ver {
+            GICVer::V2 => self.gicc_eoir().write(iar),
+            GICVer::V3 => write_icc_eoir0(iar),
+        }
+    }
+
+    /// Send the SGI `it` to the CPUs of `cpu_mask`.
+    fn raise_sgi(&self, it: usize, cpu_mask: u8) {
+        if it >= NUM_SGI {
+            return;
+        }
+
+        if self.ver == GICVer::V3 {
+            // CPUs of this cluster, whose Aff0 are the core positions
+            let aff1 = cpu::get_affinity_lv1();
+            let sgir = (aff1 << ICC_SGIR_AFF1_SHIFT)
+                | ((it as u64) << ICC_SGIR_INTID_SHIFT)
+                | cpu_mask as u64;
+            write_icc_sgi0r(sgir);
+            return;
+        }
+
+        let sgir =
+            ((cpu_mask as u32) << GICD_SGIR_TARGET_LIST_SHIFT) | (it as u32 & GICD_SGIR_INTID_MASK);
+        self.gicd_sgir().write(sgir);
+    }
+
     fn it_enable(&self, it: usize) -> bool {
         if it > self.max_it {
             return false;
@@ -236,8 +486,32 @@ impl GIC {
     }
 }
 
+// ICC_IAR0_EL1 is read-only, ICC_EOIR0_EL1 and ICC_SGI0R_EL1 are write-only,
+// so they cannot be defined by `sysreg!`
+fn read_icc_iar0() -> u32 {
+    let v: u64;
+    unsafe { asm!("mrs {}, icc_iar0_el1", lateout(reg) v) };
+    v as u32
+}
+
+fn write_icc_eoir0(v: u32) {
+    unsafe { asm!("msr icc_eoir0_el1, {}", in(reg) v as u64) };
+}
+
+fn write_icc_sgi0r(v: u64) {
+    unsafe { asm!("msr icc_sgi0r_el1, {}", in(reg) v) };
+    cpu::isb();
+}
+
+/// Interrupt ID of the GIC.
+/// An IRQ number returned by `pending` is the value of GICC_IAR,
+/// so it contains the CPU ID of the requester in bits 12..10 for SGIs.
 pub type IRQNumber = u16;
 
+fn it_id(irq_num: IRQNumber) -> usize {
+    (irq_num as u32 & GICC_IAR_IT_ID_MASK) as usize
+}
+
 pub struct IRQManager {
     handlers: [Option<IRQ<IRQNumber>>; GIC_MAX_INTS],
 }
@@ -245,10 +519,58 @@ pub struct IRQManager {
 impl int::IRQManager for IRQManager {
     type IRQNumberType = IRQNumber;
 
-    fn enable(&self, _irq_num: Self::IRQNumberType) {}
-    fn disable(&self, _irq_num: Self::IRQNumberType) {}
-    fn ack(&self, _irq_num: Self::IRQNumberType) {}
-    fn handle(&self, _irq_num: Self::IRQNumberType) {}
+    fn enable(&self, irq_num: Self::IRQNumberType) {
+        with_gic(|g| g.it_enable(it_id(irq_num)));
+    }
+
+    /// SGIs and PPIs are banked per CPU,
+    /// so they are configured on each CPU before being enabled.
+    fn enable_local(&self, irq_num: Self::IRQNumberType) {
+        let it = it_id(irq_num);
+        with_gic(|g| {
+            if it < 32 {
+                g.it_add(it);
+                g.it_set_prio(it, DEFAULT_PRIORITY);
+            }
+            g.it_enable(it)
+        });
+    }
+
+    fn send_ipi(&self, irq_num: Self::IRQNumberType, cpu_mask: u8) {
+        with_gic(|g| g.raise_sgi(it_id(irq_num), cpu_mask));
+    }
+
+    fn disable(&self, irq_num: Self::IRQNumberType) {
+        with_gic(|g| g.it_disable(it_id(irq_num)));
+    }
+
+    fn pending(&self) -> Option<Self::IRQNumberType> {
+        with_gic(|g| g.read_iar())
+            .flatten()
+            .map(|iar| iar as IRQNumber)
+    }
+
+    fn ack(&self, irq_num: Self::IRQNumberType) {
+        with_gic(|g| g.end_it(irq_num as u32));
+    }
+
+    fn handler(&self, irq_num: Self::IRQNumberType) -> Option<IRQ<Self::IRQNumberType>> {
+        if let Some(Some(f)) = self.handlers.get(it_id(irq_num)) {
+            Some(*f)
+        } else {
+            out::msg("GIC", "unexpected interrupt");
+            self.disable(irq_num);
+            None
+        }
+    }
+
+    fn set_priority(&self, irq_num: Self::IRQNumberType, prio: u8) {
+        with_gic(|g| g.it_set_prio(it_id(irq_num), prio));
+    }
+
+    fn set_target(&self, irq_num: Self::IRQNumberType, cpu_mask: u8) {
+        with_gic(|g| g.it_set_cpu_mask(it_id(irq_num), cpu_mask));
+    }
 
     fn new() -> Self {
         IRQManager {
@@ -256,11 +578,41 @@ impl int::IRQManager for IRQManager {
         }
     }
 
+    /// Only SPIs are delivered to user processes.
+    fn device_irq(n: u32) -> Option<Self::IRQNumberType> {
+        if (32..GIC_MAX_INTS as u32).contains(&n) {
+            Some(n as IRQNumber)
+        } else {
+            None
+        }
+    }
+
+    fn device_num(irq_num: Self::IRQNumberType) -> u32 {
+        it_id(irq_num) as u32
+    }
+
     fn register_handler(
         &mut self,
         irq_num: Self::IRQNumberType,
         handler: IRQ<Self::IRQNumberType>,
-    ) {
-        self.handlers[irq_num as usize] = Some(handler);
+    ) -> bool {
+        let it = it_id(irq_num);
+        if self.handlers[it].is_some() {
+            return false;
+        }
+
+        // route it to this CPU with the default priority
+        with_gic(|g| {
+            g.it_add(it);
+            g.it_set_cpu_mask(it, 1 << core_pos());
+            g.it_set_prio(it, DEFAULT_PRIORITY);
+        });
+
+        self.handlers[it] = Some(handler);
+        true
+    }
+
+    fn unregister_handler(&mut self, irq_num: Self::IRQNumberType) {
+        self.handlers[it_id(irq_num)] = None;
     }
 }
@@ -4,6 +4,9 @@ use super::device::raspi::topology;
 #[cfg(feature = "pine64")]
 use super::device::allwinner::topology;
 
+#[cfg(feature = "virt")]
+use super::device::virt::topology;
+
 use crate::aarch64::cpu;
 
 pub const MAX_CPUS_PER_CLUSTER: usize = topology::MAX_CPUS_PER_CLUSTER;
@@ -1,5 +1,3 @@
-use alloc::vec::Vec;
-
 pub mod pl011;
 pub mod sunxi_uart;
 
@@ -10,6 +8,7 @@ pub trait UART {
     fn new(base: usize) -> Self;
     fn send(&self, c: u32);
     fn recv(&self) -> u32;
+    fn try_recv(&self) -> Option<u32>;
     fn enable_recv_interrupt(&self);
     fn disable_recv_interrupt(&self);
     fn on(&self);
@@ -23,6 +22,9 @@ type DevUART = super::device::allwinner::uart::A64UART;
 #[cfg(any(feature = "raspi3", feature = "raspi4"))]
 type DevUART = super::device::raspi::uart::RaspiUART;
 
+#[cfg(feature = "virt")]
+type DevUART = super::device::virt::uart::VirtUART;
+
 impl DevUART where DevUART: UART {}
 
 const UART0: DevUART = DevUART {};
@@ -31,10 +33,6 @@ fn send(c: u32) {
     UART0.send(c);
 }
 
-fn recv() -> u32 {
-    UART0.recv()
-}
-
 pub fn enable_recv_interrupt() {
     UART0.enable_recv_interrupt()
 }
@@ -43,13 +41,6 @@ pub fn disable_recv_interrupt() {
     UART0.disable_recv_interrupt()
 }
 
-pub fn enable_recv_int() {
-    //let mut node = mcs::MCSNode::new();
-    //let _lock = lock(&mut node);
-    //uart::enable_recv_int();
-    todo!("")
-}
-
 pub fn init() {
     UART0.init(UART_CLOCK, UART_BAUD);
 }
@@ -115,41 +106,3 @@ pub fn decimal(mut h: u64) {
         i -= 1;
     }
 }
-
-pub fn read_line() -> Vec<u8> {
-    let mut res = Vec::new();
-
-    loop {
-        let c = recv() as u8;
-        if c == b'\r' || c == b'\n' {
-            break;
-        } else if c == 0x08 || c == 0x7F {
-            if !res.is_empty() {
-                send(0x08);
-                send(b' ' as u32);
-                send(0x08);
-                res.pop();
-            }
-        } else if c == b'\t' {
-            let c = b' ';
-            for _ in 0..8 {
-                send(c as u32);
-                res.push(c);
-            }
-        } else if c == 0x15 {
-            while !res.is_empty() {
-                send(0x08);
-                send(b' ' as u32);
-                send(0x08);
-                res.pop();
-            }
-        } else {
-            send(c as u32);
-            res.push(c);
-        }
-    }
-
-    puts("\n");
-
-    res
-}
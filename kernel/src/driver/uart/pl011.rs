@@ -16,7 +16,10 @@ const IFLS_RXIFLSEL_1_2: u32 = 0b010 << 3;
 const IFLS_RXIFLSEL_3_4: u32 = 0b011 << 3;
 const IFLS_RXIFLSEL_7_8: u32 = 0b100 << 3;
 
-const IMSC_RXIM: u32 = 1 << 4;
+const IMSC_RXIM: u32 = 1 << 4; // receive interrupt
+const IMSC_RTIM: u32 = 1 << 6; // receive timeout interrupt
+
+const FR_RXFE: u32 = 1 << 4; // receive FIFO empty
 
 pub struct PL011 {
     base: usize,
@@ -81,19 +84,29 @@ impl UART for PL011 {
         self.uart0_dr().read()
     }
 
+    fn try_recv(&self) -> Option<u32> {
+        if self.uart0_fr().read() & FR_RXFE != 0 {
+            None
+        } else {
+            Some(self.uart0_dr().read())
+        }
+    }
+
+    /// The receive timeout interrupt is enabled as well,
+    /// because the receive interrupt is asserted only when the FIFO reaches the fill level.
     fn enable_recv_interrupt(&self) {
-        self.uart0_imsc().setbits(IMSC_RXIM);
+        self.uart0_imsc().setbits(IMSC_RXIM | IMSC_RTIM);
     }
 
     fn disable_recv_interrupt(&self) {
-        self.uart0_imsc().clrbits(IMSC_RXIM);
+        self.uart0_imsc().clrbits(IMSC_RXIM | IMSC_RTIM);
     }
 
     fn on(&self) {
-        self.uart0_cr().write(0); // turn off UART0
+        self.uart0_cr().write(CR_EN | CR_RXE | CR_TXE); // enable, Rx, Tx
     }
 
     fn off(&self) {
-        self.uart0_cr().write(CR_EN | CR_RXE | CR_TXE); // enable, Rx, Tx
+        self.uart0_cr().write(0); // turn off UART0
     }
 }
@@ -1,20 +1,68 @@
-use crate::driver::uart::UART;
+use crate::{driver::uart::UART, mmio_rw_base};
+use core::arch::asm;
+
+const IER_ERBFI: u32 = 1; // enable received data available interrupt
+
+const FCR_FIFOE: u32 = 1; // enable FIFOs
+
+const LSR_DR: u32 = 1; // data ready
+const LSR_THRE: u32 = 1 << 5; // TX holding register empty
 
 pub struct SunxiUART {
     base: usize,
 }
 
+impl SunxiUART {
+    mmio_rw_base!(0x000 => uart_thr<u32>); // transmit holding register
+    mmio_rw_base!(0x000 => uart_rbr<u32>); // receive buffer register
+    mmio_rw_base!(0x004 => uart_ier<u32>); // interrupt enable register
+    mmio_rw_base!(0x008 => uart_fcr<u32>); // FIFO control register
+    mmio_rw_base!(0x014 => uart_lsr<u32>); // line status register
+}
+
 impl UART for SunxiUART {
     fn new(base: usize) -> Self {
         Self { base }
     }
-    fn send(&self, _c: u32) {}
+
+    /// send a character to serial console
+    fn send(&self, c: u32) {
+        while self.uart_lsr().read() & LSR_THRE == 0 {
+            unsafe { asm!("nop;") };
+        }
+
+        self.uart_thr().write(c);
+    }
+
     fn recv(&self) -> u32 {
-        0
+        while self.uart_lsr().read() & LSR_DR == 0 {
+            unsafe { asm!("nop;") };
+        }
+
+        self.uart_rbr().read()
     }
-    fn enable_recv_interrupt(&self) {}
-    fn disable_recv_interrupt(&self) {}
+
+    fn try_recv(&self) -> Option<u32> {
+        if self.uart_lsr().read() & LSR_DR == 0 {
+            None
+        } else {
+            Some(self.uart_rbr().read())
+        }
+    }
+
+    fn enable_recv_interrupt(&self) {
+        self.uart_ier().setbits(IER_ERBFI);
+    }
+
+    fn disable_recv_interrupt(&self) {
+        self.uart_ier().clrbits(IER_ERBFI);
+    }
+
     fn on(&self) {}
     fn off(&self) {}
-    fn init(&self, _clock: usize, _baudrate: usize) {}
+
+    /// The baud rate has been configured by the boot loader.
+    fn init(&self, _clock: usize, _baudrate: usize) {
+        self.uart_fcr().write(FCR_FIFOE);
+    }
 }
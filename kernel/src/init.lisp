@@ -1,10 +1,160 @@
 (export spawn (app) (IO (-> (Int) (Option Int)))
     (call-rust 1 app 0))
 
-(export exit () (IO (-> () []))
-    (let ((_ (call-rust 2 0 0)))
+; copy this process, and return the child's pid to the parent and 0 to the child
+(export fork () (IO (-> () (Option Int)))
+    (call-rust 34 0 0))
+
+; spawn a process whose mailbox can queue cap messages
+(export spawn_cap (app cap) (IO (-> (Int Int) (Option Int)))
+    (call-rust 1 app cap))
+
+; spawn a process of priority prio, where 0 is the highest and 7 is the lowest
+; the priority of spawn and spawn_cap is 4
+(export spawn_prio (app cap prio) (IO (-> (Int Int Int) (Option Int)))
+    (match (call-rust 105 prio 0)
+        ((Some _) (call-rust 1 app cap))
+        (_ None)))
+
+; spawn a process with a list of integers and strings as its arguments
+; None is returned if the list has another kind of values
+(export spawn_args (app xs) (IO (-> (Int '(Val)) (Option Int)))
+    (let ((_ (push_vals xs)))
+        (call-rust 1 app 0)))
+
+; the number of the arguments passed by the parent
+(export argc () (IO (-> () Int))
+    (match (call-rust 108 0 0)
+        ((Some n) n)
+        (_ 0)))
+
+; the i-th argument, which is VInt or VStr
+(export arg (i) (IO (-> (Int) (Option Val)))
+    (match (call-rust 109 i 0)
+        ((Some _) (Some (read_val)))
+        (_ None)))
+
+; the arguments
+(export args () (IO (-> () '(Val)))
+    (args' 0 (argc)))
+
+(defun args' (i n) (IO (-> (Int Int) '(Val)))
+    (if (< i n)
+        (match (arg i)
+            ((Some x) (Cons x (args' (+ i 1) n)))
+            (_ (args' (+ i 1) n)))
+        '()))
+
+; set the priority of pid
+(export setpriority (pid prio) (IO (-> (Int Int) Bool))
+    (match (call-rust 19 pid prio)
+        ((Some _) true)
+        (_ false)))
+
+; set the maximum number of pages which pid can map
+; only the parent of pid or the init process can set it,
+; and a process cannot raise its own
+(export setquota (pid pages) (IO (-> (Int Int) Bool))
+    (match (call-rust 33 pid pages)
+        ((Some _) true)
+        (_ false)))
+
+(export exit (code) (IO (-> (Int) []))
+    (let ((_ (call-rust 2 code 0)))
         []))
 
+; how a child process terminated
+(data Exit
+    (Exited Int)
+    Killed
+    StackOverflow
+    InvalidAccess
+    Error
+    QuotaExceeded
+    OutOfMemory)
+
+; wait for a child process to terminate
+(export wait (pid) (IO (-> (Int) (Option Exit)))
+    (match (call-rust 15 pid 0)
+        ((Some kind) (Some (to_exit kind (exit_code))))
+        (_ None)))
+
+(defun to_exit (kind code) (Pure (-> (Int Int) Exit))
+    (if (= kind 0)
+        (Exited code)
+        (if (= kind 1)
+            Killed
+            (if (= kind 2)
+                StackOverflow
+                (if (= kind 3)
+                    InvalidAccess
+                    (if (= kind 5)
+                        QuotaExceeded
+                        (if (= kind 6)
+                            OutOfMemory
+                            Error)))))))
+
+(defun exit_code () (IO (-> () Int))
+    (match (call-rust 102 0 0)
+        ((Some code) code)
+        (_ 0))) ; unreachable
+
+; scheduler counters of cpu
+; (switches steals migrations lock_wait_usec lock_count)
+(export sched_stats (cpu) (IO (-> (Int) '(Int)))
+    (sched_stats' cpu 0))
+
+(defun sched_stats' (cpu i) (IO (-> (Int Int) '(Int)))
+    (match (call-rust 20 cpu i)
+        ((Some x) (Cons x (sched_stats' cpu (+ i 1))))
+        (_ '())))
+
+; states of processes
+(data ProcState
+    Ready
+    Active
+    Recv
+    Send
+    Blocked
+    Wait
+    Sleep
+    Killed
+    Zombie)
+
+; a process
+; (Ps pid state cpu priority queued_messages mapped_pages cpu_time_usec)
+(data Ps
+    (Ps Int ProcState Int Int Int Int Int))
+
+; take a snapshot of the processes
+(export ps () (IO (-> () '(Ps)))
+    (match (call-rust 23 0 0)
+        ((Some n) (ps' 0 n))
+        (_ '())))
+
+(defun ps' (i n) (IO (-> (Int Int) '(Ps)))
+    (if (< i n)
+        (Cons (Ps (ps_at i 0) (to_state (ps_at i 1)) (ps_at i 2) (ps_at i 3)
+                  (ps_at i 4) (ps_at i 5) (ps_at i 6))
+              (ps' (+ i 1) n))
+        '()))
+
+(defun ps_at (i field) (IO (-> (Int Int) Int))
+    (match (call-rust 106 i field)
+        ((Some x) x)
+        (_ 0)))
+
+(defun to_state (n) (Pure (-> (Int) ProcState))
+    (if (= n 0) Ready
+    (if (= n 1) Active
+    (if (= n 2) Recv
+    (if (= n 3) Send
+    (if (= n 4) Blocked
+    (if (= n 5) Wait
+    (if (= n 6) Sleep
+    (if (= n 7) Killed
+        Zombie)))))))))
+
 (export sched_yield () (IO (-> () []))
     (let ((_ (call-rust 3 0 0)))
         []))
@@ -14,15 +164,232 @@
         ((Some id) id)
         (_ 0))) ; unreachable
 
-(export send (dst val) (IO (-> (Int Int) Bool))
-    (match (call-rust 5 dst val)
+; a value of messages
+(data Val
+    (VInt Int)
+    (VStr String)
+    (VBool Bool)
+    (VList '(Val))
+    (VOption (Option Val)))
+
+; a destination of messages
+(data Dst
+    (Pid Int)       ; process
+    (Name String))  ; process registered as the name
+
+(export send (dst val) (IO (-> (Dst Val) Bool))
+    (send' 5 dst val))
+
+(export recv () (IO (-> () Val))
+    (let ((_ (call-rust 6 0 0)))
+        (read_val)))
+
+; send a value, and block while the mailbox of dst is full
+(export send_wait (dst val) (IO (-> (Dst Val) Bool))
+    (send' 14 dst val))
+
+; a name is built after the value, and z is 1 for it
+(defun send' (op dst val) (IO (-> (Int Dst Val) Bool))
+    (let ((_ (push_val val)))
+        (match (match dst
+                   ((Pid pid) (call-rust op pid 0))
+                   ((Name name)
+                       (let ((_ (push_val (VStr name))))
+                           (call-rust op 0 1))))
+            ((Some _) true)
+            (_ false))))
+
+; a received message
+(data Msg
+    (Msg Int Val)     ; sender and value
+    (Down Int Exit)   ; terminated process and how it terminated
+    (Irq Int))        ; device interrupt
+
+; receive a message, which may be DOWN or a device interrupt
+(export recv_msg () (IO (-> () Msg))
+    (let ((_ (call-rust 6 0 0)))
+        (match (call-rust 104 0 0)
+            ((Some pid) (Down pid (to_down_exit (read_val))))
+            (_ (match (call-rust 107 0 0)
+                ((Some irq) (Irq irq))
+                (_ (Msg (sender) (read_val))))))))
+
+(defun sender () (IO (-> () Int))
+    (match (call-rust 103 0 0)
+        ((Some pid) pid)
+        (_ 0)))
+
+; a DOWN message carries the kind and the exit code
+(defun to_down_exit (val) (Pure (-> (Val) Exit))
+    (match val
+        ((VList (Cons (VInt kind) (Cons (VInt code) _))) (to_exit kind code))
+        (_ Error)))
+
+; send a DOWN message to the caller when pid terminates
+(export monitor (pid) (IO (-> (Int) Bool))
+    (match (call-rust 16 pid 0)
         ((Some _) true)
         (_ false)))
 
-(export recv () (IO (-> () Int))
-    (match (call-rust 6 0 0)
-        ((Some val) val)
-        (_ 0))) ; unreachable
+; kill each other when one of them terminates abnormally
+(export link (pid) (IO (-> (Int) Bool))
+    (match (call-rust 17 pid 0)
+        ((Some _) true)
+        (_ false)))
+
+; receive DOWN messages instead of being killed by links
+(export trap_exit (flag) (IO (-> (Bool) []))
+    (let ((_ (call-rust 18 (if flag 1 0) 0)))
+        []))
+
+(defun push_msg (xs) (IO (-> ('(Int)) []))
+    (match xs
+        ((Cons h t)
+            (let ((_ (call-rust 100 h 0)))
+                (push_msg t)))
+        (_ [])))
+
+; build a value in Rust, which is sent by the next call-rust
+(defun push_val (val) (IO (-> (Val) []))
+    (match val
+        ((VInt n) (let ((_ (call-rust 100 n 0))) []))
+        ((VStr s)
+            (let ((_ (call-rust 110 0 0)))
+                (let ((_ (push_msg (map_code (chars s)))))
+                    (let ((_ (call-rust 111 0 0))) []))))
+        ((VBool b) (let ((_ (call-rust 113 (if b 1 0) 0))) []))
+        ((VList xs)
+            (let ((_ (call-rust 110 0 0)))
+                (let ((_ (push_vals xs)))
+                    (let ((_ (call-rust 112 0 0))) []))))
+        ((VOption (Some x))
+            (let ((_ (push_val x)))
+                (let ((_ (call-rust 114 1 0))) [])))
+        (_ (let ((_ (call-rust 114 0 0))) []))))
+
+(defun push_vals (xs) (IO (-> ('(Val)) []))
+    (match xs
+        ((Cons h t)
+            (let ((_ (push_val h)))
+                (push_vals t)))
+        (_ [])))
+
+; read the last received value, which Rust flattened to integers
+(defun read_val () (IO (-> () Val))
+    (let ((tag (next_int)))
+        (if (= tag 0) (VInt (next_int))
+        (if (= tag 1) (VStr (str (read_chars (next_int))))
+        (if (= tag 2) (VBool (= (next_int) 1))
+        (if (= tag 3) (VList (read_vals (next_int)))
+        (if (= tag 4)
+            (if (= (next_int) 1)
+                (VOption (Some (read_val)))
+                (VOption None))
+            (VList '()))))))))
+
+(defun read_vals (n) (IO (-> (Int) '(Val)))
+    (if (> n 0)
+        (let ((x (read_val)))
+            (Cons x (read_vals (- n 1))))
+        '()))
+
+(defun read_chars (n) (IO (-> (Int) '(Char)))
+    (if (> n 0)
+        (let ((c (to_char (next_int))))
+            (Cons c (read_chars (- n 1))))
+        '()))
+
+(defun next_int () (IO (-> () Int))
+    (match (call-rust 101 0 0)
+        ((Some x) x)
+        (_ 0)))
+
+; characters are exchanged as ASCII codes
+; printable characters except double quote and backslash are supported,
+; and the others are replaced by ?
+(defun ascii () (Pure (-> () '(Char)))
+    (chars " !#$%&'()*+,-./0123456789:;<=>?@ABCDEFGHIJKLMNOPQRSTUVWXYZ[]^_`abcdefghijklmnopqrstuvwxyz{|}~"))
+
+(defun map_code (cs) (Pure (-> ('(Char)) '(Int)))
+    (match cs
+        ((Cons c t) (Cons (to_code c (ascii) 0) (map_code t)))
+        (_ '())))
+
+(defun to_code (c cs i) (Pure (-> (Char '(Char) Int) Int))
+    (match cs
+        ((Cons h t)
+            (if (eq c h)
+                (if (< i 2) (+ i 32) (if (< i 59) (+ i 33) (+ i 34)))
+                (to_code c t (+ i 1))))
+        (_ 63)))
+
+(defun to_char (code) (Pure (-> (Int) Char))
+    (if (or (< code 32) (> code 126))
+        `?`
+        (if (or (= code 34) (= code 92))
+            `?`
+            (nth_char (if (< code 34) (- code 32) (if (< code 92) (- code 33) (- code 34)))
+                      (ascii)))))
+
+(defun nth_char (i cs) (Pure (-> (Int '(Char)) Char))
+    (match cs
+        ((Cons c t) (if (= i 0) c (nth_char (- i 1) t)))
+        (_ `?`)))
+
+(export try_recv () (IO (-> () (Option Val)))
+    (match (call-rust 12 0 0)
+        ((Some _) (Some (read_val)))
+        (_ None)))
+
+(export recv_timeout (ms) (IO (-> (Int) (Option Val)))
+    (match (call-rust 13 ms 0)
+        ((Some _) (Some (read_val)))
+        (_ None)))
+
+; register the caller as name
+; a process has at most one name, which is removed when it terminates
+(export register (name) (IO (-> (String) Bool))
+    (let ((_ (push_val (VStr name))))
+        (match (call-rust 24 0 0)
+            ((Some _) true)
+            (_ false))))
+
+; get the process registered as name
+(export whereis (name) (IO (-> (String) (Option Int)))
+    (let ((_ (push_val (VStr name))))
+        (call-rust 25 0 0)))
+
+; remove the name of the caller
+(export unregister () (IO (-> () Bool))
+    (match (call-rust 26 0 0)
+        ((Some _) true)
+        (_ false)))
+
+; sleep for ms milliseconds
+(export sleep (ms) (IO (-> (Int) []))
+    (let ((_ (call-rust 21 ms 0)))
+        []))
+
+; send val to pid after ms milliseconds
+; false is returned if pid does not exist
+(export send_after (ms pid val) (IO (-> (Int Int Val) Bool))
+    (let ((_ (push_val val)))
+        (match (call-rust 22 pid ms)
+            ((Some _) true)
+            (_ false))))
+
+; receive the device interrupt irq as (Irq irq)
+; irq stays masked until ack_irq is called
+(export subscribe_irq (irq) (IO (-> (Int) Bool))
+    (match (call-rust 29 irq 0)
+        ((Some _) true)
+        (_ false)))
+
+; unmask irq after handling it
+(export ack_irq (irq) (IO (-> (Int) Bool))
+    (match (call-rust 30 irq 0)
+        ((Some _) true)
+        (_ false)))
 
 (export kill (pid) (IO (-> (Int) []))
     (let ((_ (call-rust 9 pid 0)))
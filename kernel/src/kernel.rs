@@ -1,19 +1,39 @@
 use crate::{
-    aarch64::mmu,
+    aarch64::{mmu, timer},
+    bsp::{self, int::IRQ},
     cpuint,
     driver::topology,
     process::set_tpid_kernel,
-    {allocator, out, paging, process},
+    tty, {allocator, out, paging, process},
 };
 
 pub fn kernel_entry() {
     // make tpidrro_el0 kernel space
     set_tpid_kernel();
 
+    let aff = topology::core_pos() as u64;
+
+    if aff == 0 {
+        assert!(bsp::int::register_handler(
+            bsp::int::IRQ_TIMER,
+            IRQ::new("scheduler tick", on_tick)
+        ));
+        assert!(bsp::int::register_handler(
+            bsp::int::IRQ_IPI,
+            IRQ::new("IPI", on_ipi)
+        ));
+
+        // receive the serial console by interrupts
+        tty::handler::init();
+    }
+
     // enable IRQ and FIQ
     cpuint::enable_irq();
 
-    let aff = topology::core_pos() as u64;
+    // start the scheduler tick
+    timer::init();
+    bsp::int::enable_local_irq_num(bsp::int::IRQ_TIMER);
+    bsp::int::enable_local_irq_num(bsp::int::IRQ_IPI);
 
     // spawn init process
     if aff == 0 {
@@ -36,7 +56,21 @@ pub fn kernel_entry() {
             out::msg("Buddy allocator (Kernel)", &msg);
         }
 
+        // power on the secondary CPUs, which wait for Ready processes
+        bsp::start_secondaries();
+
         // spawn the init process
         process::init();
+    } else {
+        process::start_secondary();
     }
 }
+
+fn on_tick(_irq_num: bsp::int::DevIRQNumber) {
+    timer::reload();
+    process::tick();
+}
+
+fn on_ipi(_irq_num: bsp::int::DevIRQNumber) {
+    process::on_ipi();
+}
@@ -35,7 +35,7 @@ pub fn entry() {
     if driver::topology::core_pos() == 0 {
         init_primary();
     } else {
-        // called from vector_cpu_on_entry
+        // powered on by bsp::start_secondaries or vector_cpu_on_entry
         init_secondary();
         return;
     }
@@ -78,6 +78,8 @@ fn init_secondary() {
     }
 
     aarch64::mmu::set_regs();
+    bsp::init_secondary();
+    kernel::kernel_entry();
 }
 
 //-----------------------------------------------------------------------------
@@ -1,4 +1,16 @@
-use crate::{aarch64::mmu, allocator, cpuint, global::GlobalVar, process::get_raw_id};
+use crate::{
+    aarch64::mmu,
+    allocator, cpuint,
+    driver::topology::{core_pos, CORE_COUNT},
+    global::GlobalVar,
+    process::{get_raw_id, PROCESS_MAX, TABLE_CHUNK},
+    syscall::{PROT_READ, PROT_WRITE},
+};
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use core::{
+    ptr::null_mut,
+    sync::atomic::{AtomicPtr, Ordering},
+};
 use memac::pager::PageManager;
 use synctools::mcs::{MCSLock, MCSNode};
 
@@ -9,9 +21,39 @@ pub enum FaultResult {
     Ok,
     StackOverflow,
     InvalidAccess,
+    QuotaExceeded,
+    OutOfMemory,
 }
 
+/// Why pages could not be mapped.
+enum MapError {
+    QuotaExceeded, // the page quota of the process
+    OutOfMemory,   // no page is left in the pager
+}
+
+/// Number of pages kept for the kernel heap and page tables,
+/// so that the scheduler and the console keep working even if processes exhaust the pager.
+const KERN_RESERVE: usize = 64;
+
+/// Number of free pages of the pager.
+/// This is updated while locking PAGER.
+static mut FREE_PAGES: usize = 0;
+
+/// Maximum number of pages of the pager, which is placed in the first 128MiB of RAM.
+/// See `mmu::Addr::init`.
+const PAGER_PAGES_MAX: usize = 128 * 1024 * 1024 / memac::ALIGNMENT;
+
+/// Start address of the pager.
+static mut PAGER_START: usize = 0;
+
+/// Number of mappings of each page of the pager.
+/// A page shared copy-on-write is returned to the pager when the last mapping is unmapped.
+/// This is updated while locking PAGER.
+static mut PAGE_REFS: [u16; PAGER_PAGES_MAX] = [0; PAGER_PAGES_MAX];
+
 pub fn init(start: usize, end: usize) {
+    assert!((end - start) / memac::ALIGNMENT <= PAGER_PAGES_MAX);
+
     let mut pager = PageManager::new();
     pager.set_range(start, end);
 
@@ -19,95 +61,643 @@ pub fn init(start: usize, end: usize) {
     let mut lock = PAGER.lock(&mut node);
     if let GlobalVar::UnInit = *lock {
         *lock = GlobalVar::Having(pager);
+        unsafe {
+            FREE_PAGES = (end - start) / memac::ALIGNMENT;
+            PAGER_START = start;
+        }
     } else {
         panic!("initialized twice");
     }
 }
 
-fn map_user(vm_addr: usize, id: u8) -> FaultResult {
+/// Slot + 1 whose table was installed last with each ASID on each CPU.
+/// This is used only if ASIDs are 8 bits.
+static mut ASID_OWNER: [[u16; 256]; CORE_COUNT] = [[0; 256]; CORE_COUNT];
+
+/// Memory of a slot of the process table.
+#[derive(Clone, Copy)]
+struct UserSpace {
+    /// Physical address of the level 2 table of TTBR0, 0 means none.
+    /// A table is allocated when the memory of its slot is mapped first.
+    /// Like level 3 tables, it is never freed and is reused by processes of the same slot,
+    /// so TTBR0 of a CPU never points to a freed page.
+    ttbr0: u64,
+
+    /// Number of pages mapped, excluding tables.
+    /// This is updated while locking PAGER.
+    pages: u32,
+
+    /// Maximum number of pages which can be mapped.
+    quota: u32,
+
+    /// Slot + 1 whose addresses are used, 0 means its own.
+    /// A child of `fork` uses the addresses of its parent, because pointers are copied as they are.
+    /// Level 3 tables for the addresses are allocated in the table of the child's slot,
+    /// and they are reused like the other tables.
+    layout: u32,
+}
+
+impl UserSpace {
+    const fn new() -> UserSpace {
+        UserSpace {
+            ttbr0: 0,
+            pages: 0,
+            quota: 0,
+            layout: 0,
+        }
+    }
+}
+
+type SpaceChunk = [UserSpace; TABLE_CHUNK];
+
+/// Memory of the slots, which is allocated by TABLE_CHUNK slots as the process table is extended.
+/// Chunks are never freed nor moved, so that they are accessed without locks.
+static SPACES: [AtomicPtr<SpaceChunk>; PROCESS_MAX / TABLE_CHUNK] = {
+    const NONE: AtomicPtr<SpaceChunk> = AtomicPtr::new(null_mut());
+    [NONE; PROCESS_MAX / TABLE_CHUNK]
+};
+
+/// Allocate the memory of the slots up to `len`.
+/// This is called while locking PROC_INFO, when the process table is extended.
+pub fn extend_slots(len: usize) {
+    for chunk in SPACES[..(len + TABLE_CHUNK - 1) / TABLE_CHUNK].iter() {
+        if chunk.load(Ordering::Relaxed).is_null() {
+            let new = Box::new([UserSpace::new(); TABLE_CHUNK]);
+            chunk.store(Box::into_raw(new), Ordering::Release);
+        }
+    }
+}
+
+/// Get the memory of the slot `id`, which must exist in the process table.
+fn user_space(id: u32) -> &'static mut UserSpace {
+    let chunk = SPACES[id as usize / TABLE_CHUNK].load(Ordering::Acquire);
+    assert!(!chunk.is_null(), "no such slot");
+    unsafe { &mut (*chunk)[id as usize % TABLE_CHUNK] }
+}
+
+/// Get the slot whose addresses are used by the memory of the process `id`.
+/// The addresses of the memory are given by `allocator::user_mem(layout(id))` and so on.
+pub fn layout(id: u32) -> u32 {
+    match user_space(id).layout {
+        0 => id,
+        n => n - 1,
+    }
+}
+
+/// An anonymous mapping, [start, end).
+#[derive(Clone)]
+struct Region {
+    start: usize,
+    end: usize,
+    prot: u8,
+}
+
+/// Anonymous mappings of the slots, sorted by the addresses.
+/// This must not be locked while locking PAGER.
+static REGIONS: MCSLock<BTreeMap<u32, Vec<Region>>> = MCSLock::new(BTreeMap::new());
+
+/// Address space to map or unmap pages.
+#[derive(Clone, Copy)]
+enum Space {
+    Kernel,    // TTBR1
+    User(u32), // TTBR0 of the slot
+}
+
+fn map_user(vm_addr: usize, id: u32) -> FaultResult {
     let vm_addr = vm_addr & memac::MASK;
+    let base = layout(id);
 
-    if allocator::is_user_canary(id, vm_addr) {
+    if allocator::is_user_canary(base, vm_addr) {
         return FaultResult::StackOverflow;
     }
 
-    if !allocator::is_user_mem(id, vm_addr) {
+    // only writing a copy-on-write page causes a fault
+    if get_user_table(id).map_or(false, |ttbr| ttbr.is_cow(vm_addr as u64)) {
+        return match copy_on_write(id, vm_addr) {
+            Ok(()) => FaultResult::Ok,
+            Err(MapError::QuotaExceeded) => FaultResult::QuotaExceeded,
+            Err(MapError::OutOfMemory) => FaultResult::OutOfMemory,
+        };
+    }
+
+    let flag = if allocator::is_user_mem(base, vm_addr) {
+        mmu::user_page_flag()
+    } else if let Some(prot) = region_prot(id, vm_addr) {
+        if prot & PROT_WRITE != 0 {
+            mmu::user_page_flag()
+        } else if is_mapped(id, vm_addr) {
+            // write to a read-only mapping
+            return FaultResult::InvalidAccess;
+        } else {
+            mmu::user_page_flag_ro()
+        }
+    } else {
         return FaultResult::InvalidAccess;
+    };
+
+    match map(vm_addr, vm_addr, Space::User(id), flag, false) {
+        Ok(()) => FaultResult::Ok,
+        Err(MapError::QuotaExceeded) => FaultResult::QuotaExceeded,
+        Err(MapError::OutOfMemory) => FaultResult::OutOfMemory,
+    }
+}
+
+fn is_mapped(id: u32, vm_addr: usize) -> bool {
+    get_user_table(id)
+        .and_then(|ttbr| ttbr.to_phy_addr(vm_addr as u64))
+        .is_some()
+}
+
+/// Get the protection of the anonymous mapping containing vm_addr.
+fn region_prot(id: u32, vm_addr: usize) -> Option<u8> {
+    let _mask = cpuint::mask();
+    let mut node = MCSNode::new();
+    let regions = REGIONS.lock(&mut node);
+
+    regions
+        .get(&id)?
+        .iter()
+        .find(|r| r.start <= vm_addr && vm_addr < r.end)
+        .map(|r| r.prot)
+}
+
+/// Check that [start, last] is in an anonymous mapping of the process `id`.
+pub fn is_mmap_range(id: u32, start: usize, last: usize) -> bool {
+    if !allocator::is_user_mmap(layout(id), start) {
+        return false;
+    }
+
+    let _mask = cpuint::mask();
+    let mut node = MCSNode::new();
+    let regions = REGIONS.lock(&mut node);
+
+    if let Some(regions) = regions.get(&id) {
+        regions
+            .iter()
+            .any(|r| r.start <= start && start <= last && last < r.end)
+    } else {
+        false
     }
+}
+
+/// Reserve an anonymous mapping of `len` bytes for the process `id`, and return its address.
+/// `prot` is PROT_READ, PROT_WRITE or both, and a writable mapping is also readable.
+/// Pages are mapped on demand within the page quota of the process.
+/// None is returned if `prot` is invalid or there is no space.
+pub fn mmap_anon(id: u32, len: usize, prot: u8) -> Option<usize> {
+    if len == 0 || prot == 0 || prot & !(PROT_READ | PROT_WRITE) != 0 {
+        return None;
+    }
+
+    let len = len.checked_add(memac::ALIGNMENT - 1)? & memac::MASK;
+    let (mut start, end) = allocator::user_mmap(layout(id));
+
+    let _mask = cpuint::mask();
+    let mut node = MCSNode::new();
+    let mut regions = REGIONS.lock(&mut node);
+    let regions = regions.entry(id).or_default();
+
+    // first fit
+    let mut i = 0;
+    for r in regions.iter() {
+        if r.start - start >= len {
+            break;
+        }
+        start = r.end;
+        i += 1;
+    }
+
+    if end - start < len {
+        return None;
+    }
+
+    regions.insert(
+        i,
+        Region {
+            start,
+            end: start + len,
+            prot,
+        },
+    );
+
+    Some(start)
+}
 
-    map(vm_addr, vm_addr, false);
-    FaultResult::Ok
+/// Remove the anonymous mapping at `addr` of the process `id`, and unmap its pages.
+/// The whole mapping must be specified, otherwise false is returned.
+pub fn munmap(id: u32, addr: usize, len: usize) -> bool {
+    let len = if let Some(len) = len.checked_add(memac::ALIGNMENT - 1) {
+        len & memac::MASK
+    } else {
+        return false;
+    };
+
+    let region = {
+        let _mask = cpuint::mask();
+        let mut node = MCSNode::new();
+        let mut regions = REGIONS.lock(&mut node);
+
+        let regions = if let Some(regions) = regions.get_mut(&id) {
+            regions
+        } else {
+            return false;
+        };
+
+        if let Some(i) = regions
+            .iter()
+            .position(|r| r.start == addr && r.end - r.start == len)
+        {
+            regions.remove(i)
+        } else {
+            return false;
+        }
+    };
+
+    // the last page must not be beyond the mapping
+    unmap(region.start, region.end - memac::ALIGNMENT, Space::User(id));
+
+    true
 }
 
-pub fn unmap_user(start: usize, end: usize, id: u8) {
+/// Set the maximum number of pages of the process `id`.
+/// Pages mapped already are not unmapped even if they exceed the quota.
+pub fn set_quota(id: u32, pages: u32) {
+    user_space(id).quota = pages;
+}
+
+/// Map the kernel stack of the new process `id`, see `allocator::kern_stack`.
+/// It is mapped beforehand in TTBR1, so that the kernel never faults on it
+/// and it is accessible whichever TTBR0 is installed.
+/// false is returned if the pager does not have enough free pages.
+/// The stack is unmapped by `unmap_user_all`.
+pub fn map_kern_stack(id: u32) -> bool {
+    let (start, end) = allocator::kern_stack(id);
+    let last = end - memac::ALIGNMENT;
+    if map(start, last, Space::Kernel, mmu::kernel_page_flag(), false).is_err() {
+        unmap(start, last, Space::Kernel);
+        return false;
+    }
+
+    true
+}
+
+/// Get the maximum number of pages of the process `id`.
+pub fn quota(id: u32) -> u32 {
+    user_space(id).quota
+}
+
+/// Write data to the memory of the process `id`, mapping pages if needed.
+/// Pages are written through the physical addresses,
+/// so that the process does not need to be running on this CPU.
+pub fn write_user(id: u32, vm_addr: usize, data: &[u8]) -> bool {
+    if data.is_empty() {
+        return true;
+    }
+
+    let base = layout(id);
+    let last = vm_addr + data.len() - 1;
+    if allocator::is_user_canary(base, vm_addr)
+        || !allocator::is_user_mem(base, vm_addr)
+        || !allocator::is_user_mem(base, last)
+    {
+        return false;
+    }
+
+    if map(
+        vm_addr & memac::MASK,
+        last & memac::MASK,
+        Space::User(id),
+        mmu::user_page_flag(),
+        false,
+    )
+    .is_err()
+    {
+        return false;
+    }
+
+    let ttbr = if let Some(ttbr) = get_user_table(id) {
+        ttbr
+    } else {
+        return false;
+    };
+
+    let mut addr = vm_addr;
+    let mut rest = data;
+    while !rest.is_empty() {
+        // the page must not be written through while it is shared
+        if ttbr.is_cow(addr as u64) && copy_on_write(id, addr & memac::MASK).is_err() {
+            return false;
+        }
+
+        let phy_addr = if let Some(phy_addr) = ttbr.to_phy_addr(addr as u64) {
+            phy_addr
+        } else {
+            return false;
+        };
+
+        // the pager's memory is mapped to the same addresses for EL1
+        let len = rest.len().min(memac::ALIGNMENT - (addr & !memac::MASK));
+        unsafe { core::ptr::copy_nonoverlapping(rest.as_ptr(), phy_addr as *mut u8, len) };
+
+        addr += len;
+        rest = &rest[len..];
+    }
+
+    true
+}
+
+pub fn unmap_user(start: usize, end: usize, id: u32) {
     let start = start & memac::MASK;
     let end = end & memac::MASK;
 
+    let base = layout(id);
     for addr in (start..end).step_by(memac::ALIGNMENT) {
-        if allocator::is_user_canary(id, addr) {
+        if allocator::is_user_canary(base, addr) {
             return;
         }
 
-        if !allocator::is_user_mem(id, addr) {
+        if !allocator::is_user_mem(base, addr) {
             return;
         }
     }
 
-    unmap(start, end, false);
+    unmap(start, end, Space::User(id));
+}
+
+pub fn unmap_user_all(id: u32) {
+    let base = layout(id);
+
+    let (start, end) = allocator::user_mem(base);
+    unmap(start, end, Space::User(id));
+
+    let (start, end) = allocator::user_mmap(base);
+    unmap(start, end - memac::ALIGNMENT, Space::User(id));
+
+    user_space(id).layout = 0;
+
+    // the process is no longer running on its kernel stack
+    let (start, end) = allocator::kern_stack(id);
+    unmap(start, end - memac::ALIGNMENT, Space::Kernel);
+
+    let _mask = cpuint::mask();
+    let mut node = MCSNode::new();
+    REGIONS.lock(&mut node).remove(&id);
+}
+
+/// Copy the address space of the process `parent` to the slot `child` for `fork`.
+/// Writable pages of the stack, the heap and the anonymous mappings are shared copy-on-write,
+/// and read-only pages are shared as they are.
+/// If this fails, the pages mapped so far must be released by `unmap_user_all(child)`.
+pub fn fork(parent: u32, child: u32) -> bool {
+    let base = layout(parent);
+
+    // REGIONS must be copied before locking PAGER
+    let regions = {
+        let _mask = cpuint::mask();
+        let mut node = MCSNode::new();
+        let regions = REGIONS.lock(&mut node);
+        regions.get(&parent).cloned()
+    };
+
+    if let Some(regions) = regions {
+        let _mask = cpuint::mask();
+        let mut node = MCSNode::new();
+        REGIONS.lock(&mut node).insert(child, regions);
+    }
+
+    user_space(child).layout = base + 1;
+    user_space(child).quota = user_space(parent).quota;
+
+    let mut parent_ttbr = if let Some(ttbr) = get_user_table(parent) {
+        ttbr
+    } else {
+        return true; // nothing is mapped
+    };
+
+    // disable interrupts
+    let _mask = cpuint::mask();
+
+    let mut node = MCSNode::new();
+    let mut lock = PAGER.lock(&mut node);
+
+    let pager = if let GlobalVar::Having(pager) = &mut *lock {
+        pager
+    } else {
+        return false;
+    };
+
+    let mut child_ttbr = if let Some(ttbr) = alloc_user_table(pager, child, false) {
+        ttbr
+    } else {
+        return false;
+    };
+
+    let (mem_start, mem_end) = allocator::user_mem(base);
+    let (mmap_start, mmap_end) = allocator::user_mmap(base);
+    let addrs = (mem_start..mem_end).chain(mmap_start..mmap_end);
+
+    let mut result = true;
+    for vm_addr in addrs.step_by(memac::ALIGNMENT) {
+        let phy_addr = if let Some(phy_addr) = parent_ttbr.to_phy_addr(vm_addr as u64) {
+            phy_addr
+        } else {
+            continue;
+        };
+
+        if !child_ttbr.has_table(vm_addr as u64) {
+            if let Some(table) = alloc_page(pager, false) {
+                set_table(&mut child_ttbr, vm_addr as u64, table);
+            } else {
+                result = false;
+                break;
+            }
+        }
+
+        parent_ttbr.set_cow(vm_addr as u64);
+        if let Some(flag) = parent_ttbr.get_flag(vm_addr as u64) {
+            child_ttbr.map(vm_addr as u64, phy_addr, flag);
+        }
+        *page_ref(phy_addr) += 1;
+
+        user_space(child).pages += 1;
+    }
+
+    // pages of the parent have been made read-only
+    mmu::tlb_flush_all();
+
+    result
+}
+
+/// Give the process `id` its own copy of the copy-on-write page at vm_addr.
+/// If no other process shares the page, it is just made writable.
+fn copy_on_write(id: u32, vm_addr: usize) -> Result<(), MapError> {
+    // disable interrupts
+    let _mask = cpuint::mask();
+
+    let mut node = MCSNode::new();
+    let mut lock = PAGER.lock(&mut node);
+
+    let pager = if let GlobalVar::Having(pager) = &mut *lock {
+        pager
+    } else {
+        return Err(MapError::OutOfMemory);
+    };
+
+    let mut ttbr = if let Some(ttbr) = get_user_table(id) {
+        ttbr
+    } else {
+        return Ok(());
+    };
+
+    if !ttbr.is_cow(vm_addr as u64) {
+        return Ok(());
+    }
+
+    let phy_addr = if let Some(phy_addr) = ttbr.to_phy_addr(vm_addr as u64) {
+        phy_addr
+    } else {
+        return Ok(());
+    };
+
+    if *page_ref(phy_addr) == 1 {
+        // the other processes have released the page
+        ttbr.map(vm_addr as u64, phy_addr, mmu::user_page_flag());
+    } else {
+        // the page has been counted for the quota already
+        let copied = alloc_page(pager, false).ok_or(MapError::OutOfMemory)?;
+        copy_page(copied, phy_addr);
+        ttbr.map(vm_addr as u64, copied, mmu::user_page_flag());
+        free_page(pager, phy_addr);
+    }
+
+    mmu::tlb_flush_addr(vm_addr);
+
+    Ok(())
+}
+
+/// Copy a page of the pager.
+/// The pager's memory is mapped to the same addresses for EL1.
+fn copy_page(dst: u64, src: u64) {
+    unsafe { core::ptr::copy_nonoverlapping(src as *const u8, dst as *mut u8, memac::ALIGNMENT) };
 }
 
-pub fn unmap_user_all(id: u8) {
-    let (start, end) = allocator::user_mem(id);
-    unmap(start, end, false);
+/// Get the number of pages mapped in the memory of the process `id`.
+/// The count may be stale, because the pager is not locked.
+pub fn user_pages(id: u32) -> usize {
+    user_space(id).pages as usize
 }
 
+/// Map a page of the faulting address.
+/// Pages of processes are mapped only in the table of the running process.
 pub fn fault(vm_addr: usize) -> FaultResult {
     let vm_addr = vm_addr & memac::MASK;
 
     if allocator::is_kern_mem(vm_addr) {
-        map(vm_addr, vm_addr, true);
-        FaultResult::Ok
+        if map(
+            vm_addr,
+            vm_addr,
+            Space::Kernel,
+            mmu::kernel_page_flag(),
+            true,
+        )
+        .is_ok()
+        {
+            FaultResult::Ok
+        } else {
+            FaultResult::OutOfMemory
+        }
     } else if let Some(id) = get_raw_id() {
-        if allocator::is_user_canary(id, vm_addr) {
-            return FaultResult::StackOverflow;
+        map_user(vm_addr, id)
+    } else {
+        FaultResult::InvalidAccess
+    }
+}
+
+/// Switch TTBR0 of this CPU to the table of the slot `id`, or the kernel's table if None.
+///
+/// If ASIDs are 16 bits, the ASID of a slot is id + 1, because PROCESS_MAX is 2^14.
+/// Otherwise, slots share 255 ASIDs, and the TLB entries of an ASID are invalidated
+/// on this CPU when the ASID is used by another slot.
+/// A slot reused by a new process needs no invalidation,
+/// because the pages of the old process have been unmapped with TLB invalidation.
+pub fn switch_table(id: Option<u32>) {
+    let (id, phy_addr) = match id.map(|id| (id, user_space(id).ttbr0)) {
+        Some((id, phy_addr)) if phy_addr != 0 => (id, phy_addr),
+        _ => {
+            mmu::set_ttbr0(mmu::get_kern_ttbr0_addr(), 0);
+            return;
         }
+    };
 
-        if !allocator::is_user_mem(id, vm_addr) {
-            return FaultResult::InvalidAccess;
+    let owner = id as u16 + 1;
+    if mmu::asid_bits() == 16 {
+        mmu::set_ttbr0(phy_addr, owner);
+    } else {
+        let asid = (id % 255) as u16 + 1;
+        mmu::set_ttbr0(phy_addr, asid);
+
+        let last = unsafe { &mut ASID_OWNER[core_pos()][asid as usize] };
+        if *last != owner {
+            *last = owner;
+            mmu::tlb_flush_asid(asid);
         }
+    }
+}
 
-        map(vm_addr, vm_addr, false);
-        FaultResult::Ok
+/// Get TTBR0 of the slot `id` if it has been allocated.
+fn get_user_table(id: u32) -> Option<mmu::TTable> {
+    let phy_addr = user_space(id).ttbr0;
+    if phy_addr == 0 {
+        None
     } else {
-        FaultResult::InvalidAccess
+        Some(mmu::get_user_ttbr0(phy_addr))
     }
 }
 
-pub fn map_canary() {
-    if let Some(id) = get_raw_id() {
-        let canary = allocator::user_canary(id) as usize;
-        map(canary, canary, false);
+/// Get TTBR0 of the slot `id`, and allocate it if needed.
+/// PAGER must be locked.
+fn alloc_user_table(pager: &mut PageManager, id: u32, reserve: bool) -> Option<mmu::TTable> {
+    if let Some(ttbr) = get_user_table(id) {
+        return Some(ttbr);
     }
+
+    let phy_addr = alloc_page(pager, reserve)?;
+    map_table_page(phy_addr);
+
+    let mut ttbr = mmu::get_user_ttbr0(phy_addr);
+    ttbr.init_user();
+    user_space(id).ttbr0 = phy_addr;
+
+    Some(ttbr)
 }
 
-fn unmap(start: usize, end: usize, is_kern: bool) {
+fn unmap(start: usize, end: usize, space: Space) {
     // disable interrupts
     let _mask = cpuint::mask();
 
     let mut node = MCSNode::new();
     let mut lock = PAGER.lock(&mut node);
 
-    let mut ttbr = if is_kern {
-        mmu::get_ttbr1()
-    } else {
-        mmu::get_ttbr0()
+    let mut ttbr = match space {
+        Space::Kernel => mmu::get_ttbr1(),
+        Space::User(id) => {
+            if let Some(ttbr) = get_user_table(id) {
+                ttbr
+            } else {
+                return; // nothing is mapped
+            }
+        }
     };
 
     if let GlobalVar::Having(pager) = &mut *lock {
         for vm_addr in (start..=end).step_by(memac::ALIGNMENT) {
             if let Some(phy_addr) = ttbr.to_phy_addr(vm_addr as u64) {
-                pager.free(phy_addr as usize);
+                free_page(pager, phy_addr);
                 ttbr.unmap(vm_addr as u64);
+
+                if let Space::User(id) = space {
+                    user_space(id).pages -= 1;
+                }
             }
         }
 
@@ -121,26 +711,120 @@ fn unmap(start: usize, end: usize, is_kern: bool) {
     }
 }
 
-fn map(start: usize, end: usize, is_kern: bool) {
+/// Map a page taken from the pager to phy_addr + EL1_ADDR_OFFSET in TTBR1 to use it as a table.
+/// The page is accessible only from EL1.
+fn map_table_page(phy_addr: u64) {
+    let mut ttbr1 = mmu::get_ttbr1();
+    ttbr1.map(
+        phy_addr + mmu::EL1_ADDR_OFFSET,
+        phy_addr,
+        mmu::table_page_flag(),
+    );
+    mmu::get_ttbr0().unmap(phy_addr);
+    mmu::tlb_flush_addr(phy_addr as usize);
+    mmu::tlb_flush_addr((phy_addr + mmu::EL1_ADDR_OFFSET) as usize);
+}
+
+/// Use a page taken from the pager as the level 3 table of TTBR0 covering vm_addr.
+/// Tables are never freed, and they are reused by processes of the same slots.
+fn set_table(ttbr0: &mut mmu::TTable, vm_addr: u64, phy_addr: u64) {
+    map_table_page(phy_addr);
+    ttbr0.set_table(vm_addr, phy_addr);
+}
+
+/// Take a page from the pager.
+/// If `reserve` is false, the last KERN_RESERVE pages are not taken.
+/// PAGER must be locked.
+fn alloc_page(pager: &mut PageManager, reserve: bool) -> Option<u64> {
+    if !reserve && unsafe { FREE_PAGES <= KERN_RESERVE } {
+        return None;
+    }
+
+    let phy_addr = pager.alloc()? as u64;
+    unsafe { FREE_PAGES -= 1 };
+    *page_ref(phy_addr) = 1;
+    Some(phy_addr)
+}
+
+/// Take a page for the memory of the process `id`, and count it against the page quota.
+/// PAGER must be locked.
+fn alloc_user_page(pager: &mut PageManager, id: u32) -> Result<u64, MapError> {
+    let space = user_space(id);
+    if space.pages >= space.quota {
+        return Err(MapError::QuotaExceeded);
+    }
+
+    let page = alloc_page(pager, false).ok_or(MapError::OutOfMemory)?;
+    space.pages += 1;
+    Ok(page)
+}
+
+/// Release a mapping of a page, and return the page to the pager if it is the last one.
+/// PAGER must be locked.
+fn free_page(pager: &mut PageManager, phy_addr: u64) {
+    let phy_addr = phy_addr & memac::MASK as u64;
+    let refs = page_ref(phy_addr);
+    *refs -= 1;
+    if *refs == 0 {
+        pager.free(phy_addr as usize);
+        unsafe { FREE_PAGES += 1 };
+    }
+}
+
+/// Get the number of mappings of a page of the pager.
+/// PAGER must be locked.
+fn page_ref(phy_addr: u64) -> &'static mut u16 {
+    let i = (phy_addr as usize - unsafe { PAGER_START }) / memac::ALIGNMENT;
+    unsafe { &mut PAGE_REFS[i] }
+}
+
+/// Map pages from start to end (inclusive) with flag.
+/// Pages of the kernel and tables may be taken from the reserve if `reserve` is true.
+/// Pages of processes are counted against the page quota, and never taken from the reserve.
+/// If an error occurs, the pages mapped before it stay mapped.
+fn map(start: usize, end: usize, space: Space, flag: u64, reserve: bool) -> Result<(), MapError> {
     // disable interrupts
     let _mask = cpuint::mask();
 
     let mut node = MCSNode::new();
     let mut lock = PAGER.lock(&mut node);
 
-    let (mut ttbr, flag) = if is_kern {
-        (mmu::get_ttbr1(), mmu::kernel_page_flag())
-    } else {
-        (mmu::get_ttbr0(), mmu::user_page_flag())
-    };
-
     if let GlobalVar::Having(pager) = &mut *lock {
+        let mut ttbr = match space {
+            Space::Kernel => mmu::get_ttbr1(),
+            Space::User(id) => {
+                if let Some(ttbr) = alloc_user_table(pager, id, reserve) {
+                    ttbr
+                } else {
+                    return Err(MapError::OutOfMemory);
+                }
+            }
+        };
+
+        let mut result = Ok(());
+
         for vm_addr in (start..=end).step_by(memac::ALIGNMENT) {
-            if ttbr.to_phy_addr(vm_addr as u64).is_none() {
-                if let Some(phy_addr) = pager.alloc() {
-                    ttbr.map(vm_addr as u64, phy_addr as u64, flag);
+            if !ttbr.has_table(vm_addr as u64) {
+                if let Some(phy_addr) = alloc_page(pager, reserve) {
+                    set_table(&mut ttbr, vm_addr as u64, phy_addr);
                 } else {
-                    panic!("exhausted memory");
+                    result = Err(MapError::OutOfMemory);
+                    break;
+                }
+            }
+
+            if ttbr.to_phy_addr(vm_addr as u64).is_none() {
+                let page = match space {
+                    Space::Kernel => alloc_page(pager, reserve).ok_or(MapError::OutOfMemory),
+                    Space::User(id) => alloc_user_page(pager, id),
+                };
+
+                match page {
+                    Ok(phy_addr) => ttbr.map(vm_addr as u64, phy_addr, flag),
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
                 }
             } else {
                 mmu::tlb_flush_addr(vm_addr);
@@ -153,7 +837,7 @@ fn map(start: usize, end: usize, is_kern: bool) {
             mmu::tlb_flush_all();
         }
 
-        return;
+        return result;
     }
     lock.unlock();
 
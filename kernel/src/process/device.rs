@@ -0,0 +1,112 @@
+//! Device interrupts of user processes.
+//! An interrupt is delivered to the subscriber as a message from `Locator::Device(irq)`,
+//! and the line stays masked until the subscriber acknowledges it by `ack_irq`.
+
+use super::Msg;
+use crate::{
+    bsp::int::{self, DevIRQNumber, IRQ},
+    cpuint,
+    syscall::Locator,
+};
+use alloc::{collections::BTreeMap, vec::Vec};
+use synctools::mcs::{MCSLock, MCSNode};
+
+// device number -> pid
+static SUBS: MCSLock<BTreeMap<u32, u32>> = MCSLock::new(BTreeMap::new());
+
+/// Subscribe pid to the device interrupt `n`.
+/// false is returned if `n` is not a device interrupt, it has been subscribed already,
+/// or it has a handler of the kernel.
+pub(super) fn subscribe(n: u32, pid: u32) -> bool {
+    let irq = if let Some(irq) = int::device_irq(n) {
+        irq
+    } else {
+        return false;
+    };
+
+    {
+        let _mask = cpuint::mask();
+        let mut node = MCSNode::new();
+        let mut subs = SUBS.lock(&mut node);
+
+        if subs.contains_key(&n) {
+            return false;
+        }
+        subs.insert(n, pid);
+    }
+
+    // the handler must be registered without locking SUBS,
+    // because it locks SUBS in the interrupt context
+    if !int::register_handler(irq, IRQ::new_oneshot("user device", handler)) {
+        let _mask = cpuint::mask();
+        let mut node = MCSNode::new();
+        SUBS.lock(&mut node).remove(&n);
+        return false;
+    }
+    int::enable_irq_num(irq);
+
+    true
+}
+
+/// Unmask the device interrupt `n`.
+/// false is returned if pid is not the subscriber of `n`.
+pub(super) fn ack(n: u32, pid: u32) -> bool {
+    let irq = if let Some(irq) = int::device_irq(n) {
+        irq
+    } else {
+        return false;
+    };
+
+    if subscriber(n) != Some(pid) {
+        return false;
+    }
+
+    int::enable_irq_num(irq);
+    true
+}
+
+/// Remove the subscriptions of pid, and mask the interrupts.
+/// This must not be called while locking PROC_INFO.
+pub(super) fn unsubscribe_all(pid: u32) {
+    let irqs: Vec<u32> = {
+        let _mask = cpuint::mask();
+        let mut node = MCSNode::new();
+        let mut subs = SUBS.lock(&mut node);
+
+        let irqs = subs
+            .iter()
+            .filter(|(_, p)| **p == pid)
+            .map(|(n, _)| *n)
+            .collect();
+        subs.retain(|_, p| *p != pid);
+        irqs
+    };
+
+    for n in irqs {
+        if let Some(irq) = int::device_irq(n) {
+            int::disable_irq_num(irq);
+            int::unregister_handler(irq);
+        }
+    }
+}
+
+fn subscriber(n: u32) -> Option<u32> {
+    let _mask = cpuint::mask();
+    let mut node = MCSNode::new();
+    let subs = SUBS.lock(&mut node);
+
+    subs.get(&n).copied()
+}
+
+/// The line has been masked before calling this.
+/// If no process subscribes it, it stays masked.
+fn handler(irq: DevIRQNumber) {
+    let n = int::device_num(irq);
+    if let Some(pid) = subscriber(n) {
+        let msg = Msg {
+            loc: Locator::Device(n),
+            data: Vec::new(),
+        };
+        super::deliver(pid, msg);
+    }
+}
@@ -1,59 +1,128 @@
+mod device;
+mod registry;
 mod ringq;
+mod timeout;
 
 use crate::{
-    aarch64::{context::GpRegs, cpu},
-    allocator::{unset_user_allocator, user_stack},
+    aarch64::{context::GpRegs, cpu, mmu, timer},
+    allocator::{fork_user_allocator, kern_stack, unset_user_allocator, user_args},
+    bsp,
     cpuint::{self, InterMask},
     driver::topology::{core_pos, CORE_COUNT},
     paging,
-    syscall::Locator,
+    syscall::{
+        Arg, ExitStatus, Locator, ProcStat, RunState, SchedStats, ARGS_MAX_LEN, MAILBOX_DEFAULT,
+        MAILBOX_MAX, MSG_MAX_LEN, NAME_MAX_LEN, PAGE_QUOTA_DEFAULT, PRIORITY_DEFAULT, PRIORITY_NUM,
+    },
 };
-use arr_macro::arr;
+use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
 use core::{
     arch::asm,
+    cmp::Reverse,
+    hint::spin_loop,
+    mem::{size_of, ManuallyDrop},
     ptr::{null, null_mut},
+    sync::atomic::{fence, AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering},
 };
 use synctools::mcs::{MCSLock, MCSLockGuard, MCSNode};
 
-pub const PROCESS_MAX: usize = 256;
-
-static mut ACTIVES: [Option<u8>; CORE_COUNT] = [None; CORE_COUNT];
-static mut RECEIVER: [*const ringq::Chan<Msg>; PROCESS_MAX] = [null(); PROCESS_MAX];
-static mut FREED: [Option<u8>; CORE_COUNT] = [None; CORE_COUNT];
+/// A process ID consists of a slot of the process table and a generation of the slot.
+/// | bits    | mean       |
+/// |---------|------------|
+/// |  0 - 13 | slot (ID)  |
+/// | 14 - 31 | generation |
+const SLOT_BITS: u32 = 14;
+const SLOT_MASK: u32 = (1 << SLOT_BITS) - 1;
+const GEN_MAX: u32 = u32::MAX >> SLOT_BITS;
+
+pub const PROCESS_MAX: usize = 1 << SLOT_BITS;
+
+/// PID of the init process, which takes slot 0 in the first generation.
+const INIT_PID: u32 = 1 << SLOT_BITS;
+
+/// The process table is extended by TABLE_CHUNK slots when it is full.
+pub const TABLE_CHUNK: usize = 64;
+
+/// A Ready process is moved up a level every AGING_TICKS ticks,
+/// so that processes of low priorities are not starved.
+const AGING_TICKS: u64 = 10;
+
+/// Each CPU balances the ready queues every BALANCE_TICKS ticks.
+const BALANCE_TICKS: u64 = 4;
+
+static mut ACTIVES: [Option<u32>; CORE_COUNT] = [None; CORE_COUNT];
+static mut FREED: [Option<u32>; CORE_COUNT] = [None; CORE_COUNT];
+static mut NEED_RESCHED: [bool; CORE_COUNT] = [false; CORE_COUNT];
+static mut IDLE: [bool; CORE_COUNT] = [false; CORE_COUNT];
+static mut AGING_COUNT: u64 = 0;
+static mut BALANCE_COUNT: [u64; CORE_COUNT] = [0; CORE_COUNT];
+static SCHED_STATS: [SchedCounters; CORE_COUNT] = {
+    const EMPTY: SchedCounters = SchedCounters::new();
+    [EMPTY; CORE_COUNT]
+};
+static RUNQS: RunQs = RunQs::new();
 
 static PROC_INFO: MCSLock<ProcInfo> = MCSLock::new(ProcInfo::new());
 
+/// A message, which is allocated in the kernel heap.
+/// The payload is copied from and to the user heaps by system calls.
 #[derive(Clone)]
 struct Msg {
-    loc: Locator,
-    val: u32,
+    loc: Locator, // sender
+    data: Vec<u8>,
 }
 
 unsafe impl Send for Msg {}
 
+/// Processes are boxed, so that their contexts are not moved when the table is extended.
+type ProcTable = [Option<Box<Process>>];
+
 struct ProcInfo {
-    table: [Option<Process>; PROCESS_MAX],
-    cnt: [u16; PROCESS_MAX],
-    readyq: ProcessQ,
+    table: Vec<Option<Box<Process>>>, // never shrinks, so that raw IDs stay valid
+    cnt: Vec<u32>,                    // generations of the slots
+    free: Vec<u32>,                   // empty slots, the lowest one is at the end
 }
 
 impl ProcInfo {
     const fn new() -> ProcInfo {
         ProcInfo {
-            table: arr![None; 256], // PROCESS_MAX == 256
-            cnt: arr![0; 256],
-            readyq: ProcessQ::new(),
+            table: Vec::new(),
+            cnt: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+
+    /// The ready queues are not a part of PROC_INFO, and they are also used without it.
+    fn split(&mut self) -> (&mut ProcTable, &mut [u32], &'static RunQs) {
+        (&mut self.table, &mut self.cnt, &RUNQS)
+    }
+
+    /// Find an empty slot.
+    /// The table is extended if it is full, up to PROCESS_MAX slots.
+    /// The slot stays in the free list until `init_process` fills it,
+    /// so that it is not lost if creating a process fails.
+    fn find_slot(&mut self) -> Option<u32> {
+        if let Some(&id) = self.free.last() {
+            return Some(id);
         }
+
+        let len = self.table.len();
+        if len >= PROCESS_MAX {
+            return None;
+        }
+
+        let new_len = (len + TABLE_CHUNK).min(PROCESS_MAX);
+        paging::extend_slots(new_len);
+        self.table.resize_with(new_len, || None);
+        self.cnt.resize(new_len, 0);
+        self.free.extend((len as u32..new_len as u32).rev());
+        Some(len as u32)
     }
 
-    fn split(
-        &mut self,
-    ) -> (
-        &mut [Option<Process>; PROCESS_MAX],
-        &mut [u16; PROCESS_MAX],
-        &mut ProcessQ,
-    ) {
-        (&mut self.table, &mut self.cnt, &mut self.readyq)
+    /// Empty the slot `id`, and make it available for new processes.
+    fn release(&mut self, id: u32) {
+        self.table[id as usize] = None;
+        self.free.push(id);
     }
 
     unsafe fn get_ctx(&self, i: usize) -> *mut GpRegs {
@@ -65,94 +134,423 @@ impl ProcInfo {
     }
 }
 
-fn get_actives() -> &'static mut [Option<u8>; CORE_COUNT] {
+/// Get the entry of the slot `id`, which may come from an invalid PID.
+fn entry_mut(tbl: &mut ProcTable, id: u32) -> Option<&mut Process> {
+    tbl.get_mut(id as usize)?.as_deref_mut()
+}
+
+fn get_actives() -> &'static mut [Option<u32>; CORE_COUNT] {
     unsafe { &mut ACTIVES }
 }
 
-fn get_freed() -> &'static mut [Option<u8>; CORE_COUNT] {
+fn get_freed() -> &'static mut [Option<u32>; CORE_COUNT] {
     unsafe { &mut FREED }
 }
 
-struct ProcessQ(Option<(u8, u8)>); // (head, tail)
+fn get_need_resched() -> &'static mut [bool; CORE_COUNT] {
+    unsafe { &mut NEED_RESCHED }
+}
+
+fn get_idle() -> &'static mut [bool; CORE_COUNT] {
+    unsafe { &mut IDLE }
+}
+
+fn get_sched_stats() -> &'static [SchedCounters; CORE_COUNT] {
+    &SCHED_STATS
+}
+
+/// Scheduler counters of a CPU.
+/// They are atomic, because `steals` and `migrations` are counted by the CPU taking processes
+/// without the lock of the CPU they are taken from.
+struct SchedCounters {
+    switches: AtomicU64,
+    steals: AtomicU64,
+    migrations: AtomicU64,
+    lock_wait: AtomicU64, // in counts of the physical counter
+    lock_count: AtomicU64,
+}
 
-impl ProcessQ {
-    const fn new() -> ProcessQ {
-        ProcessQ(None)
+impl SchedCounters {
+    const fn new() -> SchedCounters {
+        SchedCounters {
+            switches: AtomicU64::new(0),
+            steals: AtomicU64::new(0),
+            migrations: AtomicU64::new(0),
+            lock_wait: AtomicU64::new(0),
+            lock_count: AtomicU64::new(0),
+        }
     }
+}
 
-    fn is_empty(&self) -> bool {
-        self.0.is_none()
+/// Lock PROC_INFO.
+/// The time waiting for the lock is accounted to this CPU.
+fn lock_proc_info(node: &mut MCSNode<ProcInfo>) -> MCSLockGuard<'_, ProcInfo> {
+    let start = timer::get_count();
+    let proc_info = PROC_INFO.lock(node);
+
+    let stats = &get_sched_stats()[core_pos()];
+    stats
+        .lock_wait
+        .fetch_add(timer::get_count() - start, Ordering::Relaxed);
+    stats.lock_count.fetch_add(1, Ordering::Relaxed);
+
+    proc_info
+}
+
+/// Get the scheduler counters of `cpu`.
+/// The time waiting for the lock is converted to microseconds.
+pub fn sched_stats(cpu: usize) -> Option<SchedStats> {
+    let stats = get_sched_stats().get(cpu)?;
+    Some(SchedStats {
+        switches: stats.switches.load(Ordering::Relaxed),
+        steals: stats.steals.load(Ordering::Relaxed),
+        migrations: stats.migrations.load(Ordering::Relaxed),
+        lock_wait: timer::count_to_usec(stats.lock_wait.load(Ordering::Relaxed)),
+        lock_count: stats.lock_count.load(Ordering::Relaxed),
+    })
+}
+
+/// Multi-level ready queue of a CPU.
+/// Level 0 is the highest priority, and processes of the same level are served in FIFO order.
+/// It holds only IDs, so that it can be locked apart from the process table.
+struct ReadyQ {
+    levels: [VecDeque<u32>; PRIORITY_NUM],
+    len: usize,
+}
+
+impl ReadyQ {
+    const fn new() -> ReadyQ {
+        const EMPTY: VecDeque<u32> = VecDeque::new();
+        ReadyQ {
+            levels: [EMPTY; PRIORITY_NUM],
+            len: 0,
+        }
     }
 
-    fn enque(&mut self, id: u8, tbl: &mut [Option<Process>; PROCESS_MAX]) {
-        match self.0 {
-            Some((h, t)) => {
-                if let Some(entry) = tbl[t as usize].as_mut() {
-                    entry.next = Some(id);
-                    self.0 = Some((h, id));
-                }
-            }
-            None => {
-                self.0 = Some((id, id));
+    /// Get the highest level which has Ready processes.
+    fn top(&self) -> Option<u8> {
+        self.levels
+            .iter()
+            .position(|q| !q.is_empty())
+            .map(|lv| lv as u8)
+    }
+
+    fn enque(&mut self, id: u32, level: u8) {
+        self.levels[level as usize].push_back(id);
+        self.len += 1;
+    }
+
+    /// Remove a process from the queue.
+    /// false is returned if it is not in the queue.
+    fn remove(&mut self, id: u32, level: u8) -> bool {
+        let q = &mut self.levels[level as usize];
+        if let Some(pos) = q.iter().position(|&e| e == id) {
+            q.remove(pos);
+            self.len -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Dequeue a process of the highest priority.
+    /// Processes below `lowest` are not dequeued.
+    fn deque(&mut self, lowest: u8) -> Option<u32> {
+        let lv = self.top()?;
+        if lv > lowest {
+            return None;
+        }
+
+        let id = self.levels[lv as usize].pop_front()?;
+        self.len -= 1;
+        Some(id)
+    }
+
+    /// Dequeue a process of the lowest priority to move it to another CPU.
+    fn deque_bottom(&mut self) -> Option<u32> {
+        let lv = self.levels.iter().rposition(|q| !q.is_empty())?;
+        let id = self.levels[lv].pop_front()?;
+        self.len -= 1;
+        Some(id)
+    }
+
+    /// Move the first process of each level up a level.
+    /// `moved` is called with each moved process and its new level.
+    fn age(&mut self, mut moved: impl FnMut(u32, u8)) {
+        for lv in 1..PRIORITY_NUM {
+            if let Some(id) = self.levels[lv].pop_front() {
+                self.levels[lv - 1].push_back(id);
+                moved(id, lv as u8 - 1);
             }
         }
     }
+}
 
-    fn remove(&mut self, id: u8, tbl: &mut [Option<Process>; PROCESS_MAX]) {
-        if let Some((h, t)) = self.0 {
-            if h == id {
-                if let Some(entry) = tbl[h as usize].as_mut() {
-                    if h == t {
-                        self.0 = None
-                    } else {
-                        self.0 = Some((entry.next.unwrap(), t));
-                    }
-                    entry.next = None;
-                    return;
-                }
+/// The ready queue of a CPU and its summary,
+/// which other CPUs read without the lock to choose a queue to take processes from.
+struct RunQ {
+    queue: MCSLock<ReadyQ>,
+    len: AtomicUsize,
+    top: AtomicU8, // PRIORITY_NUM if empty
+}
+
+impl RunQ {
+    const fn new() -> RunQ {
+        RunQ {
+            queue: MCSLock::new(ReadyQ::new()),
+            len: AtomicUsize::new(0),
+            top: AtomicU8::new(PRIORITY_NUM as u8),
+        }
+    }
+
+    /// Lock the queue to apply `f`, and update the summary.
+    fn with<R>(&self, f: impl FnOnce(&mut ReadyQ) -> R) -> R {
+        let mut node = MCSNode::new();
+        let mut queue = self.queue.lock(&mut node);
+        let ret = f(&mut queue);
+
+        self.len.store(queue.len, Ordering::Relaxed);
+        self.top
+            .store(queue.top().unwrap_or(PRIORITY_NUM as u8), Ordering::Relaxed);
+        ret
+    }
+
+    fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    fn top(&self) -> Option<u8> {
+        let top = self.top.load(Ordering::Relaxed);
+        if top < PRIORITY_NUM as u8 {
+            Some(top)
+        } else {
+            None
+        }
+    }
+}
+
+/// Fields of a slot which the ready queues use.
+/// They are not a part of PROC_INFO, so that the queues are used without it.
+/// They are changed while locking the queue of `cpu`, except `cpu` of a new process.
+struct SchedSlot {
+    cpu: AtomicU8,      // CPU whose ready queue the process belongs to, or MOVING
+    level: AtomicU8,    // level of the ready queue, which may be raised by aging
+    queued: AtomicBool, // in a ready queue, or being moved between queues
+}
+
+/// `SchedSlot::cpu` of a process which has been taken from a queue to be enqueued to another one.
+const MOVING: u8 = u8::MAX;
+
+impl SchedSlot {
+    const fn new() -> SchedSlot {
+        SchedSlot {
+            cpu: AtomicU8::new(0),
+            level: AtomicU8::new(PRIORITY_DEFAULT),
+            queued: AtomicBool::new(false),
+        }
+    }
+}
+
+/// Per-CPU ready queues.
+/// A process is enqueued to the queue of the CPU which it ran on last,
+/// and CPUs take processes from the queues of the others
+/// if they have processes of higher priorities or nothing to run.
+///
+/// Each queue has its own lock, and the queues are used with or without PROC_INFO,
+/// because the fields of processes for the queues are kept in `slots` instead of the table.
+/// PROC_INFO is never locked while locking a queue,
+/// and the lock of a queue is never held while locking another queue.
+struct RunQs {
+    cpus: [RunQ; CORE_COUNT],
+    slots: [SchedSlot; PROCESS_MAX],
+}
+
+impl RunQs {
+    const fn new() -> RunQs {
+        const EMPTY: RunQ = RunQ::new();
+        const SLOT: SchedSlot = SchedSlot::new();
+        RunQs {
+            cpus: [EMPTY; CORE_COUNT],
+            slots: [SLOT; PROCESS_MAX],
+        }
+    }
+
+    /// Whether all the queues are empty.
+    /// No lock is taken, so the caller must check it again after being woken up.
+    fn is_empty(&self) -> bool {
+        self.cpus.iter().all(|q| q.len() == 0)
+    }
+
+    /// Get the CPU whose queue the process `id` belongs to, or ran on last.
+    /// MOVING is returned while `balance` is moving it.
+    fn cpu(&self, id: u32) -> u8 {
+        self.slots[id as usize].cpu.load(Ordering::Relaxed)
+    }
+
+    /// Let a new process `id` start on `cpu`.
+    /// It must not be in the queues yet.
+    fn set_cpu(&self, id: u32, cpu: usize) {
+        self.slots[id as usize]
+            .cpu
+            .store(cpu as u8, Ordering::Relaxed);
+    }
+
+    /// Enqueue a process to `level`, which is its effective priority.
+    fn enque(&self, id: u32, level: u8) {
+        let slot = &self.slots[id as usize];
+        let cpu = slot.cpu.load(Ordering::Relaxed);
+        self.cpus[cpu as usize].with(|q| {
+            q.enque(id, level);
+            slot.level.store(level, Ordering::Relaxed);
+            slot.queued.store(true, Ordering::Relaxed);
+        });
+    }
+
+    /// Remove a process from the queue it belongs to.
+    /// false is returned if it is not in the queues.
+    fn remove(&self, id: u32) -> bool {
+        let slot = &self.slots[id as usize];
+        loop {
+            let cpu = slot.cpu.load(Ordering::Relaxed);
+            if cpu == MOVING {
+                // `balance` is enqueueing it to another queue
+                spin_loop();
+                continue;
             }
 
-            let id_next = tbl[id as usize].as_ref().unwrap().next;
-            let mut n = h;
-            loop {
-                if let Some(entry) = tbl[n as usize].as_mut() {
-                    if let Some(next) = entry.next {
-                        if next == id {
-                            entry.next = id_next;
-                            if t == id {
-                                self.0 = Some((h, entry.id));
-                            }
-                            break;
-                        } else {
-                            n = next;
-                        }
-                    } else {
-                        return; // not found
-                    }
+            let removed = self.cpus[cpu as usize].with(|q| {
+                if slot.cpu.load(Ordering::Relaxed) != cpu {
+                    return None;
+                }
+
+                if slot.queued.load(Ordering::Relaxed) {
+                    slot.queued.store(false, Ordering::Relaxed);
+                    Some(q.remove(id, slot.level.load(Ordering::Relaxed)))
+                } else {
+                    Some(false)
                 }
+            });
+
+            if let Some(removed) = removed {
+                return removed;
             }
         }
     }
 
-    fn deque(&mut self, tbl: &mut [Option<Process>; PROCESS_MAX]) -> Option<u8> {
-        match self.0 {
-            Some((h, t)) => {
-                let hidx = h as usize;
-                if let Some(entry) = tbl[hidx].as_mut() {
-                    if let Some(next) = entry.next {
-                        self.0 = Some((next, t));
-                    } else {
-                        assert_eq!(h, t);
-                        self.0 = None;
-                    }
+    /// Enqueue a Ready process again, because its priority has been changed to `level`.
+    fn requeue(&self, id: u32, level: u8) {
+        if self.slots[id as usize].level.load(Ordering::Relaxed) != level && self.remove(id) {
+            self.enque(id, level);
+        }
+    }
 
-                    entry.next = None;
-                    Some(entry.id)
-                } else {
-                    None
-                }
+    /// Dequeue a process of the highest priority for this CPU.
+    /// Processes below `lowest` are not dequeued.
+    /// A process in the queue of another CPU is stolen
+    /// if its priority is higher than that of any local process.
+    /// Only the local queue is locked unless a process is stolen.
+    fn deque(&self, lowest: u8) -> Option<u32> {
+        let aff = core_pos();
+        let local = self.cpus[aff].top();
+
+        // the highest priority of the others, and the longest queue of that priority
+        let remote = (0..CORE_COUNT)
+            .filter(|&i| i != aff)
+            .filter_map(|i| {
+                self.cpus[i]
+                    .top()
+                    .map(|lv| (lv, Reverse(self.cpus[i].len()), i))
+            })
+            .min();
+
+        match (local, remote) {
+            // the process may have been taken by another CPU since the summary was read
+            (Some(l), Some((r, _, victim))) if r < l => self
+                .steal(victim, lowest)
+                .or_else(|| self.deque_local(lowest)),
+            (Some(_), _) => self.deque_local(lowest),
+            (None, Some((_, _, victim))) => self.steal(victim, lowest),
+            (None, None) => None,
+        }
+    }
+
+    fn deque_local(&self, lowest: u8) -> Option<u32> {
+        let aff = core_pos();
+        self.cpus[aff].with(|q| {
+            let id = q.deque(lowest)?;
+            self.slots[id as usize]
+                .queued
+                .store(false, Ordering::Relaxed);
+            Some(id)
+        })
+    }
+
+    /// Take a process from the queue of `victim` for this CPU.
+    fn steal(&self, victim: usize, lowest: u8) -> Option<u32> {
+        let aff = core_pos();
+        let id = self.cpus[victim].with(|q| {
+            let id = q.deque(lowest)?;
+            let slot = &self.slots[id as usize];
+            slot.queued.store(false, Ordering::Relaxed);
+            slot.cpu.store(aff as u8, Ordering::Relaxed);
+            Some(id)
+        })?;
+
+        get_sched_stats()[aff]
+            .steals
+            .fetch_add(1, Ordering::Relaxed);
+        Some(id)
+    }
+
+    /// Move a process from the longest queue to the queue of this CPU,
+    /// if the difference of their lengths is 2 or more.
+    fn balance(&self) {
+        let aff = core_pos();
+
+        let mut busiest = aff;
+        for (i, q) in self.cpus.iter().enumerate() {
+            if q.len() > self.cpus[busiest].len() {
+                busiest = i;
             }
-            None => None,
+        }
+
+        if self.cpus[busiest].len() < self.cpus[aff].len() + 2 {
+            return;
+        }
+
+        // `remove` waits for the process while it is in neither queue
+        let moved = self.cpus[busiest].with(|q| {
+            let id = q.deque_bottom()?;
+            let slot = &self.slots[id as usize];
+            slot.cpu.store(MOVING, Ordering::Relaxed);
+            Some((id, slot.level.load(Ordering::Relaxed)))
+        });
+
+        if let Some((id, level)) = moved {
+            self.cpus[aff].with(|q| {
+                q.enque(id, level);
+                self.slots[id as usize]
+                    .cpu
+                    .store(aff as u8, Ordering::Relaxed);
+            });
+
+            get_sched_stats()[aff]
+                .migrations
+                .fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Move the first process of each level up a level.
+    /// Its level is restored to its priority when it is enqueued again.
+    fn age(&self) {
+        for q in self.cpus.iter() {
+            q.with(|q| {
+                q.age(|id, level| {
+                    self.slots[id as usize]
+                        .level
+                        .store(level, Ordering::Relaxed)
+                })
+            });
         }
     }
 }
@@ -162,6 +560,10 @@ pub enum State {
     Ready,
     Active,
     Recv,
+    Send, // waiting for room in the mailbox of the destination
+    Blocked,
+    Wait,  // waiting for a child to terminate
+    Sleep, // waiting for a timer
     Killed,
     Zombie,
 }
@@ -169,30 +571,74 @@ pub enum State {
 struct Process {
     regs: GpRegs,
     state: State,
-    next: Option<u8>,
-    stack: *mut u8,
+    stack: *mut u8, // null after the memory has been released
     tx: *const ringq::Chan<Msg>,
-    id: u8,
+    rx: *const ringq::Chan<Msg>,
+    parent: Option<u32>,        // PID of the parent, None if orphaned
+    status: Option<ExitStatus>, // how the process terminated
+    monitors: Vec<u32>,         // processes monitoring this process
+    links: Vec<u32>,            // processes linked with this process
+    is_trap_exit: bool,         // receive DOWN messages instead of being killed by links
+    priority: u8,               // 0 is the highest
+    boost: Option<u8>,          // priority inherited from blocked senders
+    blocked: Vec<u32>,          // IDs of the senders blocked by this process
+    send_to: Option<u32>,       // ID of the receiver blocking this process
+    cpu_time: u64,              // time running on CPUs, in counts of the physical counter
+    started: u64,               // when cpu_time was last accounted
+    id: u32,
 }
 
 impl Process {
-    pub fn new(id: u8) -> Process {
+    pub fn new(id: u32) -> Process {
         Process {
             regs: GpRegs::new(),
             state: State::Ready,
-            next: None,
             stack: null_mut(),
             tx: null(),
+            rx: null(),
+            parent: None,
+            status: None,
+            monitors: Vec::new(),
+            links: Vec::new(),
+            is_trap_exit: false,
+            priority: PRIORITY_DEFAULT,
+            boost: None,
+            blocked: Vec::new(),
+            send_to: None,
+            cpu_time: 0,
+            started: 0,
             id,
         }
     }
 
-    fn get_pid(&self, cnt: u16) -> u32 {
-        (cnt as u32) << 8 | self.id as u32
+    /// Account the time since `started` to `cpu_time`.
+    fn charge(&mut self, now: u64) {
+        self.cpu_time += now - self.started;
+        self.started = now;
+    }
+
+    /// Get the effective priority.
+    fn prio(&self) -> u8 {
+        match self.boost {
+            Some(boost) => boost.min(self.priority),
+            None => self.priority,
+        }
+    }
+
+    fn get_pid(&self, cnt: u32) -> u32 {
+        cnt << SLOT_BITS | self.id
     }
 
-    fn pid_to_id_cnt(pid: u32) -> (u8, u16) {
-        ((pid & 0xff) as u8, (pid >> 8) as u16)
+    fn pid_to_id_cnt(pid: u32) -> (u32, u32) {
+        (pid & SLOT_MASK, pid >> SLOT_BITS)
+    }
+
+    /// Make the current process wait in `state`.
+    /// A process killed by another CPU stays Killed, so that `schedule2` tears it down.
+    fn set_wait_state(&mut self, state: State) {
+        if self.state != State::Killed {
+            self.state = state;
+        }
     }
 
     fn get_tx(&mut self) -> ringq::Sender<Msg> {
@@ -204,267 +650,1282 @@ impl Process {
     }
 }
 
-extern "C" {
-    fn userland_entry();
-}
+extern "C" {
+    fn userland_entry();
+}
+
+/// Initialize the first process.
+/// It is often called the init process.
+pub fn init() {
+    // disable FIQ, IRQ, Abort, Debug
+    let mask = cpuint::mask();
+
+    // let tbl = get_process_table();
+    let mut node = MCSNode::new();
+    let mut proc_info = lock_proc_info(&mut node);
+
+    let id = proc_info.find_slot().unwrap();
+    assert_eq!(id, INIT_PID & SLOT_MASK);
+
+    let ch = ringq::Chan::<Msg>::new(id, MAILBOX_DEFAULT);
+    let (tx, rx) = ch.channel();
+
+    // allocate stack, and place no arguments on it
+    paging::set_quota(id, PAGE_QUOTA_DEFAULT);
+    assert!(paging::map_kern_stack(id), "exhausted memory");
+    let stack = push_args(id, &Arg::encode(&[])).expect("exhausted memory");
+
+    // initialize the init process
+    init_process(
+        id,
+        &mut proc_info,
+        stack,
+        tx.into_raw(),
+        rx.into_raw(),
+        PRIORITY_DEFAULT,
+    );
+
+    // enque the process to Ready queue
+    let (_, _, readyq) = proc_info.split();
+    readyq.set_cpu(id, core_pos());
+    readyq.enque(id, PRIORITY_DEFAULT);
+
+    schedule2(mask, proc_info);
+}
+
+/// A new process starts here on its kernel stack, and enters EL0 with the stack `sp`.
+#[no_mangle]
+fn goto_userland(app: usize, next: usize, cnt: usize, sp: usize) -> ! {
+    release_freed();
+    set_tpid_reg(next as u32, cnt as u32);
+    unsafe {
+        asm!(
+            "msr spsel, #1
+             msr sp_el0, {}
+             eret",
+            in(reg) sp,
+            in("x0") app,
+            options(noreturn)
+        )
+    }
+}
+
+/// The child of `fork` starts here on its kernel stack,
+/// and returns to EL0 through the copy of the exception frame of SYS_FORK at `frame`.
+#[no_mangle]
+fn fork_return(frame: usize, next: usize, cnt: usize) -> ! {
+    release_freed();
+    set_tpid_reg(next as u32, cnt as u32);
+    unsafe {
+        asm!(
+            "mov sp, {}
+             b   fork_return_el0",
+            in(reg) frame,
+            options(noreturn)
+        )
+    }
+}
+
+/// Copy serialized arguments to the top of the stack of the process `id`,
+/// and return the stack pointer below them.
+/// None is returned if pages for them cannot be taken.
+/// See `allocator::user_args` for the layout.
+fn push_args(id: u32, args: &[u8]) -> Option<*mut u8> {
+    let len_addr = user_args(id) as usize;
+    let args_addr = len_addr - args.len();
+
+    // the stack is in the address space of the new process, not of the caller
+    let len = (args.len() as u64).to_le_bytes();
+    if !paging::write_user(id, len_addr, &len) || !paging::write_user(id, args_addr, args) {
+        // release the pages mapped so far
+        paging::unmap_user_all(id);
+        return None;
+    }
+
+    // the stack pointer must be aligned to 16 bytes
+    Some((args_addr & !0xf) as *mut u8)
+}
+
+fn init_process(
+    id: u32,
+    proc_info: &mut MCSLockGuard<ProcInfo>,
+    stack: *mut u8,
+    tx: *const ringq::Chan<Msg>,
+    rx: *const ringq::Chan<Msg>,
+    priority: u8,
+) {
+    // initialize
+    let mut proc = Process::new(id);
+    proc.state = State::Ready;
+    proc.regs.spsr = 0; // EL0t
+    proc.regs.elr = userland_entry as u64;
+    proc.regs.sp = kern_stack(id).1 as u64;
+    proc.regs.x3 = stack as u64;
+    proc.regs.x30 = goto_userland as u64;
+    proc.stack = stack;
+    proc.tx = tx;
+    proc.rx = rx;
+    proc.priority = priority;
+
+    let slot = proc_info.free.pop();
+    assert_eq!(slot, Some(id));
+
+    let id = id as usize;
+
+    // the generation wraps around, and 0 is skipped
+    proc_info.cnt[id] = proc_info.cnt[id] % GEN_MAX + 1;
+    proc_info.table[id] = Some(Box::new(proc));
+}
+
+/// Spawn a new process.
+/// Its mailbox can queue `capacity` messages, which must be in 1..=MAILBOX_MAX.
+/// `priority` must be less than PRIORITY_NUM, and 0 is the highest.
+/// `args` are serialized by `Arg::encode`, and copied to the stack of the new process.
+/// If successful this function is unreachable, otherwise (fail) this returns normally.
+pub fn spawn(app: u64, capacity: usize, priority: u8, args: &[u8]) -> Option<u32> {
+    if capacity == 0
+        || capacity > MAILBOX_MAX
+        || priority as usize >= PRIORITY_NUM
+        || args.len() > ARGS_MAX_LEN
+    {
+        return None;
+    }
+
+    // disable FIQ, IRQ, Abort, Debug
+    let mask = cpuint::mask();
+
+    // create channel
+    let mut ch = ringq::Chan::<Msg>::new(0, capacity);
+
+    // aqcuire lock
+    let mut node = MCSNode::new();
+    let mut proc_info = lock_proc_info(&mut node);
+
+    // find empty slot
+    let id = proc_info.find_slot()?;
+    ch.set_pid(id);
+    let (tx, rx) = ch.channel();
+
+    paging::set_quota(id, PAGE_QUOTA_DEFAULT);
+    if !paging::map_kern_stack(id) {
+        return None;
+    }
+    let stack = push_args(id, args)?;
+
+    // initialize process
+    init_process(
+        id,
+        &mut proc_info,
+        stack,
+        tx.into_raw(),
+        rx.into_raw(),
+        priority,
+    );
+
+    // the current process is the parent
+    let parent = get_actives()[core_pos()].and_then(|current| {
+        proc_info.table[current as usize]
+            .as_ref()
+            .map(|entry| entry.get_pid(proc_info.cnt[current as usize]))
+    });
+
+    let (tbl, cnt, readyq) = proc_info.split();
+    // the child starts on this CPU, and is moved by stealing or load balancing
+    readyq.set_cpu(id, core_pos());
+    readyq.enque(id, priority);
+    kick(priority, tbl);
+    let pid = {
+        if let Some(entry) = tbl[id as usize].as_mut() {
+            let cnt2 = cnt[id as usize];
+            entry.regs.x0 = app;
+            entry.regs.x1 = id as u64;
+            entry.regs.x2 = cnt2 as u64;
+            entry.parent = parent;
+            entry.get_pid(cnt2)
+        } else {
+            return None;
+        }
+    };
+
+    schedule2(mask, proc_info);
+
+    Some(pid)
+}
+
+/// Fork the current process, whose exception frame of SYS_FORK is at `frame`.
+/// The child gets a copy of the address space, where writable pages are shared copy-on-write,
+/// and it returns from SYS_FORK with 0.
+/// The child inherits the priority and the page quota,
+/// and its mailbox can queue MAILBOX_DEFAULT messages.
+/// The PID of the child is returned to the parent, or None if it cannot be created.
+pub fn fork(frame: u64) -> Option<u32> {
+    // disable FIQ, IRQ, Abort, Debug
+    let mask = cpuint::mask();
+
+    // create channel
+    let mut ch = ringq::Chan::<Msg>::new(0, MAILBOX_DEFAULT);
+
+    // aqcuire lock
+    let mut node = MCSNode::new();
+    let mut proc_info = lock_proc_info(&mut node);
+
+    let current = get_actives()[core_pos()]?;
+    let (parent, priority) = {
+        let entry = proc_info.table[current as usize].as_ref()?;
+        (
+            entry.get_pid(proc_info.cnt[current as usize]),
+            entry.priority,
+        )
+    };
+
+    // find empty slot
+    let id = proc_info.find_slot()?;
+    ch.set_pid(id);
+    let (tx, rx) = ch.channel();
+
+    if !paging::map_kern_stack(id) {
+        return None;
+    }
+
+    if !paging::fork(current, id) {
+        paging::unmap_user_all(id);
+        return None;
+    }
+    fork_user_allocator(current, id);
+
+    // the child returns through the copy of the frame placed on its kernel stack,
+    // and x0 of the frame is the return value of the child
+    let child_frame = kern_stack(id).1 - size_of::<GpRegs>();
+    unsafe {
+        let regs = child_frame as *mut GpRegs;
+        *regs = *(frame as *const GpRegs);
+        (*regs).x0 = 0;
+    }
+
+    // initialize process
+    init_process(
+        id,
+        &mut proc_info,
+        child_frame as *mut u8,
+        tx.into_raw(),
+        rx.into_raw(),
+        priority,
+    );
+
+    let (tbl, cnt, readyq) = proc_info.split();
+    let pid = {
+        if let Some(entry) = tbl[id as usize].as_mut() {
+            let cnt2 = cnt[id as usize];
+            entry.regs.x0 = child_frame as u64;
+            entry.regs.x1 = id as u64;
+            entry.regs.x2 = cnt2 as u64;
+            entry.regs.x30 = fork_return as u64;
+            entry.parent = Some(parent);
+            entry.get_pid(cnt2)
+        } else {
+            return None;
+        }
+    };
+    readyq.set_cpu(id, core_pos());
+    readyq.enque(id, priority);
+    kick(priority, tbl);
+
+    schedule2(mask, proc_info);
+
+    Some(pid)
+}
+
+/// exit process
+/// this function is always unreachable
+///
+/// The entry stays Zombie until the parent calls `wait`.
+pub fn exit(status: ExitStatus) -> ! {
+    // disable FIQ, IRQ, Abort, Debug
+    let mask = cpuint::mask();
+
+    let aff = core_pos();
+    let actives = get_actives();
+    if let Some(current) = actives[aff] {
+        let rx = {
+            let mut node = MCSNode::new();
+            let mut proc_info = lock_proc_info(&mut node);
+            take_receiver(current, &mut proc_info)
+        };
+        close_mailbox(rx);
+
+        let watchers = {
+            let mut node = MCSNode::new();
+            let mut proc_info = lock_proc_info(&mut node);
+            let watchers = make_zombie(current, status, &mut proc_info);
+
+            // the parent reaps this process after the memory is released by `release_freed`,
+            // which is called by the next process on this CPU
+            wake_parent(current, &mut proc_info);
+            watchers
+        };
+
+        if let Some(watchers) = watchers {
+            device::unsubscribe_all(watchers.pid);
+            notify_down(watchers);
+        }
+
+        unset_user_allocator(current);
+
+        let freed = get_freed();
+        freed[aff] = Some(current);
+    }
+
+    actives[aff] = None;
+
+    // aqcuire lock
+    let mut node = MCSNode::new();
+    let proc_info = lock_proc_info(&mut node);
+
+    schedule2(mask, proc_info);
+    unreachable!()
+}
+
+/// Processes to be notified of the termination of a process.
+struct Watchers {
+    pid: u32,
+    status: ExitStatus,
+    monitors: Vec<u32>,
+    links: Vec<u32>,
+}
+
+/// Make the process `id` Zombie, and remove its name.
+fn make_zombie(
+    id: u32,
+    status: ExitStatus,
+    proc_info: &mut MCSLockGuard<ProcInfo>,
+) -> Option<Watchers> {
+    let (tbl, cnt, _) = proc_info.split();
+    let entry = tbl[id as usize].as_mut()?;
+
+    unsafe {
+        if !entry.tx.is_null() {
+            ringq::Sender::from_raw(entry.tx);
+            entry.tx = null();
+        }
+    }
+    entry.state = State::Zombie;
+    entry.status = Some(status);
+
+    // senders blocked by this process are woken up when its mailbox is closed
+    entry.boost = None;
+    entry.blocked.clear();
+
+    let watchers = Watchers {
+        pid: entry.get_pid(cnt[id as usize]),
+        status,
+        monitors: core::mem::take(&mut entry.monitors),
+        links: core::mem::take(&mut entry.links),
+    };
+
+    // the receiver no longer runs with the priority of this process
+    release_priority(id, proc_info);
+
+    orphan_children(watchers.pid, proc_info);
+    registry::unregister(watchers.pid);
+    timeout::cancel(watchers.pid);
+
+    Some(watchers)
+}
+
+/// Send DOWN messages to the monitoring processes,
+/// and kill the linked processes unless they trap exits.
+/// PROC_INFO must not be locked by the caller.
+fn notify_down(watchers: Watchers) {
+    for &dst in watchers.monitors.iter() {
+        send_down(dst, watchers.pid, watchers.status);
+    }
+
+    for &dst in watchers.links.iter() {
+        let is_trap_exit = {
+            // disable FIQ, IRQ, Abort, Debug
+            let _mask = cpuint::mask();
+            let mut node = MCSNode::new();
+            let mut proc_info = lock_proc_info(&mut node);
+
+            let (id, count) = Process::pid_to_id_cnt(dst);
+            let (tbl, cnt, _) = proc_info.split();
+            match tbl[id as usize].as_mut() {
+                Some(entry) if cnt[id as usize] == count && entry.state != State::Zombie => {
+                    entry.links.retain(|&pid| pid != watchers.pid);
+                    entry.is_trap_exit
+                }
+                _ => continue,
+            }
+        };
+
+        if is_trap_exit {
+            send_down(dst, watchers.pid, watchers.status);
+        } else if !watchers.status.is_normal() {
+            kill(dst);
+        }
+    }
+}
+
+/// Send a DOWN message of `pid` to `dst`.
+fn send_down(dst: u32, pid: u32, status: ExitStatus) {
+    let (id, count) = Process::pid_to_id_cnt(dst);
+
+    // disable FIQ, IRQ, Abort, Debug
+    let _mask = cpuint::mask();
+
+    let tx = {
+        let mut node = MCSNode::new();
+        let mut proc_info = lock_proc_info(&mut node);
+
+        let (tbl, cnt, _) = proc_info.split();
+        match tbl[id as usize].as_mut() {
+            Some(entry) if cnt[id as usize] == count && entry.state != State::Zombie => {
+                entry.get_tx()
+            }
+            _ => return,
+        }
+    };
+
+    tx.send_notice(Msg {
+        loc: Locator::Down(pid),
+        data: status.to_bytes().to_vec(),
+    });
+}
+
+/// Monitor the process `pid`.
+/// If `pid` has already terminated, a DOWN message is sent at once.
+/// false is returned if `pid` does not exist.
+pub fn monitor(pid: u32) -> bool {
+    let me = get_pid();
+    let (id, count) = Process::pid_to_id_cnt(pid);
+
+    let status = {
+        // disable FIQ, IRQ, Abort, Debug
+        let _mask = cpuint::mask();
+        let mut node = MCSNode::new();
+        let mut proc_info = lock_proc_info(&mut node);
+
+        let (tbl, cnt, _) = proc_info.split();
+        match entry_mut(tbl, id) {
+            Some(entry) if cnt[id as usize] == count => {
+                if entry.state == State::Zombie {
+                    entry.status
+                } else {
+                    entry.monitors.push(me);
+                    return true;
+                }
+            }
+            _ => return false,
+        }
+    };
+
+    if let Some(status) = status {
+        send_down(me, pid, status);
+    }
+
+    true
+}
+
+/// Link the current process and the process `pid`.
+/// false is returned if `pid` does not exist or has already terminated.
+pub fn link(pid: u32) -> bool {
+    let me = get_pid();
+    if me == pid {
+        return false;
+    }
+
+    let (id, count) = Process::pid_to_id_cnt(pid);
+    let current = get_actives()[core_pos()].unwrap(); // must be active
+
+    // disable FIQ, IRQ, Abort, Debug
+    let _mask = cpuint::mask();
+    let mut node = MCSNode::new();
+    let mut proc_info = lock_proc_info(&mut node);
+
+    let (tbl, cnt, _) = proc_info.split();
+    match entry_mut(tbl, id) {
+        Some(entry) if cnt[id as usize] == count && entry.state != State::Zombie => {
+            if !entry.links.contains(&me) {
+                entry.links.push(me);
+            }
+        }
+        _ => return false,
+    }
+
+    if let Some(entry) = tbl[current as usize].as_mut() {
+        if !entry.links.contains(&pid) {
+            entry.links.push(pid);
+        }
+    }
+
+    true
+}
+
+/// Receive DOWN messages instead of being killed when a linked process terminates.
+pub fn trap_exit(is_trap: bool) {
+    let current = get_actives()[core_pos()].unwrap(); // must be active
+
+    // disable FIQ, IRQ, Abort, Debug
+    let _mask = cpuint::mask();
+    let mut node = MCSNode::new();
+    let mut proc_info = lock_proc_info(&mut node);
+
+    if let Some(entry) = proc_info.table[current as usize].as_mut() {
+        entry.is_trap_exit = is_trap;
+    }
+}
+
+fn schedule2(mask: cpuint::ArchIntMask, mut proc_info: MCSLockGuard<ProcInfo>) {
+    let actives = get_actives();
+    let aff = core_pos();
+
+    // tear down the current process if it has been killed by another CPU
+    if let Some(current) = actives[aff] {
+        if let Some(State::Killed) = proc_info.table[current as usize].as_ref().map(|e| e.state) {
+            proc_info.unlock();
+            mask.unmask();
+            exit(ExitStatus::Killed);
+        }
+    }
+
+    let (tbl, _, readyq) = proc_info.split();
+
+    let now = timer::get_count();
+    if let Some(current) = actives[aff] {
+        if let Some(entry) = tbl[current as usize].as_mut() {
+            entry.charge(now);
+        }
+    }
+
+    // get next
+    // the current process is not preempted by processes of lower priorities
+    let lowest = match actives[aff].and_then(|current| tbl[current as usize].as_ref()) {
+        Some(entry) if entry.state == State::Active => entry.prio(),
+        _ => PRIORITY_NUM as u8 - 1,
+    };
+    let next = readyq.deque(lowest);
+
+    if let Some(next) = next {
+        let next_ctx;
+
+        // move the current process to Ready queue
+        if let Some(current) = actives[aff] {
+            if let Some(entry) = tbl[current as usize].as_mut() {
+                if entry.state == State::Active {
+                    entry.state = State::Ready;
+                    readyq.enque(current, entry.prio());
+                }
+            } else {
+                return;
+            }
+
+            // make the next process Active
+            actives[aff] = Some(next);
+            if let Some(entry) = tbl[next as usize].as_mut() {
+                entry.state = State::Active;
+                entry.started = now;
+            } else {
+                return;
+            }
+
+            // give the next process a full time slice
+            get_need_resched()[aff] = false;
+            timer::reload();
+            get_sched_stats()[aff]
+                .switches
+                .fetch_add(1, Ordering::Relaxed);
+
+            next_ctx = unsafe { proc_info.get_ctx(next as usize) };
+            let current_ctx = unsafe { proc_info.get_ctx(current as usize) };
+
+            proc_info.unlock();
+            mask.unmask();
+
+            unsafe {
+                if (*current_ctx).save_context() > 0 {
+                    release_freed();
+                    return;
+                }
+            }
+        } else {
+            // make the next process Active
+            actives[aff] = Some(next);
+            if let Some(entry) = tbl[next as usize].as_mut() {
+                entry.state = State::Active;
+                entry.started = now;
+            } else {
+                return;
+            }
+
+            // give the next process a full time slice
+            get_need_resched()[aff] = false;
+            timer::reload();
+            get_sched_stats()[aff]
+                .switches
+                .fetch_add(1, Ordering::Relaxed);
+
+            next_ctx = unsafe { proc_info.get_ctx(next as usize) };
+
+            proc_info.unlock();
+            mask.unmask();
+        }
+
+        switch_to(next, next_ctx);
+    } else if None == actives[aff] {
+        proc_info.unlock();
+        mask.unmask();
+
+        idle();
+    }
+}
+
+/// Switch to the context of the process `next`.
+/// The kernel stacks of processes are in TTBR1,
+/// so TTBR0 is switched here after every lock has been released.
+fn switch_to(next: u32, next_ctx: *mut GpRegs) -> ! {
+    // the top of the kernel stack used by the exception vectors from EL0
+    cpu::tpidr_el1::set(kern_stack(next).1 as u64);
+    paging::switch_table(Some(next));
+
+    // context switch
+    unsafe { (*next_ctx).context_switch() }
+}
+
+/// Secondary CPUs join the scheduler here.
+/// Under OP-TEE, they return to the normal world and enter by SMCs instead.
+pub fn start_secondary() -> ! {
+    if cfg!(feature = "optee") {
+        crate::aarch64::smc::on_done();
+    }
+
+    idle()
+}
+
+/// Switch to the EL1 stack of this CPU and wait for a Ready process.
+/// Under OP-TEE, the CPU is given back to the normal world instead.
+fn idle() -> ! {
+    if cfg!(feature = "optee") {
+        crate::aarch64::smc::done();
+    }
+
+    // no process runs on this CPU
+    paging::switch_table(None);
+
+    let start = mmu::get_stack_el1_start();
+    let aff = core_pos() as u64;
+    let sp = start - mmu::STACK_SIZE * aff + mmu::EL1_ADDR_OFFSET;
+
+    unsafe {
+        asm!(
+            "mov sp, {}
+             br  {}",
+            in(reg) sp,
+            in(reg) idle_loop as usize,
+            options(noreturn)
+        );
+    }
+}
+
+extern "C" fn idle_loop() -> ! {
+    let aff = core_pos();
+
+    loop {
+        // the process which exited on this CPU is no longer running on its stack
+        release_freed();
+
+        // disable FIQ, IRQ, Abort, Debug
+        let mask = cpuint::mask();
+
+        // PROC_INFO is not locked while nothing is ready.
+        // The flag is set before checking the queues,
+        // so that `kick` after enqueueing a process sees it
+        get_idle()[aff] = true;
+        fence(Ordering::SeqCst);
+
+        if RUNQS.is_empty() {
+            // WFI returns by a pending interrupt even if it is masked,
+            // so an IPI sent by kick after enqueueing is not lost
+            cpu::wait_interrupt();
+
+            // handle the interrupt
+            let daif = cpu::daif::get();
+            cpuint::enable_irq();
+            cpu::isb();
+            cpu::daif::set(daif);
+        } else {
+            get_idle()[aff] = false;
+            let mut node = MCSNode::new();
+            let proc_info = lock_proc_info(&mut node);
+            schedule2(mask, proc_info);
+        }
+    }
+}
+
+/// Release the memory of the process which exited on this CPU.
+/// This must be called after switching from the stack of the exited process.
+fn release_freed() {
+    let aff = core_pos();
+    let freed = get_freed();
+    if let Some(id) = freed[aff].take() {
+        // unmap exited process's memory
+        paging::unmap_user_all(id);
+
+        // disable FIQ, IRQ, Abort, Debug
+        let _mask = cpuint::mask();
+
+        let mut node = MCSNode::new();
+        let mut proc_info = lock_proc_info(&mut node);
+        if let Some(entry) = proc_info.table[id as usize].as_mut() {
+            entry.stack = null_mut();
+        }
+        notify_parent(id, &mut proc_info);
+    }
+}
+
+/// Notify the parent that the Zombie process `id` has been released.
+/// An orphan is reaped at once, because nobody waits for it.
+fn notify_parent(id: u32, proc_info: &mut MCSLockGuard<ProcInfo>) {
+    let is_orphan = if let Some(entry) = proc_info.table[id as usize].as_ref() {
+        entry.parent.is_none()
+    } else {
+        return;
+    };
+
+    if is_orphan {
+        proc_info.release(id);
+    } else {
+        wake_parent(id, proc_info);
+    }
+}
+
+/// Make the parent of the process `id` Ready if it is waiting.
+fn wake_parent(id: u32, proc_info: &mut MCSLockGuard<ProcInfo>) {
+    let (tbl, cnt, readyq) = proc_info.split();
+    if let Some(pid) = tbl[id as usize].as_ref().and_then(|entry| entry.parent) {
+        let (parent, count) = Process::pid_to_id_cnt(pid);
+        if let Some(entry) = tbl[parent as usize].as_ref() {
+            if cnt[parent as usize] == count && entry.state == State::Wait {
+                make_ready(parent, tbl, readyq);
+            }
+        }
+    }
+}
+
+/// Orphan the children of the terminating process `pid`.
+/// Released Zombie children are reaped at once.
+fn orphan_children(pid: u32, proc_info: &mut MCSLockGuard<ProcInfo>) {
+    let mut reaped = Vec::new();
+    for entry in proc_info.table.iter_mut().flatten() {
+        if entry.parent == Some(pid) {
+            if entry.state == State::Zombie && entry.stack.is_null() {
+                reaped.push(entry.id);
+            } else {
+                entry.parent = None;
+            }
+        }
+    }
+
+    for id in reaped {
+        proc_info.release(id);
+    }
+}
+
+/// Wait for the child process `pid` to terminate, and reap it.
+/// None is returned if `pid` is not a child of the caller.
+pub fn wait(pid: u32) -> Option<ExitStatus> {
+    let (id, count) = Process::pid_to_id_cnt(pid);
+
+    let aff = core_pos();
+    let current = get_actives()[aff].unwrap(); // must be active
+
+    loop {
+        // disable FIQ, IRQ, Abort, Debug
+        let mask = cpuint::mask();
+        let mut node = MCSNode::new();
+        let mut proc_info = lock_proc_info(&mut node);
+
+        let (tbl, cnt, _) = proc_info.split();
+        let me = tbl[current as usize]
+            .as_ref()?
+            .get_pid(cnt[current as usize]);
+
+        let child = entry_mut(tbl, id)?;
+        if cnt[id as usize] != count || child.parent != Some(me) {
+            return None;
+        }
+
+        // the child has terminated and its memory has been released
+        if child.state == State::Zombie && child.stack.is_null() {
+            let status = child.status;
+            proc_info.release(id);
+            return status;
+        }
+
+        // make this thread's state Wait
+        if let Some(entry) = tbl[current as usize].as_mut() {
+            entry.set_wait_state(State::Wait);
+        }
+
+        schedule2(mask, proc_info);
+
+        wait_while(current, State::Wait);
+    }
+}
+
+/// Yielding.
+pub fn schedule() {
+    // disable FIQ, IRQ, Abort, Debug
+    let mask = cpuint::mask();
+
+    // aqcuire lock
+    let mut node = MCSNode::new();
+    let proc_info = lock_proc_info(&mut node);
+
+    schedule2(mask, proc_info);
+}
+
+/// Make the current process Blocked and yield the CPU.
+/// `unlock` is called after the state has been changed and before yielding,
+/// so that a `wake_up` is never lost.
+/// The process must check its condition again when this function returns.
+pub(crate) fn block<F: FnOnce()>(mask: cpuint::ArchIntMask, unlock: F) {
+    let aff = core_pos();
+    let current = get_actives()[aff].unwrap(); // must be active
+
+    let mut node = MCSNode::new();
+    let mut proc_info = lock_proc_info(&mut node);
+
+    if let Some(entry) = proc_info.table[current as usize].as_mut() {
+        entry.set_wait_state(State::Blocked);
+    } else {
+        panic!("no current process");
+    }
+
+    unlock();
+    schedule2(mask, proc_info);
+
+    wait_while(current, State::Blocked);
+}
+
+/// Wait for an interrupt if the process is still in `state` after `schedule2`,
+/// which means no other process was ready on this CPU.
+fn wait_while(id: u32, state: State) {
+    let is_waiting = {
+        let _mask = cpuint::mask();
+        let mut node = MCSNode::new();
+        let proc_info = lock_proc_info(&mut node);
+        matches!(proc_info.table[id as usize].as_ref(), Some(p) if p.state == state)
+    };
+
+    if is_waiting {
+        wait_event();
+    }
+}
+
+/// Wait for an interrupt.
+/// Interrupts are enabled only while waiting, even in kernel space.
+fn wait_event() {
+    let daif = cpu::daif::get();
+    cpuint::enable_irq();
+    cpu::wait_interrupt();
+    cpu::daif::set(daif);
+}
+
+/// Make a Blocked process Ready.
+pub(crate) fn wake_up(pid: u32) {
+    let (id, count) = Process::pid_to_id_cnt(pid);
+
+    // disable FIQ, IRQ, Abort, Debug
+    let _mask = cpuint::mask();
+    let mut node = MCSNode::new();
+    let mut proc_info = lock_proc_info(&mut node);
+
+    let (tbl, cnt, readyq) = proc_info.split();
+    if let Some(entry) = tbl[id as usize].as_mut() {
+        if cnt[id as usize] == count && entry.state == State::Blocked {
+            make_ready(id, tbl, readyq);
+        }
+    }
+}
+
+/// Make a process in Send state Ready.
+/// false is returned if the process has already exited.
+fn wake_sender(pid: u32) -> bool {
+    let (id, count) = Process::pid_to_id_cnt(pid);
+
+    // disable FIQ, IRQ, Abort, Debug
+    let _mask = cpuint::mask();
+    let mut node = MCSNode::new();
+    let mut proc_info = lock_proc_info(&mut node);
+
+    let (tbl, cnt, readyq) = proc_info.split();
+    match tbl[id as usize].as_ref() {
+        Some(entry) if cnt[id as usize] == count && entry.state != State::Zombie => {
+            if entry.state == State::Send {
+                make_ready(id, tbl, readyq);
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Is the process alive?
+fn is_alive(pid: u32) -> bool {
+    let (id, count) = Process::pid_to_id_cnt(pid);
+
+    // disable FIQ, IRQ, Abort, Debug
+    let _mask = cpuint::mask();
+    let mut node = MCSNode::new();
+    let proc_info = lock_proc_info(&mut node);
+
+    matches!(proc_info.table.get(id as usize).and_then(|e| e.as_ref()),
+        Some(entry) if proc_info.cnt[id as usize] == count && entry.state != State::Zombie)
+}
+
+/// Make a waiting process Ready.
+/// If it is still running on a CPU because no other process was ready,
+/// it becomes Active again instead of being enqueued.
+fn make_ready(id: u32, tbl: &mut ProcTable, readyq: &RunQs) {
+    if let Some(entry) = tbl[id as usize].as_mut() {
+        if entry.state == State::Killed {
+            // `schedule2` tears it down on its CPU
+            return;
+        }
+
+        if get_actives().contains(&Some(id)) {
+            // the time waiting on the CPU is not accounted
+            entry.state = State::Active;
+            entry.started = timer::get_count();
+        } else {
+            entry.state = State::Ready;
+            let prio = entry.prio();
+            readyq.enque(id, prio);
+            kick(prio, tbl);
+        }
+    }
+}
+
+/// Let a CPU run a process of `prio` which became Ready.
+/// An idle CPU is woken up,
+/// otherwise a CPU running a process of a lower priority is preempted.
+fn kick(prio: u8, tbl: &ProcTable) {
+    // pairs with the fence in `idle_loop`
+    fence(Ordering::SeqCst);
+
+    let aff = core_pos();
+    let idle = get_idle();
+    let actives = get_actives();
+
+    let cpu = (0..CORE_COUNT).find(|&i| i != aff && idle[i]).or_else(|| {
+        (0..CORE_COUNT).find(|&i| {
+            matches!(actives[i].and_then(|id| tbl[id as usize].as_ref()),
+                    Some(entry) if entry.prio() > prio)
+        })
+    });
+
+    match cpu {
+        Some(cpu) if cpu == aff => get_need_resched()[aff] = true,
+        Some(cpu) => bsp::int::send_ipi(1 << cpu),
+        None => (),
+    }
+}
+
+/// The process `id` inherits the priority of `from`, which is blocked by it.
+fn inherit_priority(id: u32, from: u32, proc_info: &mut MCSLockGuard<ProcInfo>) {
+    let (tbl, _, readyq) = proc_info.split();
+    match tbl[from as usize].as_mut() {
+        Some(entry) => entry.send_to = Some(id),
+        None => return,
+    }
+
+    if let Some(entry) = tbl[id as usize].as_mut() {
+        if !entry.blocked.contains(&from) {
+            entry.blocked.push(from);
+        }
+    }
+
+    update_boost(id, tbl, readyq);
+}
+
+/// The receiver blocking `from` gives back the priority inherited from it,
+/// because `from` has sent, exited or been woken up by closing the mailbox.
+fn release_priority(from: u32, proc_info: &mut MCSLockGuard<ProcInfo>) {
+    let (tbl, _, readyq) = proc_info.split();
+    let id = match tbl[from as usize].as_mut() {
+        Some(entry) => match entry.send_to.take() {
+            Some(id) => id,
+            None => return,
+        },
+        None => return,
+    };
+
+    if let Some(entry) = tbl[id as usize].as_mut() {
+        entry.blocked.retain(|&b| b != from);
+    }
+
+    update_boost(id, tbl, readyq);
+}
+
+/// Set the boost of the process `id` to the highest priority of the senders blocked by it.
+fn update_boost(id: u32, tbl: &mut ProcTable, readyq: &RunQs) {
+    let entry = match tbl[id as usize].as_ref() {
+        Some(entry) => entry,
+        None => return,
+    };
+
+    let boost = entry
+        .blocked
+        .iter()
+        .filter_map(|&b| tbl[b as usize].as_ref())
+        .map(|sender| sender.prio())
+        .min()
+        .filter(|&prio| prio < entry.priority);
+
+    if let Some(entry) = tbl[id as usize].as_mut() {
+        if entry.boost != boost {
+            entry.boost = boost;
+            readyq.requeue(id, entry.prio());
+        }
+    }
+}
+
+/// Set the priority of the process pid.
+/// false is returned if pid does not exist or `priority` is not less than PRIORITY_NUM.
+pub fn set_priority(pid: u32, priority: u8) -> bool {
+    if priority as usize >= PRIORITY_NUM {
+        return false;
+    }
+
+    let (id, count) = Process::pid_to_id_cnt(pid);
 
-/// Initialize the first process.
-/// It is often called the init process.
-pub fn init() {
     // disable FIQ, IRQ, Abort, Debug
     let mask = cpuint::mask();
-
-    let ch = ringq::Chan::<Msg>::new(0);
-    let (tx, rx) = ch.channel();
-
-    // allocate stack
-    let stack = user_stack(0);
-
-    // let tbl = get_process_table();
     let mut node = MCSNode::new();
-    let mut proc_info = PROC_INFO.lock(&mut node);
-
-    // initialize the init process
-    init_process(0, &mut proc_info, stack, tx.into_raw(), rx.into_raw());
+    let mut proc_info = lock_proc_info(&mut node);
 
-    // enque the process to Ready queue
-    let (tbl, _, readyq) = proc_info.split();
-    readyq.enque(0, tbl);
+    let (tbl, cnt, readyq) = proc_info.split();
+    match entry_mut(tbl, id) {
+        Some(entry) if cnt[id as usize] == count && entry.state != State::Zombie => {
+            entry.priority = priority;
+            readyq.requeue(id, entry.prio());
+        }
+        _ => return false,
+    }
 
+    // the caller may no longer have the highest priority
     schedule2(mask, proc_info);
-}
 
-#[no_mangle]
-fn goto_userland(_app: usize, next: usize, cnt: usize) {
-    set_tpid_reg(next as u8, cnt as u16);
-    unsafe { asm!("eret") }
+    true
 }
 
-fn init_process(
-    id: usize,
-    proc_info: &mut MCSLockGuard<ProcInfo>,
-    stack: *mut u8,
-    tx: *const ringq::Chan<Msg>,
-    rx: *const ringq::Chan<Msg>,
-) {
-    // initialize
-    let mut proc = Process::new(0);
-    proc.state = State::Ready;
-    proc.next = None;
-    proc.id = id as u8;
-    proc.regs.spsr = 0; // EL0t
-    proc.regs.elr = userland_entry as u64;
-    proc.regs.sp = stack as u64;
-    proc.regs.x30 = goto_userland as u64;
-    proc.stack = stack;
-    proc.tx = tx;
+/// Set the maximum number of pages which the process pid can map.
+/// Only the parent of pid or the init process can set it,
+/// and a process can lower its own quota but cannot raise it.
+/// false is returned if pid does not exist or the caller is not permitted.
+pub fn set_quota(pid: u32, pages: u32) -> bool {
+    let (id, count) = Process::pid_to_id_cnt(pid);
 
-    unsafe { RECEIVER[id] = rx };
+    // disable FIQ, IRQ, Abort, Debug
+    let _mask = cpuint::mask();
+    let mut node = MCSNode::new();
+    let mut proc_info = lock_proc_info(&mut node);
 
-    proc_info.cnt[id] += 1;
-    proc_info.table[id] = Some(proc);
-}
+    let (tbl, cnt, _) = proc_info.split();
+    let caller = match get_actives()[core_pos()] {
+        Some(current) => match tbl[current as usize].as_ref() {
+            Some(entry) => entry.get_pid(cnt[current as usize]),
+            None => return false,
+        },
+        None => return false,
+    };
 
-/// Spawn a new process.
-/// If successful this function is unreachable, otherwise (fail) this returns normally.
-pub fn spawn(app: u64) -> Option<u32> {
-    // disable FIQ, IRQ, Abort, Debug
-    let mask = cpuint::mask();
+    match entry_mut(tbl, id) {
+        Some(entry) if cnt[id as usize] == count && entry.state != State::Zombie => {
+            let is_permitted = if pid == caller {
+                pages <= paging::quota(id)
+            } else {
+                entry.parent == Some(caller) || caller == INIT_PID
+            };
 
-    // create channel
-    let mut ch = ringq::Chan::<Msg>::new(0);
+            if is_permitted {
+                paging::set_quota(id, pages);
+            }
+            is_permitted
+        }
+        _ => false,
+    }
+}
 
-    // aqcuire lock
-    let mut node = MCSNode::new();
-    let mut proc_info = PROC_INFO.lock(&mut node);
+/// Wake up a process in Recv or Sleep state whose deadline has passed.
+fn expire(pid: u32) {
+    let (id, count) = Process::pid_to_id_cnt(pid);
 
-    let tbl = &proc_info.table;
+    let mut node = MCSNode::new();
+    let mut proc_info = lock_proc_info(&mut node);
 
-    // find empty slot
-    let mut id: Option<u8> = None;
-    //    for i in 0..PROCESS_MAX {
-    for (i, item) in tbl.iter().enumerate().take(PROCESS_MAX) {
-        if item.is_none() {
-            id = Some(i as u8);
-            break;
+    let (tbl, cnt, readyq) = proc_info.split();
+    if let Some(entry) = tbl[id as usize].as_ref() {
+        if cnt[id as usize] == count && matches!(entry.state, State::Recv | State::Sleep) {
+            make_ready(id, tbl, readyq);
         }
     }
+}
 
-    let id = id?;
-    ch.set_pid(id);
-    let (tx, rx) = ch.channel();
-
-    let stack = user_stack(id);
+/// Deliver a message of a fired timer of `send_after` or a device interrupt.
+/// Like DOWN messages, it is queued even if the mailbox is full.
+fn deliver(dst: u32, msg: Msg) {
+    let (id, count) = Process::pid_to_id_cnt(dst);
 
-    // initialize process
-    init_process(
-        id as usize,
-        &mut proc_info,
-        stack,
-        tx.into_raw(),
-        rx.into_raw(),
-    );
+    let tx = {
+        let mut node = MCSNode::new();
+        let mut proc_info = lock_proc_info(&mut node);
 
-    let (tbl, cnt, readyq) = proc_info.split();
-    readyq.enque(id, tbl);
-    let pid = {
-        if let Some(entry) = tbl[id as usize].as_mut() {
-            let cnt2 = cnt[id as usize];
-            entry.regs.x0 = app;
-            entry.regs.x1 = id as u64;
-            entry.regs.x2 = cnt2 as u64;
-            entry.get_pid(cnt2)
-        } else {
-            return None;
+        let (tbl, cnt, _) = proc_info.split();
+        match tbl[id as usize].as_mut() {
+            Some(entry) if cnt[id as usize] == count && entry.state != State::Zombie => {
+                entry.get_tx()
+            }
+            _ => return, // dst has terminated
         }
     };
 
-    schedule2(mask, proc_info);
-
-    Some(pid)
+    tx.send_notice(msg);
 }
 
-/// exit process
-/// this function is always unreachable
-pub fn exit() -> ! {
-    // disable FIQ, IRQ, Abort, Debug
-    let mask = cpuint::mask();
-
-    // aqcuire lock
-    let mut node = MCSNode::new();
-    let mut proc_info = PROC_INFO.lock(&mut node);
+/// Sleep for `msec` milliseconds.
+pub fn sleep(msec: u64) {
+    let deadline = deadline(msec);
+    let current = get_actives()[core_pos()].unwrap(); // must be active
+    let mut is_registered = false;
 
-    let tbl = &mut proc_info.table;
+    while timer::get_count() < deadline {
+        // disable FIQ, IRQ, Abort, Debug
+        let mask = cpuint::mask();
+        let mut node = MCSNode::new();
+        let mut proc_info = lock_proc_info(&mut node);
 
-    let aff = core_pos();
-    let actives = get_actives();
-    if let Some(current) = actives[aff] {
+        let (tbl, cnt, _) = proc_info.split();
         if let Some(entry) = tbl[current as usize].as_mut() {
-            unsafe {
-                if entry.tx.is_null() {
-                    ringq::Sender::from_raw(entry.tx);
-                }
+            entry.set_wait_state(State::Sleep);
 
-                let rx = RECEIVER[current as usize];
-                if rx.is_null() {
-                    ringq::Receiver::from_raw(rx);
-                }
+            // wake up at the deadline
+            if !is_registered {
+                let pid = entry.get_pid(cnt[current as usize]);
+                timeout::add(deadline, timeout::Event::Wake(pid));
+                is_registered = true;
             }
-            entry.state = State::Zombie;
+        } else {
+            panic!("no current process");
         }
 
-        unset_user_allocator(current);
+        schedule2(mask, proc_info);
 
-        let freed = get_freed();
-        freed[aff] = Some(current);
+        wait_while(current, State::Sleep);
     }
+}
 
-    actives[aff] = None;
+/// Send data to dst after `msec` milliseconds.
+/// The source of the message is the caller.
+/// false is returned if dst does not exist now, the length of data exceeds `syscall::MSG_MAX_LEN`,
+/// or the caller has `syscall::TIMER_MAX` messages pending.
+pub fn send_after(dst: &Locator, data: Vec<u8>, msec: u64) -> bool {
+    let addr = if let Locator::Process(a) = dst {
+        *a
+    } else {
+        return false;
+    };
 
-    schedule2(mask, proc_info);
-    unreachable!()
-}
+    if data.len() > MSG_MAX_LEN || !is_alive(addr) {
+        return false;
+    }
 
-fn schedule2(mask: cpuint::ArchIntMask, mut proc_info: MCSLockGuard<ProcInfo>) {
-    // get next
-    let (tbl, _, readyq) = proc_info.split();
-    let actives = get_actives();
-    let next = readyq.deque(tbl);
-    let aff = core_pos();
+    let src = get_pid();
+    let msg = Msg {
+        loc: Locator::Process(src),
+        data,
+    };
 
-    if let Some(next) = next {
-        let next_ctx;
+    timeout::add(deadline(msec), timeout::Event::Send(src, addr, msg))
+}
 
-        // move the current process to Ready queue
-        if let Some(current) = actives[aff] {
-            if let Some(entry) = tbl[current as usize].as_mut() {
-                match entry.state {
-                    State::Active => {
-                        entry.state = State::Ready;
-                        readyq.enque(current, tbl);
-                    }
-                    State::Killed => {
-                        proc_info.unlock();
-                        mask.unmask();
-                        exit();
-                    }
-                    _ => (),
-                }
-            } else {
-                return;
-            }
+/// Get the value of the physical counter after `msec` milliseconds.
+/// It saturates instead of wrapping around, so a long timeout never fires early.
+fn deadline(msec: u64) -> u64 {
+    timer::get_count().saturating_add(timer::msec_to_count(msec))
+}
 
-            // make the next process Active
-            actives[aff] = Some(next);
-            if let Some(entry) = tbl[next as usize].as_mut() {
-                entry.state = State::Active;
-            } else {
-                return;
-            }
+/// Called by the timer interrupt on every tick.
+/// The process running on this CPU will be preempted by `preempt`.
+pub fn tick() {
+    request_resched();
 
-            next_ctx = unsafe { proc_info.get_ctx(next as usize) };
-            let current_ctx = unsafe { proc_info.get_ctx(current as usize) };
+    let now = timer::get_count();
+    while let Some(event) = timeout::pop_expired(now) {
+        match event {
+            timeout::Event::Wake(pid) => expire(pid),
+            timeout::Event::Send(_, dst, msg) => deliver(dst, msg),
+        }
+    }
 
-            proc_info.unlock();
-            mask.unmask();
+    if core_pos() == 0 {
+        age();
+    }
 
-            unsafe {
-                if (*current_ctx).save_context() > 0 {
-                    let aff = core_pos();
-                    let freed = get_freed();
-                    if let Some(id) = freed[aff] {
-                        // unmap exited process's memory
-                        paging::unmap_user_all(id);
-                        freed[aff] = None;
-
-                        // disable FIQ, IRQ, Abort, Debug
-                        let _mask = cpuint::mask();
-
-                        // clear entry
-                        let mut node = MCSNode::new();
-                        let mut proc_info = PROC_INFO.lock(&mut node);
-                        proc_info.table[id as usize] = None;
-                    }
+    balance();
+}
 
-                    return;
-                }
-            }
-        } else {
-            // make the next process Active
-            actives[aff] = Some(next);
-            if let Some(entry) = tbl[next as usize].as_mut() {
-                entry.state = State::Active;
-            } else {
-                return;
-            }
-            next_ctx = unsafe { proc_info.get_ctx(next as usize) };
+/// Balance the ready queues periodically.
+fn balance() {
+    let aff = core_pos();
+    let count = unsafe {
+        BALANCE_COUNT[aff] += 1;
+        BALANCE_COUNT[aff]
+    };
 
-            proc_info.unlock();
-            mask.unmask();
-        }
+    // PROC_INFO is not locked
+    if count % BALANCE_TICKS == 0 {
+        RUNQS.balance();
+    }
+}
 
-        // context switch
-        unsafe {
-            (*next_ctx).context_switch();
-        }
-    } else if None == actives[aff] {
-        proc_info.unlock();
-        mask.unmask();
+/// Raise the levels of Ready processes periodically.
+fn age() {
+    let count = unsafe {
+        AGING_COUNT += 1;
+        AGING_COUNT
+    };
 
-        crate::aarch64::smc::done();
+    // PROC_INFO is not locked
+    if count % AGING_TICKS == 0 {
+        RUNQS.age();
     }
 }
 
-/// Yielding.
-pub fn schedule() {
-    // disable FIQ, IRQ, Abort, Debug
-    let mask = cpuint::mask();
+/// Called by the inter-processor interrupt.
+/// The process running on this CPU will be preempted by `preempt`,
+/// and torn down by `schedule2` if it has been killed.
+pub fn on_ipi() {
+    request_resched();
+}
 
-    // aqcuire lock
-    let mut node = MCSNode::new();
-    let proc_info = PROC_INFO.lock(&mut node);
+fn request_resched() {
+    let aff = core_pos();
+    if get_actives()[aff].is_some() {
+        get_need_resched()[aff] = true;
+    }
+}
 
-    schedule2(mask, proc_info);
+/// Yield the CPU if the time slice of the current process has expired.
+/// This must be called at the end of interrupt handling,
+/// after the interrupt controller has been acknowledged.
+pub fn preempt() {
+    let aff = core_pos();
+    let need_resched = get_need_resched();
+    if need_resched[aff] {
+        need_resched[aff] = false;
+        schedule();
+    }
 }
 
 /// Get the process ID.
@@ -478,7 +1939,7 @@ pub fn get_pid() -> u32 {
     let id = actives[aff].unwrap();
 
     let mut node = MCSNode::new();
-    let proc_info = PROC_INFO.lock(&mut node);
+    let proc_info = lock_proc_info(&mut node);
     if let Some(entry) = proc_info.table[id as usize].as_ref() {
         entry.get_pid(proc_info.cnt[id as usize])
     } else {
@@ -486,33 +1947,165 @@ pub fn get_pid() -> u32 {
     }
 }
 
+/// Take a snapshot of the process table.
+/// Up to `buf.len()` processes are stored to buf, and the number of processes is returned.
+pub fn snapshot(buf: &mut [ProcStat]) -> usize {
+    let actives = get_actives();
+    let mut procs = Vec::new(); // (stat, raw ID, sender to count queued messages)
+    let mut num = 0;
+
+    {
+        // disable FIQ, IRQ, Abort, Debug
+        let _mask = cpuint::mask();
+        let mut node = MCSNode::new();
+        let mut proc_info = lock_proc_info(&mut node);
+
+        let now = timer::get_count();
+        let (tbl, cnt, readyq) = proc_info.split();
+        for (slot, &count) in tbl.iter_mut().zip(cnt.iter()) {
+            let entry = if let Some(entry) = slot {
+                entry
+            } else {
+                continue;
+            };
+
+            num += 1;
+            if procs.len() >= buf.len() {
+                continue;
+            }
+
+            // the current slice of a running process
+            let mut cpu_time = entry.cpu_time;
+            if actives.contains(&Some(entry.id)) {
+                cpu_time += now - entry.started;
+            }
+
+            let stat = ProcStat {
+                pid: entry.get_pid(count),
+                state: run_state(entry.state),
+                cpu: readyq.cpu(entry.id) as u32,
+                priority: entry.prio() as u32,
+                mailbox: 0,
+                pages: 0,
+                cpu_time: timer::count_to_usec(cpu_time),
+            };
+
+            let tx = if entry.tx.is_null() {
+                None
+            } else {
+                Some(entry.get_tx())
+            };
+
+            procs.push((stat, entry.id, tx));
+        }
+    }
+
+    // the mailboxes and the page tables are examined without locking PROC_INFO
+    for (dst, (mut stat, id, tx)) in buf.iter_mut().zip(procs.into_iter()) {
+        stat.mailbox = tx.map_or(0, |tx| tx.len()) as u32;
+        stat.pages = paging::user_pages(id) as u32;
+        *dst = stat;
+    }
+
+    num
+}
+
+fn run_state(state: State) -> RunState {
+    match state {
+        State::Ready => RunState::Ready,
+        State::Active => RunState::Active,
+        State::Recv => RunState::Recv,
+        State::Send => RunState::Send,
+        State::Blocked => RunState::Blocked,
+        State::Wait => RunState::Wait,
+        State::Sleep => RunState::Sleep,
+        State::Killed => RunState::Killed,
+        State::Zombie => RunState::Zombie,
+    }
+}
+
+/// Register the current process as `name`.
+/// A process has at most one name, which is removed when it terminates.
+/// false is returned if `name` is already used, the process already has a name,
+/// or the length of `name` is not in 1..=NAME_MAX_LEN.
+pub fn register(name: &[u8]) -> bool {
+    if name.is_empty() || name.len() > NAME_MAX_LEN {
+        return false;
+    }
+
+    registry::register(name, get_pid())
+}
+
+/// Get the process registered as `name`.
+pub fn whereis(name: &[u8]) -> Option<u32> {
+    registry::whereis(name)
+}
+
+/// Remove the name of the current process.
+/// false is returned if the process has no name.
+pub fn unregister() -> bool {
+    registry::unregister(get_pid())
+}
+
+/// Subscribe the current process to the device interrupt `irq`.
+/// Each interrupt is delivered as a message from `Locator::Device(irq)`,
+/// and the line is masked until the process calls `ack_irq`.
+/// false is returned if `irq` is not a device interrupt or another process subscribes it.
+pub fn subscribe_irq(irq: u32) -> bool {
+    device::subscribe(irq, get_pid())
+}
+
+/// Unmask the device interrupt `irq` subscribed by the current process.
+pub fn ack_irq(irq: u32) -> bool {
+    device::ack(irq, get_pid())
+}
+
 /// Get the raw Process ID.
-pub fn get_raw_id() -> Option<u8> {
+pub fn get_raw_id() -> Option<u32> {
     let aff = core_pos();
 
     let actives = get_actives();
     actives[aff]
 }
 
-pub fn send(dst: &Locator, val: u32) -> bool {
+/// Send data to dst.
+/// The length of data must not exceed `syscall::MSG_MAX_LEN`.
+/// This fails if the mailbox of dst is full.
+pub fn send(dst: &Locator, data: Vec<u8>) -> bool {
+    send2(dst, data, false)
+}
+
+/// Send data to dst.
+/// The caller blocks while the mailbox of dst is full.
+pub fn send_wait(dst: &Locator, data: Vec<u8>) -> bool {
+    send2(dst, data, true)
+}
+
+fn send2(dst: &Locator, data: Vec<u8>, is_wait: bool) -> bool {
     let addr = if let Locator::Process(a) = dst {
         a
     } else {
         return false;
     };
 
-    let id = addr & 0xff;
-    let count = addr >> 8;
+    if data.len() > MSG_MAX_LEN {
+        return false;
+    }
+
+    let pid = get_pid();
+    let src = Locator::Process(pid);
+
+    let (id, count) = Process::pid_to_id_cnt(*addr);
 
     // disable FIQ, IRQ, Abort, Debug
     let mask = cpuint::mask();
     let mut node = MCSNode::new();
-    let mut proc_info = PROC_INFO.lock(&mut node);
+    let mut proc_info = lock_proc_info(&mut node);
 
     let (tbl, cnt, _) = proc_info.split();
 
-    if let Some(p) = tbl[id as usize].as_mut() {
-        if cnt[id as usize] != count as u16 || p.state == State::Zombie {
+    if let Some(p) = entry_mut(tbl, id) {
+        if cnt[id as usize] != count || p.state == State::Zombie {
             return false;
         }
 
@@ -521,13 +2114,21 @@ pub fn send(dst: &Locator, val: u32) -> bool {
         proc_info.unlock();
         mask.unmask();
 
-        tx.send(Msg { loc: *dst, val }).is_ok()
+        let msg = Msg { loc: src, data };
+        if is_wait {
+            tx.send_wait(msg, pid).is_ok()
+        } else {
+            tx.send(msg).is_ok()
+        }
     } else {
         false
     }
 }
 
-pub fn recv(src: &mut Locator) -> u32 {
+fn recv2<F>(src: &mut Locator, f: F) -> Option<Vec<u8>>
+where
+    F: FnOnce(&ringq::Receiver<Msg>) -> Option<Msg>,
+{
     let aff = core_pos();
     let id = if let Some(id) = get_actives()[aff] {
         id
@@ -535,19 +2136,55 @@ pub fn recv(src: &mut Locator) -> u32 {
         panic!("no active process");
     };
 
-    let rx = unsafe {
-        let ptr = RECEIVER[id as usize];
-        assert_ne!(ptr, null());
-        RECEIVER[id as usize] = null();
-        ringq::Receiver::from_raw(ptr)
+    // the entry keeps the ownership,
+    // so that the mailbox is closed even if this process is killed while receiving
+    let ptr = {
+        let _mask = cpuint::mask();
+        let mut node = MCSNode::new();
+        let proc_info = lock_proc_info(&mut node);
+        proc_info.table[id as usize]
+            .as_ref()
+            .map_or(null(), |entry| entry.rx)
     };
+    assert_ne!(ptr, null());
+    let rx = unsafe { ManuallyDrop::new(ringq::Receiver::from_raw(ptr)) };
 
-    let ret = rx.recv();
+    let ret = f(&rx)?;
+    *src = ret.loc;
+    Some(ret.data)
+}
 
-    unsafe { RECEIVER[id as usize] = rx.into_raw() };
+/// Take the receiver of the process out of its entry.
+fn take_receiver(id: u32, proc_info: &mut MCSLockGuard<ProcInfo>) -> *const ringq::Chan<Msg> {
+    proc_info.table[id as usize]
+        .as_mut()
+        .map_or(null(), |entry| core::mem::replace(&mut entry.rx, null()))
+}
 
-    *src = ret.loc;
-    ret.val
+/// Close a mailbox taken by `take_receiver`.
+/// PROC_INFO must not be locked, because blocked senders are woken up.
+fn close_mailbox(rx: *const ringq::Chan<Msg>) {
+    if !rx.is_null() {
+        drop(unsafe { ringq::Receiver::from_raw(rx) });
+    }
+}
+
+/// Receive data.
+/// The caller blocks until a message arrives.
+pub fn recv(src: &mut Locator) -> Vec<u8> {
+    recv2(src, |rx| Some(rx.recv())).unwrap()
+}
+
+/// Receive data without blocking.
+pub fn try_recv(src: &mut Locator) -> Option<Vec<u8>> {
+    recv2(src, |rx| rx.try_recv())
+}
+
+/// Receive data.
+/// None is returned if no message arrives within `msec` milliseconds.
+pub fn recv_timeout(src: &mut Locator, msec: u64) -> Option<Vec<u8>> {
+    let deadline = deadline(msec);
+    recv2(src, |rx| rx.recv_deadline(Some(deadline)))
 }
 
 pub struct EnterKernel {
@@ -557,7 +2194,7 @@ pub struct EnterKernel {
 impl EnterKernel {
     pub fn new() -> Self {
         let tpid = cpu::tpidr_el0::get();
-        cpu::tpidrro_el0::set(1 << 63);
+        cpu::tpidr_el0::set(TPID_KERNEL_FLAG);
         EnterKernel { tpid }
     }
 }
@@ -573,15 +2210,15 @@ const TPID_KERNEL_FLAG: u64 = 1 << 63;
 /// tpidrro_el0 register format
 /// | bits    | mean         |
 /// |---------|--------------|
-/// |  0 -  7 | raw ID       |
-/// |  8 - 23 | count        |
-/// | 24 - 31 | CPU affinity |
+/// |  0 - 31 | raw ID       |
+/// | 32 - 55 | count        |
+/// | 56 - 62 | CPU affinity |
 ///
 /// tpidrro_el0 must be 1 << 63 in kernel space
-fn set_tpid_reg(id: u8, cnt: u16) {
-    let aff = core_pos() as u64 & 0xff;
-    let cnt = cnt as u64;
-    let tpid = (aff << 24) | (cnt << 8) | (id as u64);
+fn set_tpid_reg(id: u32, cnt: u32) {
+    let aff = core_pos() as u64 & 0x7f;
+    let cnt = cnt as u64 & 0xff_ffff;
+    let tpid = (aff << 56) | (cnt << 32) | (id as u64);
     cpu::tpidr_el0::set(tpid);
 }
 
@@ -592,14 +2229,14 @@ pub fn set_tpid_kernel() {
 
 /// Get raw ID from tpidrro_el0
 /// This function is for EL0
-pub fn get_raw_id_user() -> u8 {
-    cpu::tpidr_el0::get() as u8
+pub fn get_raw_id_user() -> u32 {
+    cpu::tpidr_el0::get() as u32
 }
 
 /// Get CPU affinity from tpidrro_el0
 /// This function is for EL0
 pub fn get_affinity_user() -> u8 {
-    (cpu::tpidr_el0::get() >> 24) as u8
+    ((cpu::tpidr_el0::get() >> 56) & 0x7f) as u8
 }
 
 /// Kernel space or user space?
@@ -612,7 +2249,7 @@ pub fn kill(pid: u32) {
     // disable FIQ, IRQ, Abort, Debug
     let mask = cpuint::mask();
     let mut node = MCSNode::new();
-    let mut proc_info = PROC_INFO.lock(&mut node);
+    let mut proc_info = lock_proc_info(&mut node);
 
     let (tbl, cnt, q) = proc_info.split();
 
@@ -624,25 +2261,37 @@ pub fn kill(pid: u32) {
             if pid == p.get_pid(cnt[current as usize]) {
                 proc_info.unlock();
                 mask.unmask();
-                exit();
+                exit(ExitStatus::Killed);
             }
         }
     }
 
-    let id = pid & 0xff;
-    if let Some(p) = tbl[id as usize].as_mut() {
+    let (id, _) = Process::pid_to_id_cnt(pid);
+    if let Some(p) = entry_mut(tbl, id) {
         if pid == p.get_pid(cnt[id as usize]) {
+            // a waiting process may still be on a CPU until it switches to another process
+            let on_cpu = actives.iter().position(|a| *a == Some(id));
             match p.state {
                 State::Ready => {
-                    q.remove(id as u8, tbl);
-                    kill_proc(id as u8, mask, proc_info);
+                    q.remove(id);
+                    kill_proc(id, mask, proc_info);
                 }
-                State::Recv => {
-                    kill_proc(id as u8, mask, proc_info);
+                State::Recv | State::Send | State::Blocked | State::Wait | State::Sleep
+                    if on_cpu.is_none() =>
+                {
+                    kill_proc(id, mask, proc_info);
                 }
-                State::Active => {
+                State::Active
+                | State::Recv
+                | State::Send
+                | State::Blocked
+                | State::Wait
+                | State::Sleep => {
+                    // interrupt the CPU running it, which tears it down in `schedule2`
                     p.state = State::Killed;
-                    // TODO
+                    if let Some(aff) = on_cpu {
+                        bsp::int::send_ipi(1 << aff);
+                    }
                 }
                 _ => (),
             }
@@ -650,28 +2299,31 @@ pub fn kill(pid: u32) {
     }
 }
 
-fn kill_proc(id: u8, mask: cpuint::ArchIntMask, mut proc_info: MCSLockGuard<ProcInfo>) {
-    let tbl = proc_info.table.as_mut();
-    if let Some(entry) = tbl[id as usize].as_mut() {
-        unsafe {
-            if entry.tx.is_null() {
-                ringq::Sender::from_raw(entry.tx);
-            }
-
-            let rx = RECEIVER[id as usize];
-            if rx.is_null() {
-                ringq::Receiver::from_raw(rx);
-            }
-        }
-    }
-
-    tbl[id as usize] = None;
+fn kill_proc(id: u32, mask: cpuint::ArchIntMask, mut proc_info: MCSLockGuard<ProcInfo>) {
+    let watchers = make_zombie(id, ExitStatus::Killed, &mut proc_info);
+    let rx = take_receiver(id, &mut proc_info);
 
     proc_info.unlock();
-    mask.unmask();
+    close_mailbox(rx);
+
+    if let Some(watchers) = watchers {
+        device::unsubscribe_all(watchers.pid);
+        notify_down(watchers);
+    }
 
     unset_user_allocator(id);
 
     // unmap killed process's memory
     paging::unmap_user_all(id);
+
+    // the entry stays Zombie until the parent waits for it
+    let mut node = MCSNode::new();
+    let mut proc_info = lock_proc_info(&mut node);
+    if let Some(entry) = proc_info.table[id as usize].as_mut() {
+        entry.stack = null_mut();
+    }
+    notify_parent(id, &mut proc_info);
+    proc_info.unlock();
+
+    mask.unmask();
 }
@@ -0,0 +1,45 @@
+//! Names of processes.
+//! A process has at most one name, which is removed when it terminates.
+
+use crate::cpuint;
+use alloc::{collections::BTreeMap, vec::Vec};
+use synctools::mcs::{MCSLock, MCSNode};
+
+// name -> pid
+static NAMES: MCSLock<BTreeMap<Vec<u8>, u32>> = MCSLock::new(BTreeMap::new());
+
+/// Register pid as name.
+/// false is returned if name is already used or pid already has a name.
+pub(super) fn register(name: &[u8], pid: u32) -> bool {
+    let _mask = cpuint::mask();
+    let mut node = MCSNode::new();
+    let mut names = NAMES.lock(&mut node);
+
+    if names.contains_key(name) || names.values().any(|&p| p == pid) {
+        return false;
+    }
+
+    names.insert(name.to_vec(), pid);
+    true
+}
+
+/// Get the process registered as name.
+pub(super) fn whereis(name: &[u8]) -> Option<u32> {
+    let _mask = cpuint::mask();
+    let mut node = MCSNode::new();
+    let names = NAMES.lock(&mut node);
+
+    names.get(name).copied()
+}
+
+/// Remove the name of pid.
+/// false is returned if pid has no name.
+pub(super) fn unregister(pid: u32) -> bool {
+    let _mask = cpuint::mask();
+    let mut node = MCSNode::new();
+    let mut names = NAMES.lock(&mut node);
+
+    let len = names.len();
+    names.retain(|_, p| *p != pid);
+    names.len() != len
+}
@@ -1,45 +1,53 @@
 use super::*;
 use crate::{cpuint, driver::topology::core_pos};
-use ::alloc::sync::Arc;
-use synctools::mcs::{MCSLock, MCSNode};
-
-const QUEUE_SIZE: usize = 8;
+use ::alloc::{collections::VecDeque, sync::Arc};
+use synctools::mcs::{MCSLock, MCSLockGuard, MCSNode};
 
 struct RingQ<T> {
-    buf: [Option<T>; QUEUE_SIZE],
+    buf: Vec<Option<T>>,
     head: usize,
     last: usize,
     num: usize,
+    senders: VecDeque<u32>, // processes waiting for room, in FIFO order
+    overflow: VecDeque<T>,  // notices which did not fit, only while buf is full
+    is_closed: bool,        // the receiver has gone
 }
 
 pub(super) struct Chan<T> {
     q: MCSLock<RingQ<T>>,
-    pid: u8,
+    pid: u32,
 }
 
 impl<T: Send> RingQ<T> {
-    const BIT_MASK: usize = QUEUE_SIZE - 1;
+    fn new(capacity: usize) -> Self {
+        assert!(capacity > 0);
 
-    fn new() -> Self {
-        // check N == 2^x
-        // assert!((QUEUE_SIZE != 0) && (QUEUE_SIZE & (QUEUE_SIZE - 1)) == 0);
+        let mut buf = Vec::with_capacity(capacity);
+        buf.resize_with(capacity, || None);
 
         RingQ {
-            buf: arr![None; 8], // QUEUE_SIZE == 8
+            buf,
             head: 0,
             last: 0,
             num: 0,
+            senders: VecDeque::new(),
+            overflow: VecDeque::new(),
+            is_closed: false,
         }
     }
 
+    fn is_full(&self) -> bool {
+        self.num >= self.buf.len()
+    }
+
     fn enque(&mut self, v: T) -> Result<(), T> {
-        if self.num >= QUEUE_SIZE {
+        if self.is_full() {
             return Err(v);
         }
 
         self.buf[self.last] = Some(v);
         self.num += 1;
-        self.last += 1;
+        self.last = (self.last + 1) % self.buf.len();
 
         Ok(())
     }
@@ -50,22 +58,45 @@ impl<T: Send> RingQ<T> {
         }
 
         let v = self.buf[self.head].take();
-        self.head += 1;
+        self.head = (self.head + 1) % self.buf.len();
         self.num -= 1;
 
+        // notices keep the order with the other values
+        if let Some(notice) = self.overflow.pop_front() {
+            let _ = self.enque(notice);
+        }
+
         v
     }
+
+    /// Enqueue a value even if the queue is full.
+    fn enque_force(&mut self, v: T) {
+        if let Err(v) = self.enque(v) {
+            self.overflow.push_back(v);
+        }
+    }
+
+    /// Wake up the first blocked sender, because there is room now.
+    /// Senders which have already exited are removed.
+    fn notify_sender(&mut self) {
+        while let Some(&pid) = self.senders.front() {
+            if wake_sender(pid) {
+                break;
+            }
+            self.senders.pop_front();
+        }
+    }
 }
 
 impl<T: Send> Chan<T> {
-    pub(super) fn new(pid: u8) -> Self {
+    pub(super) fn new(pid: u32, capacity: usize) -> Self {
         Chan {
-            q: MCSLock::new(RingQ::new()),
+            q: MCSLock::new(RingQ::new(capacity)),
             pid,
         }
     }
 
-    pub(super) fn set_pid(&mut self, pid: u8) {
+    pub(super) fn set_pid(&mut self, pid: u32) {
         self.pid = pid;
     }
 
@@ -81,27 +112,151 @@ pub(super) struct Sender<T> {
 }
 
 impl<T: Send> Sender<T> {
+    /// Number of queued values, including notices which did not fit.
+    pub(super) fn len(&self) -> usize {
+        let mut node = MCSNode::new();
+        let _mask = cpuint::mask();
+        let q = self.ch.q.lock(&mut node);
+        q.num + q.overflow.len()
+    }
+
+    /// Send a value without blocking.
+    /// This fails if the queue is full or other senders are waiting for room,
+    /// so that blocked senders are never overtaken.
     pub(super) fn send(&self, v: T) -> Result<(), T> {
         let mut node = MCSNode::new();
         let mask = cpuint::mask();
         let mut q = self.ch.q.lock(&mut node);
-        let _ = q.enque(v)?;
 
-        // notify to the receiver
-        let mut node = MCSNode::new();
-        let mut proc_info = PROC_INFO.lock(&mut node);
-        let (tbl, _, readyq) = proc_info.split();
-        if let Some(entry) = tbl[self.ch.pid as usize].as_mut() {
-            if entry.state == State::Recv {
-                entry.state = State::Ready;
-                readyq.enque(self.ch.pid, tbl);
+        if q.is_closed || !q.senders.is_empty() {
+            return Err(v);
+        }
+
+        q.enque(v)?;
+        self.notify_receiver(mask, q);
+
+        Ok(())
+    }
+
+    /// Send a value.
+    /// If the queue is full, the caller, whose process ID is `pid`, blocks
+    /// until the receiver drains the queue.
+    /// Blocked senders are served in FIFO order.
+    /// This fails only if the receiver has gone.
+    pub(super) fn send_wait(&self, v: T, pid: u32) -> Result<(), T> {
+        let mut v = v;
+
+        loop {
+            let mask = cpuint::mask();
+            let mut node = MCSNode::new();
+            let mut q = self.ch.q.lock(&mut node);
+
+            if q.is_closed {
+                return Err(v);
+            }
+
+            // skip senders which have already exited
+            while let Some(&front) = q.senders.front() {
+                if front == pid || is_alive(front) {
+                    break;
+                }
+                q.senders.pop_front();
+            }
+
+            let is_turn = match q.senders.front() {
+                Some(&front) => front == pid,
+                None => true,
+            };
+
+            if is_turn {
+                match q.enque(v) {
+                    Ok(()) => {
+                        if q.senders.front() == Some(&pid) {
+                            q.senders.pop_front();
+
+                            // the receiver no longer blocks this sender
+                            let (id, _) = Process::pid_to_id_cnt(pid);
+                            let mut node = MCSNode::new();
+                            let mut proc_info = lock_proc_info(&mut node);
+                            release_priority(id, &mut proc_info);
+                        }
+
+                        // let the next sender try if there is still room
+                        if !q.is_full() {
+                            q.notify_sender();
+                        }
+
+                        self.notify_receiver(mask, q);
+                        return Ok(());
+                    }
+                    Err(r) => v = r,
+                }
+            }
+
+            if !q.senders.contains(&pid) {
+                q.senders.push_back(pid);
+            }
+
+            let aff = core_pos();
+            let current = get_actives()[aff].unwrap(); // must be active
+
+            // make this thread's state Send
+            let mut node = MCSNode::new();
+            let mut proc_info = lock_proc_info(&mut node);
+            if let Some(entry) = proc_info.table[current as usize].as_mut() {
+                entry.set_wait_state(State::Send);
+            } else {
+                panic!("no current process");
             }
+
+            // the receiver runs with the priority of this process until there is room
+            inherit_priority(self.ch.pid, current, &mut proc_info);
+
+            q.unlock();
+            schedule2(mask, proc_info);
+
+            wait_while(current, State::Send);
+        }
+    }
+
+    /// Send a notice of the kernel.
+    /// Notices are never lost even if the queue is full,
+    /// and the caller never yields the CPU.
+    /// PROC_INFO must not be locked by the caller.
+    pub(super) fn send_notice(&self, v: T) {
+        let mut node = MCSNode::new();
+        let _mask = cpuint::mask();
+        let mut q = self.ch.q.lock(&mut node);
+
+        if q.is_closed {
+            return;
         }
 
+        q.enque_force(v);
+
+        let mut node = MCSNode::new();
+        let mut proc_info = lock_proc_info(&mut node);
+        self.wake_receiver(&mut proc_info);
+    }
+
+    /// Notify the receiver that a value has been enqueued,
+    /// and release the lock of the queue.
+    fn notify_receiver(&self, mask: cpuint::ArchIntMask, q: MCSLockGuard<RingQ<T>>) {
+        let mut node = MCSNode::new();
+        let mut proc_info = lock_proc_info(&mut node);
+        self.wake_receiver(&mut proc_info);
+
         q.unlock();
         schedule2(mask, proc_info);
+    }
 
-        Ok(())
+    fn wake_receiver(&self, proc_info: &mut MCSLockGuard<ProcInfo>) {
+        let (tbl, _, readyq) = proc_info.split();
+        if let Some(entry) = tbl[self.ch.pid as usize].as_ref() {
+            if entry.state == State::Recv {
+                make_ready(self.ch.pid, tbl, readyq);
+            }
+        }
     }
 
     pub(super) fn into_raw(self) -> *const Chan<T> {
@@ -115,42 +270,80 @@ impl<T: Send> Sender<T> {
     }
 }
 
-pub(super) struct Receiver<T> {
+pub(super) struct Receiver<T: Send> {
     ch: Arc<Chan<T>>,
 }
 
 impl<T: Send> Receiver<T> {
     pub(super) fn recv(&self) -> T {
+        self.recv_deadline(None).unwrap()
+    }
+
+    pub(super) fn try_recv(&self) -> Option<T> {
+        let mut node = MCSNode::new();
+        let _mask = cpuint::mask();
+        let mut q = self.ch.q.lock(&mut node);
+        let ret = q.deque()?;
+        q.notify_sender();
+        Some(ret)
+    }
+
+    /// Receive a value.
+    /// None is returned when the physical counter reaches `deadline`.
+    pub(super) fn recv_deadline(&self, deadline: Option<u64>) -> Option<T> {
         let mut node = MCSNode::new();
+        let mut is_registered = false;
 
         loop {
             let mask = cpuint::mask();
             let mut q = self.ch.q.lock(&mut node);
             if let Some(r) = q.deque() {
-                return r;
+                q.notify_sender();
+                return Some(r);
             } else {
+                if let Some(deadline) = deadline {
+                    if timer::get_count() >= deadline {
+                        return None;
+                    }
+                }
+
                 let aff = core_pos();
                 let actives = get_actives();
                 let current = actives[aff].unwrap(); // must be active
 
                 // make this thread's state Recv
                 let mut node = MCSNode::new();
-                let mut proc_info = PROC_INFO.lock(&mut node);
+                let mut proc_info = lock_proc_info(&mut node);
+
+                let (tbl, cnt, _) = proc_info.split();
+                if let Some(entry) = tbl[current as usize].as_mut() {
+                    entry.set_wait_state(State::Recv);
 
-                if let Some(entry) = proc_info.table[current as usize].as_mut() {
-                    entry.state = State::Recv;
+                    // wake up at the deadline
+                    if let (Some(deadline), false) = (deadline, is_registered) {
+                        timeout::add(
+                            deadline,
+                            timeout::Event::Wake(entry.get_pid(cnt[current as usize])),
+                        );
+                        is_registered = true;
+                    }
                 } else {
                     panic!("no current process");
                 }
 
                 q.unlock();
                 schedule2(mask, proc_info);
+
+                wait_while(current, State::Recv);
             }
         }
     }
 
     pub(super) fn into_raw(self) -> *const Chan<T> {
-        Arc::into_raw(self.ch)
+        // the channel must not be closed
+        let ptr = Arc::as_ptr(&self.ch);
+        core::mem::forget(self);
+        ptr
     }
 
     pub(super) unsafe fn from_raw(ptr: *const Chan<T>) -> Self {
@@ -159,3 +352,34 @@ impl<T: Send> Receiver<T> {
         }
     }
 }
+
+impl<T: Send> Drop for Receiver<T> {
+    /// Close the channel.
+    /// Queued values are discarded, and blocked senders are woken up to fail.
+    /// PROC_INFO must not be locked by the caller.
+    fn drop(&mut self) {
+        let mut node = MCSNode::new();
+        let _mask = cpuint::mask();
+        let mut q = self.ch.q.lock(&mut node);
+
+        q.is_closed = true;
+        while q.deque().is_some() {}
+        q.overflow.clear();
+
+        // the blocked senders no longer lend their priorities to the receiver
+        {
+            let mut node = MCSNode::new();
+            let mut proc_info = lock_proc_info(&mut node);
+            for &pid in q.senders.iter() {
+                let (id, count) = Process::pid_to_id_cnt(pid);
+                if proc_info.cnt[id as usize] == count {
+                    release_priority(id, &mut proc_info);
+                }
+            }
+        }
+
+        while let Some(pid) = q.senders.pop_front() {
+            wake_sender(pid);
+        }
+    }
+}
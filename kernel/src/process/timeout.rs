@@ -0,0 +1,143 @@
+//! Timers of processes.
+//! Timers are checked on every tick of the scheduler,
+//! so the resolution is the time slice.
+//!
+//! Messages of `send_after` are queued even if the mailbox of the destination is full,
+//! so a process can have at most TIMER_MAX of them pending,
+//! and the timers of a process are dropped when it exits.
+
+use super::Msg;
+use crate::{cpuint, syscall::TIMER_MAX};
+use alloc::collections::{BTreeMap, BinaryHeap};
+use core::cmp::{Ordering, Reverse};
+use synctools::mcs::{MCSLock, MCSNode};
+
+/// What happens when a timer fires.
+pub(super) enum Event {
+    Wake(u32),           // wake up the process pid in Recv or Sleep state
+    Send(u32, u32, Msg), // (src, dst), send a message from the process src to the process dst
+}
+
+impl Event {
+    /// Whether the process pid sends or receives the event.
+    fn involves(&self, pid: u32) -> bool {
+        match self {
+            Event::Wake(dst) => *dst == pid,
+            Event::Send(src, dst, _) => *src == pid || *dst == pid,
+        }
+    }
+}
+
+struct Timer {
+    deadline: u64, // value of the physical counter
+    seq: u64,      // timers of the same deadline fire in the order of addition
+    event: Event,
+}
+
+impl PartialEq for Timer {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Timer {}
+
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Timer {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.deadline, self.seq).cmp(&(other.deadline, other.seq))
+    }
+}
+
+struct Timers {
+    heap: BinaryHeap<Reverse<Timer>>,
+    seq: u64,
+    pending: BTreeMap<u32, usize>, // number of pending messages of each source
+}
+
+impl Timers {
+    fn dec_pending(&mut self, event: &Event) {
+        if let Event::Send(src, _, _) = event {
+            if let Some(n) = self.pending.get_mut(src) {
+                *n -= 1;
+                if *n == 0 {
+                    self.pending.remove(src);
+                }
+            }
+        }
+    }
+}
+
+static TIMERS: MCSLock<Option<Timers>> = MCSLock::new(None);
+
+/// Add a timer.
+/// The deadline is a value of the physical counter.
+/// false is returned if the source of a message has TIMER_MAX pending messages.
+pub(super) fn add(deadline: u64, event: Event) -> bool {
+    let _mask = cpuint::mask();
+    let mut node = MCSNode::new();
+    let mut lock = TIMERS.lock(&mut node);
+
+    let timers = lock.get_or_insert_with(|| Timers {
+        heap: BinaryHeap::new(),
+        seq: 0,
+        pending: BTreeMap::new(),
+    });
+
+    if let Event::Send(src, _, _) = &event {
+        let n = timers.pending.entry(*src).or_insert(0);
+        if *n >= TIMER_MAX {
+            return false;
+        }
+        *n += 1;
+    }
+
+    timers.seq += 1;
+    timers.heap.push(Reverse(Timer {
+        deadline,
+        seq: timers.seq,
+        event,
+    }));
+
+    true
+}
+
+/// Pop a timer whose deadline has passed.
+pub(super) fn pop_expired(now: u64) -> Option<Event> {
+    let _mask = cpuint::mask();
+    let mut node = MCSNode::new();
+    let mut lock = TIMERS.lock(&mut node);
+
+    let timers = lock.as_mut()?;
+    if let Some(Reverse(timer)) = timers.heap.peek() {
+        if timer.deadline <= now {
+            let Reverse(timer) = timers.heap.pop()?;
+            timers.dec_pending(&timer.event);
+            return Some(timer.event);
+        }
+    }
+
+    None
+}
+
+/// Drop the timers which the process pid sends or receives, because it has exited.
+/// This is called while locking PROC_INFO.
+pub(super) fn cancel(pid: u32) {
+    let _mask = cpuint::mask();
+    let mut node = MCSNode::new();
+    let mut lock = TIMERS.lock(&mut node);
+
+    if let Some(timers) = lock.as_mut() {
+        let heap = core::mem::take(&mut timers.heap);
+        timers.heap = heap
+            .into_iter()
+            .filter(|Reverse(timer)| !timer.event.involves(pid))
+            .collect();
+        timers.pending.remove(&pid);
+    }
+}
@@ -8,16 +8,302 @@ pub const SYS_RECV: u64 = 6;
 pub const SYS_SET_ALLOC: u64 = 7;
 pub const SYS_UNMAP: u64 = 8;
 pub const SYS_KILL: u64 = 9;
+pub const SYS_READ: u64 = 10;
+pub const SYS_WRITE: u64 = 11;
+pub const SYS_TRY_RECV: u64 = 12;
+pub const SYS_RECV_TIMEOUT: u64 = 13;
+pub const SYS_SEND_WAIT: u64 = 14;
+pub const SYS_WAIT: u64 = 15;
+pub const SYS_MONITOR: u64 = 16;
+pub const SYS_LINK: u64 = 17;
+pub const SYS_TRAP_EXIT: u64 = 18;
+pub const SYS_SETPRIORITY: u64 = 19;
+pub const SYS_SCHED_STATS: u64 = 20;
+pub const SYS_SLEEP: u64 = 21;
+pub const SYS_SEND_AFTER: u64 = 22;
+pub const SYS_PS: u64 = 23;
+pub const SYS_REGISTER: u64 = 24;
+pub const SYS_WHEREIS: u64 = 25;
+pub const SYS_UNREGISTER: u64 = 26;
+pub const SYS_SEND_NAME: u64 = 27;
+pub const SYS_SEND_NAME_WAIT: u64 = 28;
+pub const SYS_IRQ_SUBSCRIBE: u64 = 29;
+pub const SYS_IRQ_ACK: u64 = 30;
+pub const SYS_MMAP: u64 = 31;
+pub const SYS_MUNMAP: u64 = 32;
+pub const SYS_SET_QUOTA: u64 = 33;
+pub const SYS_FORK: u64 = 34;
 
+/// Maximum length of a message in bytes.
+pub const MSG_MAX_LEN: usize = 4096;
+
+/// Maximum length of a name of a process in bytes.
+pub const NAME_MAX_LEN: usize = 32;
+
+/// Maximum length of the serialized arguments of a process in bytes.
+pub const ARGS_MAX_LEN: usize = 4096;
+
+/// Default number of messages a mailbox can queue.
+pub const MAILBOX_DEFAULT: usize = 8;
+
+/// Maximum number of messages a mailbox can queue.
+pub const MAILBOX_MAX: usize = 1024;
+
+/// Maximum number of messages of `send_after` a process can have pending.
+pub const TIMER_MAX: usize = 64;
+
+/// Number of priorities. 0 is the highest, and PRIORITY_NUM - 1 is the lowest.
+pub const PRIORITY_NUM: usize = 8;
+
+/// Default priority of processes.
+pub const PRIORITY_DEFAULT: u8 = 4;
+
+/// Protection of anonymous mappings.
+pub const PROT_READ: u8 = 1;
+pub const PROT_WRITE: u8 = 2;
+
+/// Default number of pages a process can map, which is 64MiB if a page is 64KiB.
+/// Pages of the stack, the heap and anonymous mappings are counted.
+pub const PAGE_QUOTA_DEFAULT: u32 = 1024;
+
+use crate::{allocator, paging, process::get_raw_id_user};
+use alloc::vec::Vec;
 use core::arch::asm;
 
+/// The layout is fixed, so that the kernel can check a Locator passed by a process.
+#[repr(u32)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Locator {
     Process(u32),
     Device(u32),
+    Down(u32), // termination of a monitored or linked process
     Unknown,
 }
 
+impl Locator {
+    /// Decode the raw representation, which is | tag (u32) | value (u32) |.
+    pub fn from_raw(raw: [u32; 2]) -> Option<Self> {
+        match raw[0] {
+            0 => Some(Locator::Process(raw[1])),
+            1 => Some(Locator::Device(raw[1])),
+            2 => Some(Locator::Down(raw[1])),
+            3 => Some(Locator::Unknown),
+            _ => None,
+        }
+    }
+
+    /// Encode the raw representation.
+    /// The value of `Unknown` is 0, so that no uninitialized byte is copied.
+    pub fn to_raw(&self) -> [u32; 2] {
+        match *self {
+            Locator::Process(pid) => [0, pid],
+            Locator::Device(irq) => [1, irq],
+            Locator::Down(pid) => [2, pid],
+            Locator::Unknown => [3, 0],
+        }
+    }
+}
+
+/// How a process terminated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitStatus {
+    Exited(i32),   // called exit
+    Killed,        // killed by kill
+    StackOverflow, // fault detected by the canary of the stack
+    InvalidAccess, // fault outside of the process's memory
+    Error,         // SError
+    QuotaExceeded, // fault beyond the page quota
+    OutOfMemory,   // fault when no page is left
+}
+
+impl ExitStatus {
+    /// Length of the payload of DOWN messages.
+    pub const BYTES_LEN: usize = 5;
+
+    /// Serialize the status as the payload of DOWN messages.
+    /// | kind (u8) | exit code (i32 LE) |
+    pub fn to_bytes(&self) -> [u8; Self::BYTES_LEN] {
+        let (kind, code) = match *self {
+            ExitStatus::Exited(code) => (0, code),
+            ExitStatus::Killed => (1, 0),
+            ExitStatus::StackOverflow => (2, 0),
+            ExitStatus::InvalidAccess => (3, 0),
+            ExitStatus::Error => (4, 0),
+            ExitStatus::QuotaExceeded => (5, 0),
+            ExitStatus::OutOfMemory => (6, 0),
+        };
+
+        let c = code.to_le_bytes();
+        [kind, c[0], c[1], c[2], c[3]]
+    }
+
+    /// Deserialize the payload of DOWN messages.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() != Self::BYTES_LEN {
+            return None;
+        }
+
+        let code = i32::from_le_bytes([buf[1], buf[2], buf[3], buf[4]]);
+        match buf[0] {
+            0 => Some(ExitStatus::Exited(code)),
+            1 => Some(ExitStatus::Killed),
+            2 => Some(ExitStatus::StackOverflow),
+            3 => Some(ExitStatus::InvalidAccess),
+            4 => Some(ExitStatus::Error),
+            5 => Some(ExitStatus::QuotaExceeded),
+            6 => Some(ExitStatus::OutOfMemory),
+            _ => None,
+        }
+    }
+
+    /// Is this a normal termination?
+    /// A normal termination does not kill linked processes.
+    pub fn is_normal(&self) -> bool {
+        *self == ExitStatus::Exited(0)
+    }
+}
+
+/// Read a u32 LE at pos of buf, and advance pos.
+/// None is returned if buf is too short.
+pub fn read_u32(buf: &[u8], pos: &mut usize) -> Option<usize> {
+    let bytes = buf.get(*pos..*pos + 4)?;
+    *pos += 4;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+}
+
+/// Read bytes prefixed by their length in u32 LE at pos of buf, and advance pos.
+/// None is returned if buf is too short.
+pub fn read_bytes<'a>(buf: &'a [u8], pos: &mut usize) -> Option<&'a [u8]> {
+    let len = read_u32(buf, pos)?;
+    let bytes = buf.get(*pos..*pos + len)?;
+    *pos += len;
+    Some(bytes)
+}
+
+/// An argument of a process, which is passed by `spawn`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Arg {
+    Int(i64),
+    Str(Vec<u8>),
+}
+
+impl Arg {
+    /// Serialize arguments.
+    /// | count (u32 LE) | kind (u8) | len (u32 LE) | bytes | kind | len | bytes | ...
+    /// kind is 0 for `Int`, whose bytes are i64 LE, and 1 for `Str`.
+    pub fn encode(args: &[Arg]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&(args.len() as u32).to_le_bytes());
+        for arg in args.iter() {
+            let int;
+            let (kind, bytes) = match arg {
+                Arg::Int(n) => {
+                    int = n.to_le_bytes();
+                    (0, &int[..])
+                }
+                Arg::Str(s) => (1, &s[..]),
+            };
+            buf.push(kind);
+            buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+            buf.extend_from_slice(bytes);
+        }
+        buf
+    }
+
+    /// Deserialize arguments serialized by `encode`.
+    pub fn decode(buf: &[u8]) -> Option<Vec<Arg>> {
+        let mut pos = 0;
+        let cnt = read_u32(buf, &mut pos)?;
+
+        let mut args = Vec::new();
+        for _ in 0..cnt {
+            let kind = *buf.get(pos)?;
+            pos += 1;
+            let bytes = read_bytes(buf, &mut pos)?;
+
+            let arg = match kind {
+                0 => {
+                    let mut n = [0; 8];
+                    n.copy_from_slice(bytes.get(..8)?);
+                    Arg::Int(i64::from_le_bytes(n))
+                }
+                1 => Arg::Str(bytes.to_vec()),
+                _ => return None,
+            };
+            args.push(arg);
+        }
+
+        Some(args)
+    }
+}
+
+/// Scheduler counters of a CPU.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SchedStats {
+    pub switches: u64,   // context switches
+    pub steals: u64,     // processes taken from the ready queues of other CPUs
+    pub migrations: u64, // processes moved by load balancing
+    pub lock_wait: u64,  // time waiting for the lock of the process table in microseconds
+    pub lock_count: u64, // times the lock of the process table was acquired
+}
+
+impl SchedStats {
+    pub const fn new() -> Self {
+        SchedStats {
+            switches: 0,
+            steals: 0,
+            migrations: 0,
+            lock_wait: 0,
+            lock_count: 0,
+        }
+    }
+}
+
+/// State of a process in `ProcStat`.
+/// It is u32, so that `ProcStat` has no padding.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunState {
+    Ready,
+    Active,
+    Recv,
+    Send,
+    Blocked,
+    Wait,
+    Sleep,
+    Killed,
+    Zombie,
+}
+
+/// A snapshot of a process.
+/// The layout has no padding, so that the kernel copies it as it is.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct ProcStat {
+    pub pid: u32,
+    pub state: RunState,
+    pub cpu: u32,      // CPU whose ready queue the process belongs to
+    pub priority: u32, // effective priority, which may be inherited
+    pub mailbox: u32,  // queued messages
+    pub pages: u32,    // mapped pages of the process's memory
+    pub cpu_time: u64, // time running on CPUs in microseconds
+}
+
+impl ProcStat {
+    pub const fn new() -> Self {
+        ProcStat {
+            pid: 0,
+            state: RunState::Zombie,
+            cpu: 0,
+            priority: 0,
+            mailbox: 0,
+            pages: 0,
+            cpu_time: 0,
+        }
+    }
+}
+
 macro_rules! syscall {
     ($id:expr) => {
         {
@@ -73,11 +359,111 @@ macro_rules! syscall {
             ret
         }
     };
+    ($id:expr, $arg1:expr, $arg2:expr, $arg3:expr) => {
+        {
+            let ret: isize;
+            unsafe {
+                asm!(
+                    "mov x0, {}
+                     mov x1, {}
+                     mov x2, {}
+                     mov x3, {}
+                     svc #0
+                     mov {}, x0",
+                    in(reg) $id,
+                    in(reg) $arg1,
+                    in(reg) $arg2,
+                    in(reg) $arg3,
+                    lateout(reg) ret,
+                    out("x0") _, out("x1") _, out("x2") _, out("x3") _
+                )
+            };
+            ret
+        }
+    };
+    ($id:expr, $arg1:expr, $arg2:expr, $arg3:expr, $arg4:expr) => {
+        {
+            let ret: isize;
+            unsafe {
+                asm!(
+                    "mov x0, {}
+                     mov x1, {}
+                     mov x2, {}
+                     mov x3, {}
+                     mov x4, {}
+                     svc #0
+                     mov {}, x0",
+                    in(reg) $id,
+                    in(reg) $arg1,
+                    in(reg) $arg2,
+                    in(reg) $arg3,
+                    in(reg) $arg4,
+                    lateout(reg) ret,
+                    out("x0") _, out("x1") _, out("x2") _, out("x3") _, out("x4") _
+                )
+            };
+            ret
+        }
+    };
+    ($id:expr, $arg1:expr, $arg2:expr, $arg3:expr, $arg4:expr, $arg5:expr) => {
+        {
+            let ret: isize;
+            unsafe {
+                asm!(
+                    "mov x0, {}
+                     mov x1, {}
+                     mov x2, {}
+                     mov x3, {}
+                     mov x4, {}
+                     mov x5, {}
+                     svc #0
+                     mov {}, x0",
+                    in(reg) $id,
+                    in(reg) $arg1,
+                    in(reg) $arg2,
+                    in(reg) $arg3,
+                    in(reg) $arg4,
+                    in(reg) $arg5,
+                    lateout(reg) ret,
+                    out("x0") _, out("x1") _, out("x2") _, out("x3") _, out("x4") _, out("x5") _
+                )
+            };
+            ret
+        }
+    };
+}
+
+/// Create a new process whose mailbox can queue capacity messages.
+/// capacity must be in 1..=MAILBOX_MAX, and priority must be less than PRIORITY_NUM.
+/// args are copied into the child, which gets them by `args`.
+/// The serialized args must not be longer than ARGS_MAX_LEN.
+pub fn spawn(app: usize, capacity: usize, priority: u8, args: &[Arg]) -> Option<u32> {
+    let buf = Arg::encode(args);
+    if buf.len() > ARGS_MAX_LEN {
+        return None;
+    }
+
+    let ret = syscall!(
+        SYS_SPAWN,
+        app,
+        capacity,
+        priority as u64,
+        buf.as_ptr(),
+        buf.len()
+    );
+    if ret < 0 {
+        None
+    } else {
+        Some(ret as u32)
+    }
 }
 
-/// Create a new process.
-pub fn spawn(app: usize) -> Option<u32> {
-    let ret = syscall!(SYS_SPAWN, app);
+/// Create a copy of the caller, which shares the memory copy-on-write.
+/// The PID of the child is returned to the caller, and 0 is returned to the child.
+/// The child inherits the priority and the page quota, and its mailbox can queue
+/// MAILBOX_DEFAULT messages.
+pub fn fork() -> Option<u32> {
+    let ret = syscall!(SYS_FORK);
     if ret < 0 {
         None
     } else {
@@ -85,12 +471,89 @@ pub fn spawn(app: usize) -> Option<u32> {
     }
 }
 
-/// Quit the process.
-pub fn exit() -> ! {
-    syscall!(SYS_EXIT);
+/// Get the arguments passed by `spawn`.
+/// The kernel has copied them to the top of the stack of the caller.
+/// A child of `fork` gets the arguments of its parent.
+pub fn args() -> Vec<Arg> {
+    let ptr = allocator::user_args(paging::layout(get_raw_id_user()));
+    let buf = unsafe {
+        let len = *ptr as usize;
+        core::slice::from_raw_parts((ptr as *const u8).sub(len), len)
+    };
+    Arg::decode(buf).unwrap_or_default()
+}
+
+/// Quit the process with an exit code.
+pub fn exit(code: i32) -> ! {
+    syscall!(SYS_EXIT, code as i64);
     unreachable!()
 }
 
+/// Monitor the process pid.
+/// A DOWN message, whose source is `Locator::Down(pid)` and payload is
+/// `ExitStatus::to_bytes`, is sent to the caller when pid terminates.
+/// false is returned if pid does not exist.
+pub fn monitor(pid: u32) -> bool {
+    syscall!(SYS_MONITOR, pid as u64) == 1
+}
+
+/// Link the caller and the process pid.
+/// When one of them terminates abnormally, the other is killed
+/// unless it traps exits.
+/// false is returned if pid does not exist.
+pub fn link(pid: u32) -> bool {
+    syscall!(SYS_LINK, pid as u64) == 1
+}
+
+/// Trap exits of linked processes.
+/// If enabled, the caller receives DOWN messages instead of being killed.
+pub fn trap_exit(is_trap: bool) {
+    syscall!(SYS_TRAP_EXIT, is_trap as u64);
+}
+
+/// Wait for the child process pid to terminate.
+/// None is returned if pid is not a child of the caller.
+pub fn wait(pid: u32) -> Option<ExitStatus> {
+    let mut buf = [0u8; ExitStatus::BYTES_LEN];
+    let ret = syscall!(SYS_WAIT, pid as u64, buf.as_mut_ptr());
+    if ret < 0 {
+        None
+    } else {
+        ExitStatus::from_bytes(&buf)
+    }
+}
+
+/// Set the priority of the process pid.
+/// false is returned if pid does not exist or priority is not less than PRIORITY_NUM.
+pub fn setpriority(pid: u32, priority: u8) -> bool {
+    syscall!(SYS_SETPRIORITY, pid as u64, priority as u64) == 1
+}
+
+/// Set the maximum number of pages which the process pid can map.
+/// Only the parent of pid or the init process can set it, and the caller cannot raise its own.
+/// false is returned if pid does not exist or the caller is not permitted.
+pub fn setquota(pid: u32, pages: u32) -> bool {
+    syscall!(SYS_SET_QUOTA, pid as u64, pages as u64) == 1
+}
+
+/// Get the scheduler counters of cpu.
+/// None is returned if cpu does not exist.
+pub fn sched_stats(cpu: usize) -> Option<SchedStats> {
+    let mut stats = SchedStats::new();
+    let ret = syscall!(SYS_SCHED_STATS, cpu, &mut stats as *mut SchedStats);
+    if ret < 0 {
+        None
+    } else {
+        Some(stats)
+    }
+}
+
+/// Take a snapshot of the processes.
+/// Up to buf.len() processes are stored to buf, and the number of processes is returned.
+pub fn ps(buf: &mut [ProcStat]) -> usize {
+    syscall!(SYS_PS, buf.as_mut_ptr(), buf.len()).max(0) as usize
+}
+
 /// Yielding.
 pub fn sched_yield() {
     syscall!(SYS_SCHED);
@@ -102,14 +565,142 @@ pub fn getpid() -> u32 {
     id as u32
 }
 
-/// Send val to dst
-pub fn send(dst: &Locator, val: u32) -> bool {
-    syscall!(SYS_SEND, dst as *const Locator, val as u64) == 1
+/// Send data to dst.
+/// The length of data must not exceed MSG_MAX_LEN.
+pub fn send(dst: &Locator, data: &[u8]) -> bool {
+    syscall!(SYS_SEND, dst as *const Locator, data.as_ptr(), data.len()) == 1
+}
+
+/// Send data to dst.
+/// The caller blocks while the mailbox of dst is full.
+/// This fails if dst has exited.
+pub fn send_wait(dst: &Locator, data: &[u8]) -> bool {
+    syscall!(
+        SYS_SEND_WAIT,
+        dst as *const Locator,
+        data.as_ptr(),
+        data.len()
+    ) == 1
+}
+
+/// Send data to dst after msec milliseconds.
+/// The message is queued even if the mailbox of dst is full at that time,
+/// and is discarded if dst or the caller has exited.
+/// false is returned if dst does not exist now, or the caller has TIMER_MAX messages pending.
+pub fn send_after(dst: &Locator, data: &[u8], msec: u64) -> bool {
+    syscall!(
+        SYS_SEND_AFTER,
+        dst as *const Locator,
+        data.as_ptr(),
+        data.len(),
+        msec
+    ) == 1
+}
+
+/// Sleep for msec milliseconds.
+pub fn sleep(msec: u64) {
+    syscall!(SYS_SLEEP, msec);
+}
+
+/// Register the caller as name.
+/// A process has at most one name, which is removed when it terminates.
+/// false is returned if name is already used, the caller already has a name,
+/// or the length of name is not in 1..=NAME_MAX_LEN.
+pub fn register(name: &[u8]) -> bool {
+    syscall!(SYS_REGISTER, name.as_ptr(), name.len()) == 1
+}
+
+/// Get the process registered as name.
+pub fn whereis(name: &[u8]) -> Option<u32> {
+    let ret = syscall!(SYS_WHEREIS, name.as_ptr(), name.len());
+    if ret < 0 {
+        None
+    } else {
+        Some(ret as u32)
+    }
+}
+
+/// Remove the name of the caller.
+/// false is returned if the caller has no name.
+pub fn unregister() -> bool {
+    syscall!(SYS_UNREGISTER) == 1
+}
+
+/// Send data to the process registered as name.
+/// This fails if no process is registered as name or its mailbox is full.
+pub fn send_name(name: &[u8], data: &[u8]) -> bool {
+    syscall!(
+        SYS_SEND_NAME,
+        name.as_ptr(),
+        name.len(),
+        data.as_ptr(),
+        data.len()
+    ) == 1
+}
+
+/// Send data to the process registered as name.
+/// The caller blocks while the mailbox of the process is full.
+pub fn send_name_wait(name: &[u8], data: &[u8]) -> bool {
+    syscall!(
+        SYS_SEND_NAME_WAIT,
+        name.as_ptr(),
+        name.len(),
+        data.as_ptr(),
+        data.len()
+    ) == 1
+}
+
+/// Subscribe the caller to the device interrupt irq.
+/// Each interrupt is delivered as an empty message from `Locator::Device(irq)`,
+/// and the line stays masked until the caller calls `ack_irq`.
+/// false is returned if irq is not a device interrupt or another process subscribes it.
+/// The subscription is removed when the caller terminates.
+pub fn subscribe_irq(irq: u32) -> bool {
+    syscall!(SYS_IRQ_SUBSCRIBE, irq as u64) == 1
 }
 
-/// Receive a value
-pub fn recv(src: &mut Locator) -> u32 {
-    syscall!(SYS_RECV, src as *mut Locator) as u32
+/// Unmask the device interrupt irq after handling it.
+/// false is returned if the caller does not subscribe irq.
+pub fn ack_irq(irq: u32) -> bool {
+    syscall!(SYS_IRQ_ACK, irq as u64) == 1
+}
+
+/// Receive a message, and return its length.
+/// If buf is shorter than the message, the rest is discarded.
+/// 0 is returned without receiving if src or buf is not in the caller's memory.
+pub fn recv(src: &mut Locator, buf: &mut [u8]) -> usize {
+    syscall!(SYS_RECV, src as *mut Locator, buf.as_mut_ptr(), buf.len()).max(0) as usize
+}
+
+/// Receive a message without blocking
+pub fn try_recv(src: &mut Locator, buf: &mut [u8]) -> Option<usize> {
+    let ret = syscall!(
+        SYS_TRY_RECV,
+        src as *mut Locator,
+        buf.as_mut_ptr(),
+        buf.len()
+    );
+    if ret < 0 {
+        None
+    } else {
+        Some(ret as usize)
+    }
+}
+
+/// Receive a message, or None if no message arrives within msec milliseconds
+pub fn recv_timeout(src: &mut Locator, buf: &mut [u8], msec: u64) -> Option<usize> {
+    let ret = syscall!(
+        SYS_RECV_TIMEOUT,
+        src as *mut Locator,
+        buf.as_mut_ptr(),
+        buf.len(),
+        msec
+    );
+    if ret < 0 {
+        None
+    } else {
+        Some(ret as usize)
+    }
 }
 
 /// Set userland allocator
@@ -122,7 +713,36 @@ pub fn unmap(start: usize, end: usize) {
     syscall!(SYS_UNMAP, start, end);
 }
 
+/// Reserve an anonymous mapping of len bytes, whose pages are mapped on demand.
+/// prot is PROT_READ, PROT_WRITE or both, and a writable mapping is also readable.
+pub fn mmap_anon(len: usize, prot: u8) -> Option<*mut u8> {
+    let ret = syscall!(SYS_MMAP, len, prot as u64);
+    if ret <= 0 {
+        None
+    } else {
+        Some(ret as *mut u8)
+    }
+}
+
+/// Remove an anonymous mapping reserved by `mmap_anon`, and release its pages.
+/// addr and len must be the same as the mapping.
+pub fn munmap(addr: *mut u8, len: usize) -> bool {
+    syscall!(SYS_MUNMAP, addr, len) == 1
+}
+
 /// Kill a process
 pub fn kill(pid: u32) {
     syscall!(SYS_KILL, pid as u64);
 }
+
+/// Read characters from the console.
+/// This blocks until a line is available, and returns 0 at EOF.
+/// At most 4096 bytes are read at once.
+pub fn read(buf: &mut [u8]) -> usize {
+    syscall!(SYS_READ, buf.as_mut_ptr(), buf.len()).max(0) as usize
+}
+
+/// Write characters to the console.
+pub fn write(buf: &[u8]) {
+    syscall!(SYS_WRITE, buf.as_ptr(), buf.len());
+}
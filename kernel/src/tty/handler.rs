@@ -1,4 +1,12 @@
-use crate::{bsp::uart, driver::uart::UART};
+use crate::{
+    bsp::{
+        int::{self, DevIRQNumber, IRQ},
+        uart,
+    },
+    cpuint,
+    process::{self, get_pid},
+};
+use synctools::mcs::{MCSLock, MCSNode};
 
 const TY_BACKSP: u8 = 0x08; // backspace,, ^H
 const TY_BACKSP2: u8 = 0x7f; // delete, ^?
@@ -9,11 +17,17 @@ const TY_NEWLINE: u8 = b'\n'; // line feed
 const TY_RETURN: u8 = b'\r'; // carrige return
 const TY_STOPCH: u8 = 0x13; // tty stop, ^S
 const TY_STRTCH: u8 = 0x11; // tty start, ^Q
-const TY_KILLCH: u8 = 0x16; // line kill, ^U
+const TY_KILLCH: u8 = 0x15; // line kill, ^U
 const TY_UPARROW: u8 = b'^'; // used by control caracters
 const TY_FULLCH: u8 = TY_BELL; //
 
-enum Mode {
+const TY_IBUFLEN: usize = 1024; // size of the input buffer
+const TY_OBUFLEN: usize = 256; // size of the buffer for held output
+
+static TTY0: MCSLock<TTY> = MCSLock::new(TTY::new());
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
     Raw,
     Cooked,
     Cbreak,
@@ -26,21 +40,22 @@ struct Cooked {
 
 struct TTY {
     mode: Mode,
+    cooked: Cooked,
     is_echo: bool,
     is_rm_bs_echo: bool, // remove backspace when echo
     is_evis: bool,       // echo control ^X
 
     is_rcv_erase: bool, // receive erase characters
     erasec: u8,         // erase character
-    eracec2: u8,        // another erace character
+    erasec2: u8,        // another erace character
 
     is_rcv_eof: bool, // receive EOF
     eofch: u8,        // EOF character
 
-    is_rcv_kill: u8, // receive kill character
-    killc: u8,       // kill character
+    is_rcv_kill: bool, // receive kill character
+    killc: u8,         // kill character
 
-    icursor: isize, // current position of cursor
+    icursor: usize, // current position of cursor
 
     is_rcv_oflow: bool, // receive ostop/ostart
     is_oheld: bool,     // output character beeing held?
@@ -51,19 +66,363 @@ struct TTY {
     is_ocrlf: bool, // output CR-LF for LF
 
     fullc: u8, // character to be sent when buffer is full
+
+    ibuf: [u8; TY_IBUFLEN], // input buffer
+    ihead: usize,           // next character to be read
+    icount: usize,          // number of characters in ibuf
+    iavail: usize,          // number of characters readers can read
+
+    obuf: [u8; TY_OBUFLEN], // output held by ostop
+    ocount: usize,
+
+    reader: Option<u32>, // process waiting for input
+    writer: Option<u32>, // process waiting for ostart
 }
 
-fn echo(uart0: &uart::DevUART, c: u8) {
-    uart0.send(c as u32);
+impl TTY {
+    const fn new() -> Self {
+        TTY {
+            mode: Mode::Cooked,
+            cooked: Cooked {
+                is_crlf_echo: true,
+                is_map_crlf: true,
+            },
+            is_echo: true,
+            is_rm_bs_echo: true,
+            is_evis: true,
+            is_rcv_erase: true,
+            erasec: TY_BACKSP,
+            erasec2: TY_BACKSP2,
+            is_rcv_eof: true,
+            eofch: TY_EOFCH,
+            is_rcv_kill: true,
+            killc: TY_KILLCH,
+            icursor: 0,
+            is_rcv_oflow: true,
+            is_oheld: false,
+            ostop: TY_STOPCH,
+            ostart: TY_STRTCH,
+            is_ocrlf: true,
+            fullc: TY_FULLCH,
+            ibuf: [0; TY_IBUFLEN],
+            ihead: 0,
+            icount: 0,
+            iavail: 0,
+            obuf: [0; TY_OBUFLEN],
+            ocount: 0,
+            reader: None,
+            writer: None,
+        }
+    }
+
+    /// Handle a received character.
+    fn handle_in(&mut self, ch: u8) {
+        // flow control
+        if self.is_rcv_oflow {
+            if ch == self.ostart {
+                self.restart_output();
+                return;
+            } else if ch == self.ostop {
+                self.is_oheld = true;
+                return;
+            }
+        }
+
+        // any other character restarts output
+        self.restart_output();
+
+        match self.mode {
+            Mode::Raw => {
+                if self.icount < TY_IBUFLEN {
+                    self.put_in(ch);
+                    self.complete();
+                }
+            }
+            Mode::Cbreak => {
+                let ch = self.map_cr(ch);
+                if self.icount >= TY_IBUFLEN {
+                    self.output(self.fullc);
+                    return;
+                }
+
+                self.put_in(ch);
+                self.echo(ch);
+                self.complete();
+            }
+            Mode::Cooked => self.handle_cooked(ch),
+        }
+    }
+
+    fn handle_cooked(&mut self, ch: u8) {
+        let ch = self.map_cr(ch);
+
+        // kill the current line
+        if self.is_rcv_kill && ch == self.killc {
+            while self.icursor > 0 {
+                let c = self.unput_in();
+                self.echo_erase(c);
+            }
+            return;
+        }
+
+        // erase the last character
+        if self.is_rcv_erase && (ch == self.erasec || ch == self.erasec2) {
+            if self.icursor > 0 {
+                let c = self.unput_in();
+                self.echo_erase(c);
+            }
+            return;
+        }
+
+        // the last slot of the buffer is reserved for the line terminator,
+        // but it may be filled by the lines not read yet
+        if ch == TY_NEWLINE || ch == TY_RETURN || (self.is_rcv_eof && ch == self.eofch) {
+            if self.icount >= TY_IBUFLEN {
+                self.output(self.fullc);
+                return;
+            }
+
+            self.echo(ch);
+            self.put_in(ch);
+            self.complete();
+            return;
+        }
+
+        if self.icount >= TY_IBUFLEN - 1 {
+            self.output(self.fullc);
+            return;
+        }
+
+        self.echo(ch);
+        self.put_in(ch);
+        self.icursor += 1;
+    }
+
+    fn map_cr(&self, ch: u8) -> u8 {
+        if ch == TY_RETURN && self.cooked.is_map_crlf {
+            TY_NEWLINE
+        } else {
+            ch
+        }
+    }
+
+    fn put_in(&mut self, ch: u8) {
+        let tail = (self.ihead + self.icount) % TY_IBUFLEN;
+        self.ibuf[tail] = ch;
+        self.icount += 1;
+    }
+
+    /// Remove the last character of the current line.
+    fn unput_in(&mut self) -> u8 {
+        self.icount -= 1;
+        self.icursor -= 1;
+        self.ibuf[(self.ihead + self.icount) % TY_IBUFLEN]
+    }
+
+    fn get_in(&mut self) -> u8 {
+        let ch = self.ibuf[self.ihead];
+        self.ihead = (self.ihead + 1) % TY_IBUFLEN;
+        self.icount -= 1;
+        self.iavail -= 1;
+        ch
+    }
+
+    /// Make the buffered characters available to the reader.
+    fn complete(&mut self) {
+        self.icursor = 0;
+        self.iavail = self.icount;
+
+        if let Some(pid) = self.reader.take() {
+            process::wake_up(pid);
+        }
+    }
+
+    fn is_visible_ctrl(&self, ch: u8) -> bool {
+        self.is_evis && (ch < TY_BLANK || ch == TY_BACKSP2) && ch != TY_NEWLINE && ch != b'\t'
+    }
+
+    fn echo(&mut self, ch: u8) {
+        if !self.is_echo {
+            return;
+        }
+
+        if ch == TY_NEWLINE && self.cooked.is_crlf_echo {
+            self.output(TY_RETURN);
+            self.output(TY_NEWLINE);
+        } else if self.is_visible_ctrl(ch) {
+            self.output(TY_UPARROW);
+            self.output((ch + 0o100) & 0x7f);
+        } else {
+            self.output(ch);
+        }
+    }
+
+    fn echo_erase(&mut self, ch: u8) {
+        if !self.is_echo {
+            return;
+        }
+
+        let n = if self.is_visible_ctrl(ch) { 2 } else { 1 };
+        for _ in 0..n {
+            self.output(TY_BACKSP);
+            if self.is_rm_bs_echo {
+                self.output(TY_BLANK);
+                self.output(TY_BACKSP);
+            }
+        }
+    }
+
+    /// Send a character, or hold it while output is stopped.
+    fn output(&mut self, ch: u8) {
+        if self.is_oheld {
+            if self.ocount < TY_OBUFLEN {
+                self.obuf[self.ocount] = ch;
+                self.ocount += 1;
+            }
+        } else {
+            self.send(ch);
+        }
+    }
+
+    fn send(&self, ch: u8) {
+        if ch == TY_NEWLINE && self.is_ocrlf {
+            uart::send(TY_RETURN);
+        }
+        uart::send(ch);
+    }
+
+    fn restart_output(&mut self) {
+        if !self.is_oheld {
+            return;
+        }
+
+        self.is_oheld = false;
+        for &ch in self.obuf[..self.ocount].iter() {
+            self.send(ch);
+        }
+        self.ocount = 0;
+
+        if let Some(pid) = self.writer.take() {
+            process::wake_up(pid);
+        }
+    }
+
+    /// Copy available characters to buf.
+    /// In cooked mode, at most one line is copied,
+    /// and the EOF character is consumed but not copied.
+    fn read_avail(&mut self, buf: &mut [u8]) -> usize {
+        let mut n = 0;
+        while n < buf.len() && self.iavail > 0 {
+            let ch = self.get_in();
+
+            if self.mode == Mode::Cooked {
+                if self.is_rcv_eof && ch == self.eofch {
+                    break;
+                }
+
+                buf[n] = ch;
+                n += 1;
+
+                if ch == TY_NEWLINE {
+                    break;
+                }
+            } else {
+                buf[n] = ch;
+                n += 1;
+            }
+        }
+
+        n
+    }
 }
 
-fn drop(_uart0: &uart::DevUART, _c: u8) {}
+fn int_handler(_irq_num: DevIRQNumber) {
+    let mut buf = [0; 64];
 
-impl TTY {
-    pub fn int_handler(&self) {
-        let mut buf = [0; 1024];
+    let mut node = MCSNode::new();
+    let mut tty = TTY0.lock(&mut node);
+
+    loop {
+        let len = uart::try_read(&mut buf);
+        if len == 0 {
+            break;
+        }
+
+        for &ch in buf[..len].iter() {
+            tty.handle_in(ch);
+        }
+    }
+}
+
+/// Start receiving the serial console by interrupts.
+pub fn init() {
+    assert!(int::register_handler(
+        int::IRQ_UART,
+        IRQ::new("UART0", int_handler)
+    ));
+    int::enable_irq_num(int::IRQ_UART);
+    uart::enable_recv_interrupt();
+}
+
+/// Set the input mode of the console.
+pub fn set_mode(mode: Mode) {
+    let _mask = cpuint::mask();
+    let mut node = MCSNode::new();
+    let mut tty = TTY0.lock(&mut node);
+
+    tty.mode = mode;
+    if mode != Mode::Cooked {
+        // the current line becomes available
+        tty.icursor = 0;
+        tty.iavail = tty.icount;
+    }
+}
+
+/// Read characters from the console.
+/// The caller blocks until a line (cooked mode) or a character (raw and cbreak modes) is available.
+/// In cooked mode, 0 is returned when EOF is received at the beginning of a line.
+pub fn read(buf: &mut [u8]) -> usize {
+    if buf.is_empty() {
+        return 0;
+    }
+
+    loop {
+        let mask = cpuint::mask();
+        let mut node = MCSNode::new();
+        let mut tty = TTY0.lock(&mut node);
+
+        if tty.iavail > 0 {
+            return tty.read_avail(buf);
+        }
+
+        // another reader is woken up to retry
+        if let Some(pid) = tty.reader.replace(get_pid()) {
+            process::wake_up(pid);
+        }
+
+        process::block(mask, || tty.unlock());
+    }
+}
+
+/// Write characters to the console.
+/// The caller blocks while output is stopped by ostop.
+pub fn write(buf: &[u8]) {
+    loop {
+        let mask = cpuint::mask();
+        let mut node = MCSNode::new();
+        let mut tty = TTY0.lock(&mut node);
+
+        if !tty.is_oheld {
+            for &ch in buf.iter() {
+                tty.send(ch);
+            }
+            return;
+        }
+
+        if let Some(pid) = tty.writer.replace(get_pid()) {
+            process::wake_up(pid);
+        }
 
-        let ef = if self.is_echo { echo } else { drop };
-        let _len = uart::read(&mut buf, ef);
+        process::block(mask, || tty.unlock());
     }
 }
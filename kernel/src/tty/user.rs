@@ -1 +1,28 @@
+use crate::syscall;
+use alloc::vec::Vec;
 
+/// Read a line from the console, which does not contain the line feed.
+pub fn read_line() -> Vec<u8> {
+    let mut res = Vec::new();
+    let mut buf = [0; 256];
+
+    loop {
+        let n = syscall::read(&mut buf);
+        if n == 0 {
+            break; // EOF
+        }
+
+        res.extend_from_slice(&buf[..n]);
+        if res.last() == Some(&b'\n') {
+            res.pop();
+            break;
+        }
+    }
+
+    res
+}
+
+/// print characters to the console
+pub fn puts(s: &str) {
+    syscall::write(s.as_bytes());
+}
@@ -1,37 +1,405 @@
-use crate::{driver::uart, syscall, syscall::Locator};
+use crate::{
+    driver::uart,
+    syscall::{self, read_bytes, read_u32, Arg, ExitStatus, Locator, ProcStat},
+    tty,
+};
 
-use alloc::boxed::Box;
+use alloc::{boxed::Box, vec::Vec};
+use core::cell::RefCell;
 use memac::Allocator;
 use num_bigint::BigInt;
 use num_traits::{FromPrimitive, ToPrimitive, Zero};
 
 const APPS: &[&str] = &[include_str!("init.lisp")];
 
-fn callback(x: &BigInt, y: &BigInt, z: &BigInt) -> Option<BigInt> {
+// call-rust operations handled in userland
+const MSG_PUSH: u64 = 100; // push an integer to the values being built
+const MSG_NEXT: u64 = 101; // get the next integer of the last received value
+const EXIT_CODE: u64 = 102; // get the exit code of the last child waited for
+const MSG_FROM: u64 = 103; // get the sender of the last received message
+const MSG_DOWN: u64 = 104; // get the terminated process if the last message is DOWN
+const SPAWN_PRIO: u64 = 105; // set the priority of the next spawned process
+const PS_GET: u64 = 106; // get a field of a process of the last snapshot
+const MSG_DEVICE: u64 = 107; // get the interrupt if the last message is from a device
+const ARG_NUM: u64 = 108; // get the number of the arguments of this process
+const ARG_GET: u64 = 109; // get an argument of this process
+const MSG_BEGIN: u64 = 110; // begin a string or a list of the values being built
+const MSG_STR: u64 = 111; // make a string of the integers since MSG_BEGIN
+const MSG_LIST: u64 = 112; // make a list of the values since MSG_BEGIN
+const MSG_BOOL: u64 = 113; // push a boolean to the values being built
+const MSG_OPTION: u64 = 114; // push None if y is 0, otherwise make Some of the last value
+
+// tags of serialized values
+const TAG_INT: u8 = 0;
+const TAG_STR: u8 = 1;
+const TAG_BOOL: u8 = 2;
+const TAG_LIST: u8 = 3;
+const TAG_OPTION: u8 = 4;
+
+// maximum nesting of lists and options in a received value
+const VALUE_DEPTH_MAX: usize = 32;
+
+// kinds of exit status returned to Lisp
+const EXIT_EXITED: u32 = 0;
+const EXIT_KILLED: u32 = 1;
+const EXIT_STACK_OVERFLOW: u32 = 2;
+const EXIT_INVALID_ACCESS: u32 = 3;
+const EXIT_ERROR: u32 = 4;
+const EXIT_QUOTA_EXCEEDED: u32 = 5;
+const EXIT_OUT_OF_MEMORY: u32 = 6;
+
+/// A value of messages between Lisp processes.
+enum Value {
+    Int(BigInt),
+    Str(Vec<u8>),
+    Bool(bool),
+    List(Vec<Value>),
+    Option(Option<Box<Value>>),
+}
+
+/// Per-process state of Lisp.
+struct ProcState {
+    send: Vec<Value>,  // values being built
+    marks: Vec<usize>, // positions in send where MSG_BEGIN was called
+    recv: Vec<BigInt>, // the last received value flattened by `flatten`
+    cursor: usize,     // position of recv read by MSG_NEXT
+    from: Locator,     // source of the last received message
+    exit_code: i32,    // exit code of the last child waited for
+    spawn_prio: u8,    // priority of the next spawned process
+    ps: Vec<ProcStat>, // the last snapshot of the processes
+    args: Vec<Arg>,    // arguments passed by the parent
+}
+
+/// Get the kind and the exit code of a status.
+fn exit_kind(status: ExitStatus) -> (u32, i32) {
+    match status {
+        ExitStatus::Exited(code) => (EXIT_EXITED, code),
+        ExitStatus::Killed => (EXIT_KILLED, 0),
+        ExitStatus::StackOverflow => (EXIT_STACK_OVERFLOW, 0),
+        ExitStatus::InvalidAccess => (EXIT_INVALID_ACCESS, 0),
+        ExitStatus::Error => (EXIT_ERROR, 0),
+        ExitStatus::QuotaExceeded => (EXIT_QUOTA_EXCEEDED, 0),
+        ExitStatus::OutOfMemory => (EXIT_OUT_OF_MEMORY, 0),
+    }
+}
+
+/// Serialize a value with tags.
+/// | TAG_INT | len (u32 LE) | bytes (LE, signed) |
+/// | TAG_STR | len (u32 LE) | bytes |
+/// | TAG_BOOL | 0 or 1 (u8) |
+/// | TAG_LIST | count (u32 LE) | value | value | ...
+/// | TAG_OPTION | 0 (u8) | for None, and | TAG_OPTION | 1 (u8) | value | for Some
+fn encode(v: &Value, buf: &mut Vec<u8>) {
+    match v {
+        Value::Int(n) => {
+            let bytes = n.to_signed_bytes_le();
+            buf.push(TAG_INT);
+            buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&bytes);
+        }
+        Value::Str(s) => {
+            buf.push(TAG_STR);
+            buf.extend_from_slice(&(s.len() as u32).to_le_bytes());
+            buf.extend_from_slice(s);
+        }
+        Value::Bool(b) => {
+            buf.push(TAG_BOOL);
+            buf.push(*b as u8);
+        }
+        Value::List(xs) => {
+            buf.push(TAG_LIST);
+            buf.extend_from_slice(&(xs.len() as u32).to_le_bytes());
+            for x in xs.iter() {
+                encode(x, buf);
+            }
+        }
+        Value::Option(None) => {
+            buf.push(TAG_OPTION);
+            buf.push(0);
+        }
+        Value::Option(Some(x)) => {
+            buf.push(TAG_OPTION);
+            buf.push(1);
+            encode(x, buf);
+        }
+    }
+}
+
+/// Deserialize a value serialized by `encode`.
+fn decode(buf: &[u8], pos: &mut usize, depth: usize) -> Option<Value> {
+    if depth > VALUE_DEPTH_MAX {
+        return None;
+    }
+
+    let tag = *buf.get(*pos)?;
+    *pos += 1;
+
+    match tag {
+        TAG_INT => {
+            let bytes = read_bytes(buf, pos)?;
+            Some(Value::Int(BigInt::from_signed_bytes_le(bytes)))
+        }
+        TAG_STR => Some(Value::Str(read_bytes(buf, pos)?.to_vec())),
+        TAG_BOOL => {
+            let b = *buf.get(*pos)?;
+            *pos += 1;
+            Some(Value::Bool(b != 0))
+        }
+        TAG_LIST => {
+            let cnt = read_u32(buf, pos)?;
+            let mut xs = Vec::new();
+            for _ in 0..cnt {
+                xs.push(decode(buf, pos, depth + 1)?);
+            }
+            Some(Value::List(xs))
+        }
+        TAG_OPTION => {
+            let some = *buf.get(*pos)?;
+            *pos += 1;
+            if some == 0 {
+                Some(Value::Option(None))
+            } else {
+                let x = decode(buf, pos, depth + 1)?;
+                Some(Value::Option(Some(Box::new(x))))
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Flatten a value to integers, which Lisp reads by MSG_NEXT.
+/// A value is its tag followed by
+/// the integer for Int, the length and the characters for Str,
+/// 0 or 1 for Bool, the count and the values for List,
+/// and 0 for None or 1 and the value for Some.
+fn flatten(v: &Value, xs: &mut Vec<BigInt>) {
+    match v {
+        Value::Int(n) => {
+            xs.push(BigInt::from(TAG_INT));
+            xs.push(n.clone());
+        }
+        Value::Str(s) => {
+            xs.push(BigInt::from(TAG_STR));
+            xs.push(BigInt::from(s.len()));
+            xs.extend(s.iter().map(|c| BigInt::from(*c)));
+        }
+        Value::Bool(b) => {
+            xs.push(BigInt::from(TAG_BOOL));
+            xs.push(BigInt::from(*b as u8));
+        }
+        Value::List(ys) => {
+            xs.push(BigInt::from(TAG_LIST));
+            xs.push(BigInt::from(ys.len()));
+            for y in ys.iter() {
+                flatten(y, xs);
+            }
+        }
+        Value::Option(None) => {
+            xs.push(BigInt::from(TAG_OPTION));
+            xs.push(Zero::zero());
+        }
+        Value::Option(Some(y)) => {
+            xs.push(BigInt::from(TAG_OPTION));
+            xs.push(BigInt::from(1));
+            flatten(y, xs);
+        }
+    }
+}
+
+/// Store a received message to st.
+/// An empty or malformed message is received as an empty list.
+/// A DOWN message is received as the list of the kind and the exit code of the status.
+fn set_recv(st: &RefCell<ProcState>, loc: Locator, buf: &[u8], len: usize) -> Option<BigInt> {
+    let v = if len > buf.len() {
+        None // truncated
+    } else if let Locator::Down(_) = loc {
+        ExitStatus::from_bytes(&buf[..len]).map(|status| {
+            let (kind, code) = exit_kind(status);
+            Value::List(vec![
+                Value::Int(BigInt::from(kind)),
+                Value::Int(BigInt::from(code)),
+            ])
+        })
+    } else {
+        let mut pos = 0;
+        decode(&buf[..len], &mut pos, 0).filter(|_| pos == len)
+    };
+
+    let mut st = st.borrow_mut();
+    st.from = loc;
+    load(&mut st, &v.unwrap_or(Value::List(Vec::new())));
+    Some(Zero::zero())
+}
+
+/// Flatten a value to be read by MSG_NEXT.
+fn load(st: &mut ProcState, v: &Value) {
+    st.recv.clear();
+    st.cursor = 0;
+    flatten(v, &mut st.recv);
+}
+
+/// Take the last value built by Lisp, and discard the others.
+fn take_value(st: &RefCell<ProcState>) -> Option<Vec<u8>> {
+    let mut st = st.borrow_mut();
+    st.marks.clear();
+    let v = st.send.pop();
+    st.send.clear();
+
+    let mut buf = Vec::new();
+    encode(&v?, &mut buf);
+    Some(buf)
+}
+
+/// Make a value of the values built since MSG_BEGIN.
+fn make_value<F>(st: &RefCell<ProcState>, f: F) -> Option<BigInt>
+where
+    F: FnOnce(Vec<Value>) -> Option<Value>,
+{
+    let mut st = st.borrow_mut();
+    let mark = st.marks.pop()?;
+    let xs = st.send.split_off(mark);
+    let v = f(xs)?;
+    st.send.push(v);
+    Some(Zero::zero())
+}
+
+/// Take the name built last by Lisp as a string.
+/// The values being built are discarded if it is not a string.
+fn take_name(st: &RefCell<ProcState>) -> Option<Vec<u8>> {
+    let mut st = st.borrow_mut();
+    match st.send.pop() {
+        Some(Value::Str(name)) => Some(name),
+        _ => {
+            st.marks.clear();
+            st.send.clear();
+            None
+        }
+    }
+}
+
+fn callback(st: &RefCell<ProcState>, x: &BigInt, y: &BigInt, z: &BigInt) -> Option<BigInt> {
     let c = x.to_u64()?;
     match c {
         syscall::SYS_SPAWN => {
             // call spawn
+            // z is the capacity of the mailbox, and 0 means the default
+            // the priority is set by SPAWN_PRIO, and reset to the default
+            // the integers and the strings built by Lisp are the arguments
             let app = y.to_usize()?;
-            let n = syscall::spawn(app)?;
+            let capacity = match z.to_usize()? {
+                0 => syscall::MAILBOX_DEFAULT,
+                n => n,
+            };
+            let priority =
+                core::mem::replace(&mut st.borrow_mut().spawn_prio, syscall::PRIORITY_DEFAULT);
+            let xs = core::mem::take(&mut st.borrow_mut().send);
+            st.borrow_mut().marks.clear();
+            let args = xs
+                .iter()
+                .map(|x| match x {
+                    Value::Int(n) => n.to_i64().map(Arg::Int),
+                    Value::Str(s) => Some(Arg::Str(s.clone())),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>()?;
+            let n = syscall::spawn(app, capacity, priority, &args)?;
             let n = BigInt::from_u32(n)?;
             Some(n)
         }
         syscall::SYS_EXIT => {
-            syscall::exit();
+            syscall::exit(y.to_i32()?);
+        }
+        syscall::SYS_WAIT => {
+            let (kind, code) = exit_kind(syscall::wait(y.to_u32()?)?);
+            st.borrow_mut().exit_code = code;
+            BigInt::from_u32(kind)
         }
         syscall::SYS_SCHED => {
             syscall::sched_yield();
             None
         }
+        syscall::SYS_FORK => {
+            // the child continues from here with a copy of the interpreter
+            let n = syscall::fork()?;
+            let n = BigInt::from_u32(n)?;
+            Some(n)
+        }
         syscall::SYS_GETPID => {
             let id = syscall::getpid();
             let id = BigInt::from_u32(id)?;
             Some(id)
         }
-        syscall::SYS_SEND => {
+        syscall::SYS_SEND | syscall::SYS_SEND_WAIT => {
+            // send the value built by Lisp to the process y,
+            // or to the process registered as the name built after the value if z is 1
+            let name = if z.is_zero() {
+                None
+            } else {
+                Some(take_name(st)?)
+            };
+            let buf = take_value(st)?;
+            let is_wait = c == syscall::SYS_SEND_WAIT;
+
+            let ret = match name {
+                Some(name) if is_wait => syscall::send_name_wait(&name, &buf),
+                Some(name) => syscall::send_name(&name, &buf),
+                None => {
+                    let loc = Locator::Process(y.to_u32()?);
+                    if is_wait {
+                        syscall::send_wait(&loc, &buf)
+                    } else {
+                        syscall::send(&loc, &buf)
+                    }
+                }
+            };
+
+            if ret {
+                Some(Zero::zero())
+            } else {
+                None
+            }
+        }
+        syscall::SYS_SEND_AFTER => {
+            // send the value built by Lisp to y after z milliseconds
             let loc = Locator::Process(y.to_u32()?);
-            if syscall::send(&loc, z.to_u32()?) {
+            let buf = take_value(st)?;
+            if syscall::send_after(&loc, &buf, z.to_u64()?) {
+                Some(Zero::zero())
+            } else {
+                None
+            }
+        }
+        syscall::SYS_REGISTER => {
+            if syscall::register(&take_name(st)?) {
+                Some(Zero::zero())
+            } else {
+                None
+            }
+        }
+        syscall::SYS_WHEREIS => {
+            let pid = syscall::whereis(&take_name(st)?)?;
+            BigInt::from_u32(pid)
+        }
+        syscall::SYS_UNREGISTER => {
+            if syscall::unregister() {
+                Some(Zero::zero())
+            } else {
+                None
+            }
+        }
+        syscall::SYS_SLEEP => {
+            syscall::sleep(y.to_u64()?);
+            Some(Zero::zero())
+        }
+        syscall::SYS_IRQ_SUBSCRIBE => {
+            if syscall::subscribe_irq(y.to_u32()?) {
+                Some(Zero::zero())
+            } else {
+                None
+            }
+        }
+        syscall::SYS_IRQ_ACK => {
+            if syscall::ack_irq(y.to_u32()?) {
                 Some(Zero::zero())
             } else {
                 None
@@ -39,14 +407,174 @@ fn callback(x: &BigInt, y: &BigInt, z: &BigInt) -> Option<BigInt> {
         }
         syscall::SYS_RECV => {
             let mut loc = Locator::Unknown;
-            let val = syscall::recv(&mut loc);
-            let val = BigInt::from_u32(val)?;
-            Some(val)
+            let mut buf = vec![0; syscall::MSG_MAX_LEN];
+            let len = syscall::recv(&mut loc, &mut buf);
+            set_recv(st, loc, &buf, len)
+        }
+        syscall::SYS_TRY_RECV => {
+            let mut loc = Locator::Unknown;
+            let mut buf = vec![0; syscall::MSG_MAX_LEN];
+            let len = syscall::try_recv(&mut loc, &mut buf)?;
+            set_recv(st, loc, &buf, len)
+        }
+        syscall::SYS_RECV_TIMEOUT => {
+            let mut loc = Locator::Unknown;
+            let mut buf = vec![0; syscall::MSG_MAX_LEN];
+            let len = syscall::recv_timeout(&mut loc, &mut buf, y.to_u64()?)?;
+            set_recv(st, loc, &buf, len)
+        }
+        MSG_PUSH => {
+            st.borrow_mut().send.push(Value::Int(y.clone()));
+            None
+        }
+        MSG_NEXT => {
+            let mut st = st.borrow_mut();
+            let i = st.cursor;
+            st.cursor += 1;
+            st.recv.get(i).cloned()
         }
+        MSG_BEGIN => {
+            let mut st = st.borrow_mut();
+            let mark = st.send.len();
+            st.marks.push(mark);
+            Some(Zero::zero())
+        }
+        MSG_STR => make_value(st, |xs| {
+            // characters are pushed as integers
+            let s = xs
+                .iter()
+                .map(|x| match x {
+                    Value::Int(c) => c.to_u8(),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>()?;
+            Some(Value::Str(s))
+        }),
+        MSG_LIST => make_value(st, |xs| Some(Value::List(xs))),
+        MSG_BOOL => {
+            st.borrow_mut().send.push(Value::Bool(!y.is_zero()));
+            Some(Zero::zero())
+        }
+        MSG_OPTION => {
+            let mut st = st.borrow_mut();
+            let v = if y.is_zero() {
+                None
+            } else {
+                Some(Box::new(st.send.pop()?))
+            };
+            st.send.push(Value::Option(v));
+            Some(Zero::zero())
+        }
+        SPAWN_PRIO => {
+            st.borrow_mut().spawn_prio = y.to_u8()?;
+            Some(Zero::zero())
+        }
+        EXIT_CODE => BigInt::from_i32(st.borrow().exit_code),
+        MSG_FROM => match st.borrow().from {
+            Locator::Process(pid) => BigInt::from_u32(pid),
+            _ => None,
+        },
+        MSG_DOWN => match st.borrow().from {
+            Locator::Down(pid) => BigInt::from_u32(pid),
+            _ => None,
+        },
+        ARG_NUM => BigInt::from_usize(st.borrow().args.len()),
+        ARG_GET => {
+            // the argument is read by MSG_NEXT like a received value
+            let mut st = st.borrow_mut();
+            let v = match st.args.get(y.to_usize()?)? {
+                Arg::Int(n) => Value::Int(BigInt::from(*n)),
+                Arg::Str(s) => Value::Str(s.clone()),
+            };
+            load(&mut st, &v);
+            Some(Zero::zero())
+        }
+        MSG_DEVICE => match st.borrow().from {
+            Locator::Device(irq) => BigInt::from_u32(irq),
+            _ => None,
+        },
         syscall::SYS_KILL => {
             syscall::kill(y.to_u32()?);
             None
         }
+        syscall::SYS_MONITOR => {
+            if syscall::monitor(y.to_u32()?) {
+                Some(Zero::zero())
+            } else {
+                None
+            }
+        }
+        syscall::SYS_LINK => {
+            if syscall::link(y.to_u32()?) {
+                Some(Zero::zero())
+            } else {
+                None
+            }
+        }
+        syscall::SYS_TRAP_EXIT => {
+            syscall::trap_exit(!y.is_zero());
+            None
+        }
+        syscall::SYS_SCHED_STATS => {
+            // z selects a counter
+            let stats = syscall::sched_stats(y.to_usize()?)?;
+            let n = match z.to_u64()? {
+                0 => stats.switches,
+                1 => stats.steals,
+                2 => stats.migrations,
+                3 => stats.lock_wait,
+                4 => stats.lock_count,
+                _ => return None,
+            };
+            BigInt::from_u64(n)
+        }
+        syscall::SYS_PS => {
+            // take a snapshot, and return the number of processes
+            let mut buf = Vec::new();
+            loop {
+                let n = syscall::ps(&mut buf);
+                if n <= buf.len() {
+                    buf.truncate(n);
+                    break;
+                }
+                // processes may be spawned meanwhile
+                buf.resize(n + 16, ProcStat::new());
+            }
+
+            let n = buf.len();
+            st.borrow_mut().ps = buf;
+            BigInt::from_usize(n)
+        }
+        PS_GET => {
+            // y is the index, and z selects a field
+            let st = st.borrow();
+            let p = st.ps.get(y.to_usize()?)?;
+            let n = match z.to_u64()? {
+                0 => p.pid as u64,
+                1 => p.state as u64,
+                2 => p.cpu as u64,
+                3 => p.priority as u64,
+                4 => p.mailbox as u64,
+                5 => p.pages as u64,
+                6 => p.cpu_time,
+                _ => return None,
+            };
+            BigInt::from_u64(n)
+        }
+        syscall::SYS_SETPRIORITY => {
+            if syscall::setpriority(y.to_u32()?, z.to_u8()?) {
+                Some(Zero::zero())
+            } else {
+                None
+            }
+        }
+        syscall::SYS_SET_QUOTA => {
+            if syscall::setquota(y.to_u32()?, z.to_u32()?) {
+                Some(Zero::zero())
+            } else {
+                None
+            }
+        }
         _ => {
             let msg = format!("unsupported syscall: {}\n", c);
             uart::puts(&msg);
@@ -66,7 +594,19 @@ fn run_lisp(s: &str) {
             match blisp::typing(exprs) {
                 Ok(mut ctx) => {
                     // register callback function
-                    ctx.set_callback(Box::new(|x, y, z| callback(x, y, z)));
+                    // the state belongs to this process
+                    let st = RefCell::new(ProcState {
+                        send: Vec::new(),
+                        marks: Vec::new(),
+                        recv: Vec::new(),
+                        cursor: 0,
+                        from: Locator::Unknown,
+                        exit_code: 0,
+                        spawn_prio: syscall::PRIORITY_DEFAULT,
+                        ps: Vec::new(),
+                        args: syscall::args(),
+                    });
+                    ctx.set_callback(Box::new(move |x, y, z| callback(&st, x, y, z)));
 
                     repl_uart(&ctx);
 
@@ -92,10 +632,15 @@ fn repl_uart(ctx: &blisp::semantics::Context) -> ! {
     loop {
         let pid = syscall::getpid();
         let msg = format!("\n(pid: {}) >> ", pid);
-        uart::puts(&msg);
+        tty::user::puts(&msg);
 
-        let code_str = uart::read_line();
-        let code = alloc::str::from_utf8(&code_str).unwrap();
+        let code_str = tty::user::read_line();
+        let code = if let Ok(code) = alloc::str::from_utf8(&code_str) {
+            code
+        } else {
+            tty::user::puts("error: invalid UTF-8");
+            continue;
+        };
 
         let result = blisp::eval(code, &ctx);
         match result {
@@ -103,18 +648,18 @@ fn repl_uart(ctx: &blisp::semantics::Context) -> ! {
                 for r in &rs {
                     match r {
                         Ok(msg) => {
-                            uart::puts(&msg);
+                            tty::user::puts(&msg);
                         }
                         Err(e) => {
                             let msg = format!("error: {}", e);
-                            uart::puts(&msg);
+                            tty::user::puts(&msg);
                         }
                     }
                 }
             }
             Err(e) => {
                 let msg = format!("{}:{}: {}", e.pos.line + 1, e.pos.column + 1, e.msg);
-                uart::puts(&msg);
+                tty::user::puts(&msg);
             }
         }
     }
@@ -139,7 +684,7 @@ pub fn userland_entry(app: usize) -> ! {
         run_lisp(s);
     } else {
         uart::puts("no such application\n");
-        syscall::exit();
+        syscall::exit(-1);
     }
 
     loop {}